@@ -0,0 +1,2 @@
+-- whether the last response was from the pre-1.7 server list ping
+alter table servers add column is_legacy_ping boolean not null default false;
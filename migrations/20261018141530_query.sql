@@ -0,0 +1,10 @@
+-- gamespy4 query (enable-query=true in server.properties)
+alter table servers add column last_queried timestamp without time zone;
+-- when the server was last picked for a query
+alter table servers add column last_query_attempt timestamp without time zone;
+alter table servers add column query_software text collate "C";
+alter table servers add column query_plugins text[] collate "C";
+alter table servers add column query_map text collate "C";
+alter table servers add column query_hostip text collate "C";
+
+create index last_query_attempt_idx on servers (last_query_attempt);
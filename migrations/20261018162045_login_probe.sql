@@ -0,0 +1,11 @@
+-- the result of actually trying to join the server
+alter table servers add column last_login_probe timestamp without time zone;
+-- when the server was last picked for a login probe
+alter table servers add column last_login_probe_attempt timestamp without time zone;
+-- online_mode, offline_mode, whitelisted, banned, outdated_client, outdated_server,
+-- velocity_forwarding, bungee_forwarding, or kicked
+alter table servers add column login_probe_outcome text collate "C";
+-- the disconnect message as chat json, if we got kicked
+alter table servers add column login_probe_kick_reason text collate "C";
+
+create index last_login_probe_attempt_idx on servers (last_login_probe_attempt);
@@ -0,0 +1,16 @@
+-- mods from forgeData.mods, forgeData.d, or modinfo.modList
+create table
+    server_mods (
+        server_ip uint4 not null,
+        server_port uint2 not null,
+        mod_id text collate "C" not null,
+        -- null for server-side only mods and servers that don't send versions
+        version text collate "C",
+        primary key (server_ip, server_port, mod_id),
+        foreign key (server_ip, server_port) references servers (ip, port) on delete cascade
+    );
+
+create index server_mods_mod_id_idx on server_mods (mod_id);
+
+-- forge stops adding mods to the ping if there are too many
+alter table servers add column forgedata_is_truncated boolean;
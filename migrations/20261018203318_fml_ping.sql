@@ -0,0 +1,4 @@
+-- the fml marker in the handshake hostname that made the server send its mods (FML, FML2, or FML3)
+alter table servers add column fml_marker text collate "C";
+-- when the server was last picked for an fml ping
+alter table servers add column last_fml_ping timestamp without time zone;
@@ -0,0 +1,36 @@
+-- domains that point to an ip, so we can send them in the handshake to proxies that route by hostname
+create table
+    known_domains (
+        ip uint4 not null,
+        domain text collate "C" not null,
+        -- "config", or the file it was imported from
+        source text collate "C" not null,
+        first_resolved timestamp without time zone not null default now (),
+        last_resolved timestamp without time zone not null default now (),
+        -- when the domain was last picked for a virtual host probe
+        last_probed timestamp without time zone,
+        primary key (ip, domain)
+    );
+
+create index known_domains_last_probed_idx on known_domains (last_probed);
+
+-- responses that we got by sending a known domain in the handshake, if they were different from the
+-- response we got with our default hostname
+create table
+    virtual_hosts (
+        server_ip uint4 not null,
+        server_port uint2 not null,
+        domain text collate "C" not null,
+        last_pinged timestamp without time zone not null,
+        description_json text collate "C",
+        description_plaintext text collate "C",
+        version_name text collate "C",
+        version_protocol integer,
+        online_players integer,
+        max_players integer,
+        favicon_hash bytea references favicons (hash),
+        primary key (server_ip, server_port, domain),
+        foreign key (server_ip, server_port) references servers (ip, port) on delete cascade
+    );
+
+create index virtual_hosts_domain_idx on virtual_hosts (domain);
@@ -0,0 +1,21 @@
+-- the version that each server reported when we pinged it with a different protocol version
+create table
+    server_protocol_probes (
+        server_ip uint4 not null,
+        server_port uint2 not null,
+        sent_protocol integer not null,
+        reported_protocol integer,
+        version_name text collate "C",
+        last_pinged timestamp without time zone not null,
+        primary key (server_ip, server_port, sent_protocol),
+        foreign key (server_ip, server_port) references servers (ip, port) on delete cascade
+    );
+
+-- the range of protocol versions that the server echoed back to us (viaversion and similar
+-- report the client's version if they support it)
+alter table servers add column min_supported_protocol integer;
+alter table servers add column max_supported_protocol integer;
+-- whether the server reported more than one protocol version
+alter table servers add column is_multi_version boolean;
+-- when the server was last picked for protocol probing
+alter table servers add column last_protocol_probe timestamp without time zone;
@@ -0,0 +1,9 @@
+-- the result of sending an invalid login start packet, see processing/minecraft_fingerprinting.rs
+alter table servers add column last_active_fingerprint timestamp without time zone;
+-- when the server was last picked for active fingerprinting
+alter table servers add column last_active_fingerprint_attempt timestamp without time zone;
+alter table servers add column active_fingerprint_software text collate "C";
+-- the start of whatever the server sent back, usually an error message
+alter table servers add column active_fingerprint_error text collate "C";
+
+create index last_active_fingerprint_attempt_idx on servers (last_active_fingerprint_attempt);
@@ -0,0 +1,14 @@
+-- the response to each kind of active fingerprinting probe, see FingerprintProbe
+create table
+    server_fingerprint_probes (
+        server_ip uint4 not null,
+        server_port uint2 not null,
+        probe text collate "C" not null,
+        software text collate "C" not null,
+        response text collate "C" not null,
+        last_probed timestamp without time zone not null,
+        primary key (server_ip, server_port, probe),
+        foreign key (server_ip, server_port) references servers (ip, port) on delete cascade
+    );
+
+create index server_fingerprint_probes_software_idx on server_fingerprint_probes (software);
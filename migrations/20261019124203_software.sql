@@ -0,0 +1,6 @@
+-- see classify_software in processing/minecraft/passive_fingerprint.rs, run `matscan backfill-software`
+-- after changing the rules
+alter table servers add column software text collate "C";
+alter table servers add column software_confidence real;
+
+create index software_idx on servers (software);
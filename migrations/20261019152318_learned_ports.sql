@@ -0,0 +1,9 @@
+-- when the LearnedPorts strategy last scanned a port in a prefix ("1.2.0.0/16" or "AS13335"), so
+-- ports that were covered recently are skipped
+create table
+    learned_port_scans (
+        prefix text collate "C" not null,
+        port uint2 not null,
+        last_scanned timestamp without time zone not null,
+        primary key (prefix, port)
+    );
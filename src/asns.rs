@@ -1,6 +1,15 @@
 // download https://iptoasn.com/data/ip2asn-v4-u32.tsv.gz and cache it
 
-use std::{io::BufRead, net::Ipv4Addr, sync::OnceLock, time::Duration};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    net::Ipv4Addr,
+    path::Path,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use eyre::eyre;
 
 use crate::scanner::targets::Ipv4Range;
 
@@ -19,35 +28,42 @@ pub async fn download() -> eyre::Result<AsnRanges> {
     println!("Downloaded ASN data");
 
     let resp = resp.bytes().await?;
-    let resp = std::io::Cursor::new(resp);
-    let resp = flate2::read::GzDecoder::new(resp);
-
-    let mut ranges = Vec::new();
-    for line in std::io::BufReader::new(resp).lines() {
-        let line = line?;
-        let mut parts = line.split('\t');
-
-        let start = parts.next().unwrap();
-        let end = parts.next().unwrap();
-        let asn = parts.next().unwrap();
-
-        let start = Ipv4Addr::from(start.parse::<u32>()?);
-        let end = Ipv4Addr::from(end.parse::<u32>()?);
-        let asn = asn.parse::<u32>()?;
-
-        ranges.push((Ipv4Range { start, end }, asn));
-    }
+    // decompressing and parsing takes a while, so it's done off the async runtime
+    tokio::task::spawn_blocking(move || {
+        let resp = std::io::Cursor::new(resp);
+        let resp = flate2::read::GzDecoder::new(resp);
+        AsnRanges::from_tsv(BufReader::new(resp))
+    })
+    .await?
+}
 
-    Ok(AsnRanges(ranges))
+/// Load the table from a file instead of downloading it. The file can be
+/// gzipped like the one that's downloaded, or already extracted.
+pub fn load(path: &Path) -> eyre::Result<AsnRanges> {
+    let file = File::open(path).map_err(|err| eyre!("couldn't open {}: {err}", path.display()))?;
+    let reader: Box<dyn Read> = if path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    AsnRanges::from_tsv(BufReader::new(reader))
 }
 
-pub async fn get() -> eyre::Result<&'static AsnRanges> {
+/// Get the table, from the file at `path` if it's given and downloaded
+/// otherwise. It's only loaded once.
+pub async fn get(path: Option<&Path>) -> eyre::Result<&'static AsnRanges> {
     static ASN_RANGES: OnceLock<AsnRanges> = OnceLock::new();
 
     if let Some(ranges) = ASN_RANGES.get() {
         return Ok(ranges);
     }
 
+    if let Some(path) = path {
+        let path = path.to_owned();
+        let ranges = tokio::task::spawn_blocking(move || load(&path)).await??;
+        return Ok(ASN_RANGES.get_or_init(|| ranges));
+    }
+
     let ranges = loop {
         match download().await {
             Ok(r) => break r,
@@ -64,6 +80,29 @@ pub async fn get() -> eyre::Result<&'static AsnRanges> {
 }
 
 impl AsnRanges {
+    /// Parse the ip2asn-v4-u32.tsv format, where every line has the first IP,
+    /// the last IP, and the ASN (followed by columns that we don't use).
+    pub fn from_tsv(reader: impl BufRead) -> eyre::Result<Self> {
+        let mut ranges = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let mut parts = line.split('\t');
+
+            let (Some(start), Some(end), Some(asn)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(eyre!("invalid line in ASN table: {line:?}"));
+            };
+
+            let start = Ipv4Addr::from(start.parse::<u32>()?);
+            let end = Ipv4Addr::from(end.parse::<u32>()?);
+            let asn = asn.parse::<u32>()?;
+
+            ranges.push((Ipv4Range { start, end }, asn));
+        }
+
+        Ok(AsnRanges(ranges))
+    }
+
     pub fn get_asn(&self, ip: Ipv4Addr) -> Option<u32> {
         // do a binary search
 
@@ -120,4 +159,15 @@ mod tests {
             }]
         );
     }
+
+    #[test]
+    fn test_asns_from_tsv() {
+        let tsv = "16777216\t16777471\t13335\tUS\tCLOUDFLARENET\n16777472\t16778239\t0\tNone\tNot routed\n";
+        let asns = AsnRanges::from_tsv(tsv.as_bytes()).unwrap();
+        assert_eq!(asns.get_asn(Ipv4Addr::new(1, 0, 0, 1)), Some(13335));
+        assert_eq!(asns.get_asn(Ipv4Addr::new(1, 0, 1, 0)), Some(0));
+        assert_eq!(asns.get_asn(Ipv4Addr::new(1, 0, 4, 0)), None);
+
+        assert!(AsnRanges::from_tsv("16777216\t16777471".as_bytes()).is_err());
+    }
 }
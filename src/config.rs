@@ -1,8 +1,15 @@
-use std::{net::SocketAddrV4, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddrV4, path::PathBuf};
 
 use serde::Deserialize;
 
-use crate::scanner::SourcePort;
+use crate::{
+    database::collect_servers::CollectServersFilter,
+    scanner::{
+        PortRange,
+        protocols::{FingerprintProbe, FmlMarker},
+    },
+    scheduler::{CronSchedule, StrategyCategory},
+};
 
 #[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
@@ -10,6 +17,21 @@ pub struct Config {
     pub postgres_uri: String,
     pub rate: u64,
 
+    /// Adjust the rate while scanning based on how well we're keeping up with
+    /// the responses. `rate` is the rate that each scan starts at.
+    #[serde(default)]
+    pub adaptive_rate: AdaptiveRateConfig,
+
+    /// Limit how many packets we send to each IP and /24, so dense strategies
+    /// don't look like an attack to the people on the other end.
+    #[serde(default)]
+    pub destination_limit: DestinationLimitConfig,
+
+    /// Send more SYNs to the targets that didn't reply after the first pass,
+    /// so live servers aren't missed in small scans when packets are lost.
+    #[serde(default)]
+    pub retries: RetryConfig,
+
     /// The number of seconds to sleep after each scan. You can set this to 0
     /// if you want, but it mostly helps avoid pings being associated to the
     /// wrong strategy.
@@ -23,7 +45,7 @@ pub struct Config {
     ///
     /// This can be either a number like 61000 or a range like "61000-65535"
     #[serde(default)]
-    pub source_port: SourcePort,
+    pub source_port: PortRange,
 
     /// The maximum amount of time each scan will take. Defaults to 5 minutes.
     /// You should probably leave it as the default unless you're debugging
@@ -36,10 +58,35 @@ pub struct Config {
     #[serde(default)]
     pub ping_timeout_secs: Option<u64>,
 
+    /// Limits on the connections that the scanner keeps track of, so servers
+    /// that send a lot of data can't make us run out of memory.
+    #[serde(default)]
+    pub conn_limits: ConnLimitsConfig,
+
     pub target: TargetConfig,
 
     pub scanner: ScannerConfig,
 
+    /// Strategies that scan the blocks around known servers, defined with
+    /// `[[strategy]]`. They're picked along with the built-in ones.
+    #[serde(default, rename = "strategy")]
+    pub custom_strategies: Vec<StrategyConfig>,
+
+    /// The TopAsns strategy, which scans the ASNs with the most servers for
+    /// their size.
+    #[serde(default)]
+    pub asn_strategy: AsnStrategyConfig,
+
+    /// The LearnedPorts strategy, which scans the ports that are the most
+    /// common in each /16 or ASN.
+    #[serde(default)]
+    pub learned_ports: LearnedPortsConfig,
+
+    /// How often each kind of scan is done, and when the rate should be
+    /// lowered.
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+
     // useful if you want do be doing rescanning with different options
     #[serde(default)]
     pub rescan: RescanConfig,
@@ -61,6 +108,41 @@ pub struct Config {
     #[serde(default)]
     pub fingerprinting: FingerprintingConfig,
 
+    /// Query known servers with the GameSpy4 query protocol to get their full
+    /// player list and plugins.
+    #[serde(default)]
+    pub query: QueryConfig,
+
+    /// Try to join known servers to find out whether they're in online mode,
+    /// whitelisted, or behind a proxy.
+    #[serde(default)]
+    pub login_probe: LoginProbeConfig,
+
+    /// Ping known Forge servers again with the FML markers in the handshake, so
+    /// they send us their mods.
+    #[serde(default)]
+    pub fml_ping: FmlPingConfig,
+
+    /// Send known domains in the handshake to servers on the IPs they point to,
+    /// for proxies that give a different response depending on the hostname.
+    #[serde(default)]
+    pub virtual_hosts: VirtualHostsConfig,
+
+    /// Ping known servers again with different protocol versions, to find
+    /// servers that support more than one version.
+    #[serde(default)]
+    pub protocol_probe: ProtocolProbeConfig,
+
+    /// Retry servers that close the connection after our handshake with the
+    /// pre-1.7 server list ping.
+    #[serde(default)]
+    pub legacy_ping: LegacyPingConfig,
+
+    /// Servers that we know are up (like our own), which are pinged in every
+    /// scan to estimate how many packets our own network is losing.
+    #[serde(default)]
+    pub canaries: CanaryConfig,
+
     /// The directory where the rotating matscan.log files should be written to.
     /// None to disable logging to a file. Note that these logs aren't the same
     /// as the ones that are shown in stdout.
@@ -88,6 +170,250 @@ pub struct ScannerConfig {
     /// strategies.json.
     #[serde(default)]
     pub strategies: Option<Vec<String>>,
+    /// How the next strategy is picked based on how well the strategies did
+    /// before.
+    #[serde(default)]
+    pub bandit: BanditConfig,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BanditConfig {
+    /// How much the history of every strategy is weighted after each scan,
+    /// from 0 to 1. Lower values forget old scans faster.
+    #[serde(default = "default_bandit_decay")]
+    pub decay: f64,
+    /// How much strategies that haven't been picked in a while are favored.
+    /// 0 always picks the strategy that's done the best.
+    #[serde(default = "default_bandit_exploration")]
+    pub exploration: f64,
+    /// How many seconds of scanning every million packets counts as, so
+    /// strategies that send a lot of packets for what they find are picked
+    /// less.
+    #[serde(default)]
+    pub secs_per_million_packets: f64,
+}
+impl Default for BanditConfig {
+    fn default() -> Self {
+        Self {
+            decay: default_bandit_decay(),
+            exploration: default_bandit_exploration(),
+            secs_per_million_packets: 0.,
+        }
+    }
+}
+fn default_bandit_decay() -> f64 {
+    0.98
+}
+fn default_bandit_exploration() -> f64 {
+    0.5
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StrategyConfig {
+    /// The name of the strategy in strategies.json and `scanner.strategies`.
+    /// It can't be the same as a built-in strategy.
+    pub name: String,
+    /// The size of the blocks around known servers that are scanned, from 8
+    /// (a /8) to 32 (only the IPs of known servers).
+    pub prefix_len: u8,
+    /// The ports to scan in each block, like `[25565, { min = 25560, max =
+    /// 25570 }]`.
+    pub ports: Vec<PortRange>,
+    /// Only scan blocks with at least this many known servers.
+    #[serde(default = "default_strategy_min_servers")]
+    pub min_servers: usize,
+    /// Which known servers are used, `active30d`, `active365d`, or `new`.
+    #[serde(default = "default_strategy_filter")]
+    pub filter: CollectServersFilter,
+    /// Also scan every IP on port 25565 at the same time, so the blocks
+    /// aren't sent so many packets at once.
+    #[serde(default)]
+    pub pad_slash0: bool,
+}
+fn default_strategy_min_servers() -> usize {
+    1
+}
+fn default_strategy_filter() -> CollectServersFilter {
+    CollectServersFilter::Active365d
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AsnStrategyConfig {
+    /// A copy of ip2asn-v4-u32.tsv from iptoasn.com, gzipped or not. It's
+    /// downloaded if this isn't set.
+    #[serde(default)]
+    pub table_path: Option<PathBuf>,
+    /// The number of ASNs that are scanned.
+    #[serde(default = "default_asn_strategy_top")]
+    pub top: usize,
+    /// The number of ports that are scanned in each ASN, picked by how many
+    /// known servers are on them.
+    #[serde(default = "default_asn_strategy_ports")]
+    pub ports: usize,
+    /// ASNs with fewer known servers than this aren't scanned, so a tiny ASN
+    /// with a couple of servers doesn't look dense.
+    #[serde(default = "default_asn_strategy_min_servers")]
+    pub min_servers: usize,
+}
+impl Default for AsnStrategyConfig {
+    fn default() -> Self {
+        Self {
+            table_path: None,
+            top: default_asn_strategy_top(),
+            ports: default_asn_strategy_ports(),
+            min_servers: default_asn_strategy_min_servers(),
+        }
+    }
+}
+fn default_asn_strategy_top() -> usize {
+    10
+}
+fn default_asn_strategy_ports() -> usize {
+    3
+}
+fn default_asn_strategy_min_servers() -> usize {
+    50
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LearnedPortsConfig {
+    /// Whether the ports are counted per /16 or per ASN. ASNs use the table
+    /// from `asn_strategy.table_path`.
+    #[serde(default)]
+    pub group_by: PortGroupBy,
+    /// The number of ports that are scanned in each prefix, picked by how
+    /// many known servers are on them.
+    #[serde(default = "default_learned_ports_top")]
+    pub top: usize,
+    /// Ports with fewer known servers than this in a prefix aren't scanned.
+    #[serde(default = "default_learned_ports_min_servers")]
+    pub min_servers: usize,
+    /// Ports that were scanned in a prefix more recently than this are
+    /// skipped.
+    #[serde(default = "default_learned_ports_rescan_every_secs")]
+    pub rescan_every_secs: u64,
+    /// The maximum number of prefix and port pairs in each scan. The ones
+    /// with the most known servers go first.
+    #[serde(default = "default_learned_ports_limit")]
+    pub limit: usize,
+    /// The maximum number of addresses in each scan, so the picked pairs all
+    /// get scanned before they're marked as scanned. Defaults to the number
+    /// of packets that can be sent in `scan_duration_secs` at `rate`.
+    #[serde(default)]
+    pub max_targets: Option<u64>,
+}
+impl Default for LearnedPortsConfig {
+    fn default() -> Self {
+        Self {
+            group_by: PortGroupBy::default(),
+            top: default_learned_ports_top(),
+            min_servers: default_learned_ports_min_servers(),
+            rescan_every_secs: default_learned_ports_rescan_every_secs(),
+            limit: default_learned_ports_limit(),
+            max_targets: None,
+        }
+    }
+}
+fn default_learned_ports_top() -> usize {
+    5
+}
+fn default_learned_ports_min_servers() -> usize {
+    2
+}
+fn default_learned_ports_rescan_every_secs() -> u64 {
+    60 * 60 * 24 * 7
+}
+fn default_learned_ports_limit() -> usize {
+    1000
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PortGroupBy {
+    #[default]
+    Slash16,
+    Asn,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// How often each strategy category is picked compared to the others,
+    /// like `{ normal = 3, rescan = 1 }`. Enabled categories that aren't listed
+    /// have a weight of 1, and a weight of 0 means the category is only done
+    /// when it's scheduled.
+    #[serde(default)]
+    pub weights: HashMap<StrategyCategory, u32>,
+    /// Rescan profiles that should run on their own schedule instead of
+    /// taking turns with the other rescan profiles.
+    #[serde(default)]
+    pub rescan: Vec<RescanScheduleConfig>,
+    /// Times of day (in UTC) when the rate should be lowered.
+    #[serde(default)]
+    pub quiet_hours: Vec<QuietHoursConfig>,
+    /// The priority of the scans picked by the schedule, compared to
+    /// concurrent rescans. Sessions with a higher priority send first and get
+    /// the rate that other sessions aren't using.
+    #[serde(default)]
+    pub priority: u8,
+    /// How much of the rate the scans picked by the schedule get while
+    /// concurrent rescans are running.
+    #[serde(default = "default_rate_share")]
+    pub rate_share: f64,
+}
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            weights: HashMap::new(),
+            rescan: Vec::new(),
+            quiet_hours: Vec::new(),
+            priority: 0,
+            rate_share: default_rate_share(),
+        }
+    }
+}
+fn default_rate_share() -> f64 {
+    1.
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RescanScheduleConfig {
+    /// The name of the rescan config, like `rescan2`.
+    pub profile: String,
+    /// When to run it, like `*/10 * * * *` for every 10 minutes.
+    pub cron: CronSchedule,
+    /// Run it at the same time as the other scans instead of waiting for its
+    /// turn, so it keeps its cadence during long scans.
+    #[serde(default)]
+    pub concurrent: bool,
+    /// The priority of the rescan compared to the other scans that are running
+    /// at the same time, if it's concurrent.
+    #[serde(default = "default_concurrent_rescan_priority")]
+    pub priority: u8,
+    /// How much of the rate the rescan gets compared to the other scans that
+    /// are running at the same time, if it's concurrent.
+    #[serde(default = "default_rate_share")]
+    pub rate_share: f64,
+}
+fn default_concurrent_rescan_priority() -> u8 {
+    1
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHoursConfig {
+    /// The hour (0-23, UTC) that the quiet hours start at.
+    pub start_hour: u32,
+    /// The hour (0-23, UTC) that the quiet hours end at. This can be before
+    /// `start_hour` if the quiet hours go past midnight.
+    pub end_hour: u32,
+    /// The maximum packets per second while in quiet hours.
+    pub rate: u64,
 }
 
 #[derive(Deserialize, Default, Clone)]
@@ -132,7 +458,40 @@ pub struct SnipeConfig {
     pub anon_players: bool,
 }
 
-#[derive(Deserialize, Clone, Default)]
+/// How the strategies that follow up on known servers pick them, see
+/// [`crate::strategies::follow_up`]. This is the `follow_up` table in each of
+/// their sections.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FollowUpConfig {
+    /// The minimum number of seconds before the same server is picked again.
+    /// Defaults to 7 days.
+    #[serde(default = "default_follow_up_every_secs")]
+    pub every_secs: u64,
+    /// The maximum number of seconds since the last ping to consider a server.
+    #[serde(default = "default_last_ping_ago_max_secs")]
+    pub last_ping_ago_max_secs: u64,
+    /// The maximum number of servers to pick each time.
+    #[serde(default = "default_follow_up_limit")]
+    pub limit: usize,
+}
+impl Default for FollowUpConfig {
+    fn default() -> Self {
+        Self {
+            every_secs: default_follow_up_every_secs(),
+            last_ping_ago_max_secs: default_last_ping_ago_max_secs(),
+            limit: default_follow_up_limit(),
+        }
+    }
+}
+fn default_follow_up_every_secs() -> u64 {
+    60 * 60 * 24 * 7
+}
+fn default_follow_up_limit() -> usize {
+    100_000
+}
+
+#[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct FingerprintingConfig {
     /// Test quirks with the server's protocol implementation. This may cause
@@ -141,6 +500,370 @@ pub struct FingerprintingConfig {
     /// If this is false then passive fingerprinting is still done but it won't
     /// be able to gather as much information as active fingerprinting.
     pub enabled: bool,
+    /// The kinds of invalid packets to send. Each probe is a separate scan.
+    /// Defaults to all of them: `["login_start", "invalid_next_state",
+    /// "oversized_packet", "malformed_handshake"]`.
+    #[serde(default = "default_fingerprint_probes")]
+    pub probes: Vec<FingerprintProbe>,
+    /// Which servers are picked and how often, see [`FollowUpConfig`].
+    #[serde(default)]
+    pub follow_up: FollowUpConfig,
+}
+impl Default for FingerprintingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            probes: default_fingerprint_probes(),
+            follow_up: FollowUpConfig::default(),
+        }
+    }
+}
+fn default_fingerprint_probes() -> Vec<FingerprintProbe> {
+    vec![
+        FingerprintProbe::LoginStart,
+        FingerprintProbe::InvalidNextState,
+        FingerprintProbe::OversizedPacket,
+        FingerprintProbe::MalformedHandshake,
+    ]
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QueryConfig {
+    pub enabled: bool,
+    /// Which servers are picked and how often, see [`FollowUpConfig`].
+    #[serde(default)]
+    pub follow_up: FollowUpConfig,
+    /// How many servers we query at the same time.
+    #[serde(default = "default_query_concurrency")]
+    pub concurrency: usize,
+    /// How long to wait for each UDP response.
+    #[serde(default = "default_query_timeout_ms")]
+    pub timeout_ms: u64,
+}
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            follow_up: FollowUpConfig::default(),
+            concurrency: default_query_concurrency(),
+            timeout_ms: default_query_timeout_ms(),
+        }
+    }
+}
+fn default_query_concurrency() -> usize {
+    1000
+}
+fn default_query_timeout_ms() -> u64 {
+    3000
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LoginProbeConfig {
+    /// Whether we should send a login start packet to known servers. This will
+    /// show up in the server's console as a player joining (or failing to
+    /// join).
+    pub enabled: bool,
+    /// The username that we try to join with. Offline-mode servers will see
+    /// the offline UUID for this name.
+    #[serde(default = "default_login_probe_username")]
+    pub username: String,
+    /// Which servers are picked and how often, see [`FollowUpConfig`].
+    #[serde(default)]
+    pub follow_up: FollowUpConfig,
+}
+impl Default for LoginProbeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            username: default_login_probe_username(),
+            follow_up: FollowUpConfig::default(),
+        }
+    }
+}
+fn default_login_probe_username() -> String {
+    "matscan".to_string()
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FmlPingConfig {
+    pub enabled: bool,
+    /// The markers to try, in order. Each marker is a separate scan, and if
+    /// more than one of them gets mods from a server then the last one is
+    /// recorded. Defaults to `["FML", "FML2", "FML3"]`.
+    #[serde(default = "default_fml_markers")]
+    pub markers: Vec<FmlMarker>,
+    /// Which servers are picked and how often, see [`FollowUpConfig`].
+    #[serde(default)]
+    pub follow_up: FollowUpConfig,
+}
+impl Default for FmlPingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            markers: default_fml_markers(),
+            follow_up: FollowUpConfig::default(),
+        }
+    }
+}
+fn default_fml_markers() -> Vec<FmlMarker> {
+    vec![FmlMarker::Fml, FmlMarker::Fml2, FmlMarker::Fml3]
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualHostsConfig {
+    pub enabled: bool,
+    /// Domains that are resolved and added to the known_domains table every
+    /// time we probe virtual hosts. More domains can be imported from a file
+    /// with `matscan import-domains <file>`.
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Which domains are picked and how often, see [`FollowUpConfig`]. The
+    /// interval and limit apply to each domain instead of each server.
+    #[serde(default)]
+    pub follow_up: FollowUpConfig,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolProbeConfig {
+    pub enabled: bool,
+    /// The protocol versions to ping with. Each version is a separate scan.
+    /// Defaults to 1.7.2, 1.8, 1.12.2, 1.16.5, and 1.21.4.
+    #[serde(default = "default_probe_protocol_versions")]
+    pub protocol_versions: Vec<i32>,
+    /// Which servers are picked and how often, see [`FollowUpConfig`].
+    #[serde(default)]
+    pub follow_up: FollowUpConfig,
+}
+impl Default for ProtocolProbeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            protocol_versions: default_probe_protocol_versions(),
+            follow_up: FollowUpConfig::default(),
+        }
+    }
+}
+fn default_probe_protocol_versions() -> Vec<i32> {
+    vec![4, 47, 340, 754, 769]
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LegacyPingConfig {
+    /// Whether servers that accepted the connection but closed it without
+    /// responding to the modern server list ping should be pinged again with
+    /// the legacy (pre-1.7) ping right after each scan.
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveRateConfig {
+    pub enabled: bool,
+    /// The rate won't go below this, even if we keep dropping packets.
+    #[serde(default = "default_adaptive_min_rate")]
+    pub min_rate: u64,
+    /// The rate won't go above this. Defaults to the normal `rate`.
+    #[serde(default)]
+    pub max_rate: Option<u64>,
+    /// Back off if the kernel drops more than this many received packets per
+    /// second because we aren't reading them fast enough.
+    #[serde(default = "default_adaptive_max_rx_drops_per_sec")]
+    pub max_rx_drops_per_sec: u64,
+    /// Back off if the ratio of SYN+ACKs to SYNs falls below this fraction of
+    /// the best ratio seen in the scan, which usually means packets are being
+    /// dropped somewhere on the network.
+    #[serde(default = "default_adaptive_min_syn_ack_ratio")]
+    pub min_syn_ack_ratio: f64,
+    /// Back off if there are more than this many responses waiting to be
+    /// processed.
+    #[serde(default = "default_adaptive_max_queue_len")]
+    pub max_queue_len: usize,
+    /// Back off if inserting a response into the database takes longer than
+    /// this on average.
+    #[serde(default = "default_adaptive_max_db_latency_ms")]
+    pub max_db_latency_ms: u64,
+    /// What the rate is multiplied by when we back off.
+    #[serde(default = "default_adaptive_backoff_factor")]
+    pub backoff_factor: f64,
+    /// What the rate is multiplied by every second that nothing is wrong.
+    #[serde(default = "default_adaptive_ramp_up_factor")]
+    pub ramp_up_factor: f64,
+}
+impl Default for AdaptiveRateConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_rate: default_adaptive_min_rate(),
+            max_rate: None,
+            max_rx_drops_per_sec: default_adaptive_max_rx_drops_per_sec(),
+            min_syn_ack_ratio: default_adaptive_min_syn_ack_ratio(),
+            max_queue_len: default_adaptive_max_queue_len(),
+            max_db_latency_ms: default_adaptive_max_db_latency_ms(),
+            backoff_factor: default_adaptive_backoff_factor(),
+            ramp_up_factor: default_adaptive_ramp_up_factor(),
+        }
+    }
+}
+fn default_adaptive_min_rate() -> u64 {
+    1000
+}
+fn default_adaptive_max_rx_drops_per_sec() -> u64 {
+    100
+}
+fn default_adaptive_min_syn_ack_ratio() -> f64 {
+    0.5
+}
+fn default_adaptive_max_queue_len() -> usize {
+    100_000
+}
+fn default_adaptive_max_db_latency_ms() -> u64 {
+    50
+}
+fn default_adaptive_backoff_factor() -> f64 {
+    0.7
+}
+fn default_adaptive_ramp_up_factor() -> f64 {
+    1.05
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryConfig {
+    /// The addresses of the canaries, like `["203.0.113.5:25565"]`. They
+    /// should respond to the server list ping.
+    #[serde(default)]
+    pub targets: Vec<SocketAddrV4>,
+    /// Alert when more than this fraction of the canaries (from 0 to 1) don't
+    /// respond in a scan.
+    #[serde(default)]
+    pub alert_loss: Option<f64>,
+    /// The Discord webhook that alerts are sent to. Alerts are only logged if
+    /// this isn't set.
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConnLimitsConfig {
+    /// The maximum number of connections that can be open at once. When
+    /// there's too many, the one that we heard from least recently is closed.
+    #[serde(default = "default_max_conns")]
+    pub max_conns: usize,
+    /// The maximum number of bytes that a single connection can buffer before
+    /// its response is done. Connections that send more are closed with an
+    /// RST.
+    #[serde(default = "default_max_conn_bytes")]
+    pub max_conn_bytes: usize,
+    /// The maximum number of bytes that all connections together can buffer.
+    /// When there's more, the connections that we heard from least recently
+    /// are closed.
+    #[serde(default = "default_max_total_bytes")]
+    pub max_total_bytes: usize,
+}
+impl Default for ConnLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_conns: default_max_conns(),
+            max_conn_bytes: default_max_conn_bytes(),
+            max_total_bytes: default_max_total_bytes(),
+        }
+    }
+}
+fn default_max_conns() -> usize {
+    1_000_000
+}
+fn default_max_conn_bytes() -> usize {
+    // status responses are limited to 32767 characters, so this is plenty
+    1024 * 1024
+}
+fn default_max_total_bytes() -> usize {
+    1024 * 1024 * 1024
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    pub enabled: bool,
+    /// Only scans with at most this many targets are retried, since we have
+    /// to remember which targets replied.
+    #[serde(default = "default_retry_max_targets")]
+    pub max_targets: u64,
+    /// The number of extra SYNs that each target that didn't reply gets.
+    #[serde(default = "default_retry_passes")]
+    pub passes: u32,
+    /// How long to wait for SYN+ACKs after each pass before retrying the
+    /// targets that didn't reply.
+    #[serde(default = "default_retry_delay_ms")]
+    pub delay_ms: u64,
+}
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_targets: default_retry_max_targets(),
+            passes: default_retry_passes(),
+            delay_ms: default_retry_delay_ms(),
+        }
+    }
+}
+fn default_retry_max_targets() -> u64 {
+    10_000_000
+}
+fn default_retry_passes() -> u32 {
+    1
+}
+fn default_retry_delay_ms() -> u64 {
+    2000
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DestinationLimitConfig {
+    pub enabled: bool,
+    /// The number of packets per second that can be sent to a single IP.
+    #[serde(default = "default_per_ip_rate")]
+    pub per_ip_rate: u64,
+    /// The number of packets that can be sent to a single IP at once before
+    /// `per_ip_rate` applies.
+    #[serde(default = "default_per_ip_burst")]
+    pub per_ip_burst: u64,
+    /// The number of packets per second that can be sent to a single /24.
+    #[serde(default = "default_per_slash24_rate")]
+    pub per_slash24_rate: u64,
+    /// The number of packets that can be sent to a single /24 at once before
+    /// `per_slash24_rate` applies.
+    #[serde(default = "default_per_slash24_burst")]
+    pub per_slash24_burst: u64,
+}
+impl Default for DestinationLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            per_ip_rate: default_per_ip_rate(),
+            per_ip_burst: default_per_ip_burst(),
+            per_slash24_rate: default_per_slash24_rate(),
+            per_slash24_burst: default_per_slash24_burst(),
+        }
+    }
+}
+fn default_per_ip_rate() -> u64 {
+    50
+}
+fn default_per_ip_burst() -> u64 {
+    100
+}
+fn default_per_slash24_rate() -> u64 {
+    500
+}
+fn default_per_slash24_burst() -> u64 {
+    1000
 }
 
 #[derive(Deserialize, Default, Clone)]
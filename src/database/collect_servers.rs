@@ -6,6 +6,7 @@ use std::{
 use chrono::TimeDelta;
 use futures_util::TryStreamExt;
 use rustc_hash::FxHashMap;
+use serde::Deserialize;
 use sqlx::Row;
 use tracing::info;
 
@@ -48,7 +49,8 @@ pub enum UpdateResult {
     Updated,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CollectServersFilter {
     /// Was alive in the past 30 days
     Active30d,
@@ -0,0 +1,67 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use futures_util::StreamExt;
+use sqlx::QueryBuilder;
+use tracing::{debug, info};
+
+use crate::database::{Database, PgU32};
+
+impl Database {
+    /// Resolve the domains and remember which IPs they point to, so we can
+    /// send them in the handshake to servers on those IPs later.
+    ///
+    /// The source is just for keeping track of where the domain came from,
+    /// like "config" or the name of the file it was imported from. Returns the
+    /// number of (ip, domain) pairs that were added or updated.
+    pub async fn add_known_domains(&self, domains: &[String], source: &str) -> eyre::Result<usize> {
+        let mut resolved = futures_util::stream::iter(domains)
+            .map(|domain| async move {
+                let domain = domain.trim().trim_end_matches('.').to_lowercase();
+                // the port doesn't matter, we only care about the ip
+                let addrs = tokio::net::lookup_host((domain.as_str(), 25565))
+                    .await
+                    .map(|addrs| addrs.collect::<Vec<_>>());
+                (domain, addrs)
+            })
+            .buffer_unordered(100);
+
+        let mut ips_and_domains: Vec<(Ipv4Addr, String)> = Vec::new();
+        while let Some((domain, addrs)) = resolved.next().await {
+            let addrs = match addrs {
+                Ok(addrs) => addrs,
+                Err(err) => {
+                    debug!("failed to resolve {domain}: {err}");
+                    continue;
+                }
+            };
+            for addr in addrs {
+                if let IpAddr::V4(ip) = addr.ip() {
+                    ips_and_domains.push((ip, domain.clone()));
+                }
+            }
+        }
+        ips_and_domains.sort();
+        ips_and_domains.dedup();
+
+        let mut added = 0;
+        for chunk in ips_and_domains.chunks(10_000) {
+            let mut query_builder =
+                QueryBuilder::new("INSERT INTO known_domains (ip, domain, source) ");
+            query_builder.push_values(chunk, |mut b, (ip, domain)| {
+                b.push_bind(PgU32(ip.to_bits()))
+                    .push_bind(domain.clone())
+                    .push_bind(source.to_owned());
+            });
+            query_builder.push(" ON CONFLICT (ip, domain) DO UPDATE SET last_resolved = NOW()");
+            let res = query_builder.build().execute(&self.pool).await?;
+            added += res.rows_affected() as usize;
+        }
+
+        info!(
+            "Added {added} known domains from {source} ({} domains given)",
+            domains.len()
+        );
+
+        Ok(added)
+    }
+}
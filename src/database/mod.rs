@@ -1,4 +1,5 @@
 pub mod collect_servers;
+pub mod known_domains;
 pub mod migrate_mongo_to_postgres;
 
 use std::{
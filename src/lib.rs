@@ -4,7 +4,9 @@ pub mod database;
 pub mod exclude;
 pub mod net;
 pub mod processing;
+pub mod query;
 pub mod scanner;
+pub mod scheduler;
 pub mod strategies;
 pub mod terminal_colors;
 pub mod tracing;
@@ -1,37 +1,41 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    env, fs, path,
-    str::FromStr,
+    collections::HashMap,
+    env, fs, mem,
+    net::SocketAddrV4,
+    path,
     sync::{Arc, atomic::AtomicBool},
     thread,
     time::{Duration, Instant},
 };
 
+use chrono::Utc;
 use dotenv::dotenv;
 use matscan::{
-    config::{Config, RescanConfig},
+    config::{CanaryConfig, Config, RescanConfig, RescanScheduleConfig},
     database::{Database, migrate_mongo_to_postgres},
     exclude,
-    net::tcp::StatelessTcpWriteHalf,
-    processing::{SharedData, process_pings},
+    processing::{
+        ProcessableProtocol, SharedData,
+        minecraft::{offline_uuid, passive_fingerprint, snipe::send_to_webhook},
+        process_pings,
+    },
     scanner::{
-        ScanSession, Scanner, ScannerReceiver,
-        protocols::{self},
+        ReceiverLane, ScanSession, Scanner, ScannerReceiver,
+        canary::CanaryReport,
+        destination_limit::DestinationLimiter,
+        protocols::{self, Protocol, ProtocolRegistry},
+        retry::SynAckBitmap,
+        sender::{ScanSender, SessionOptions, SessionSummary},
         targets::{Ipv4Range, Ipv4Ranges, ScanRange, ScanRanges},
+        throttle::{AdaptiveRate, RateFeedback},
     },
-    strategies::{ScanStrategy, StrategyPicker},
+    scheduler::{Scheduler, StrategyCategory},
+    strategies::{StrategyId, StrategyPicker},
     terminal_colors::*,
     tracing::init_tracing,
 };
 use parking_lot::{Mutex, RwLock};
-use tracing::info;
-
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-enum StrategyCategory {
-    Normal,
-    Rescan,
-    Fingerprint,
-}
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -54,8 +58,32 @@ async fn main() -> eyre::Result<()> {
         return Ok(());
     }
 
-    // first command line argument is the location of the config file
-    let config_file = args.get(1).cloned().unwrap_or("config.toml".to_string());
+    let is_import_domains = args.get(1) == Some(&"import-domains".to_string());
+    let domains_file = if is_import_domains {
+        Some(
+            args.get(2)
+                .cloned()
+                .expect("domains file must be the second argument"),
+        )
+    } else {
+        None
+    };
+
+    let is_backfill_software = args.get(1) == Some(&"backfill-software".to_string());
+
+    // first command line argument is the location of the config file (or after
+    // the command's arguments if we're running a command)
+    let config_file_arg_index = if is_import_domains {
+        3
+    } else if is_backfill_software {
+        2
+    } else {
+        1
+    };
+    let config_file = args
+        .get(config_file_arg_index)
+        .cloned()
+        .unwrap_or("config.toml".to_string());
 
     let config_file_path = path::Path::new(&config_file).canonicalize()?;
     println!(
@@ -63,10 +91,32 @@ async fn main() -> eyre::Result<()> {
         config_file_path.as_os_str().to_string_lossy()
     );
     let config: Config = toml::from_str(&fs::read_to_string(config_file_path)?)?;
+    matscan::strategies::check_custom_strategies(&config)?;
 
     init_tracing(&config);
     info!("Logging initialized");
 
+    if let Some(domains_file) = domains_file {
+        // one domain per line
+        let domains = fs::read_to_string(&domains_file)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        let database = Database::connect(&config.postgres_uri).await?;
+        let added = database.add_known_domains(&domains, &domains_file).await?;
+        println!("Added {added} known domains.");
+        return Ok(());
+    }
+
+    if is_backfill_software {
+        let database = Database::connect(&config.postgres_uri).await?;
+        passive_fingerprint::backfill_software(&database).await?;
+        println!("Done.");
+        return Ok(());
+    }
+
     println!("parsing exclude file");
     let mut exclude_ranges = exclude::parse_file("exclude.conf")?;
     println!(
@@ -81,19 +131,35 @@ async fn main() -> eyre::Result<()> {
         config.target.protocol_version,
     );
 
+    let minecraft_legacy_protocol =
+        protocols::MinecraftLegacy::new(&config.target.addr, config.target.port);
+
     let database = Database::connect(&config.postgres_uri).await?;
     let scanner = Scanner::new(&config);
-    let mut strategy_picker = StrategyPicker::default();
+    let mut strategy_picker = StrategyPicker::new(&config);
 
-    // the number of times we've done a scan, used for switching between different
-    // strategy categories (rescanning and scanning)
-    let mut i = 0;
-
-    let rescan_enabled = config.rescan.enabled
-        || config.rescan2.enabled
-        || config.rescan3.enabled
-        || config.rescan4.enabled
-        || config.rescan5.enabled;
+    for schedule in &config.schedule.rescan {
+        if !rescan_profiles(&config)
+            .iter()
+            .any(|(name, rescan_config)| *name == schedule.profile && rescan_config.enabled)
+        {
+            panic!(
+                "rescan profile {:?} in config.schedule.rescan doesn't exist or isn't enabled",
+                schedule.profile
+            );
+        }
+    }
+    // profiles that have their own schedule aren't done with the others
+    let rescan_enabled = rescan_profiles(&config)
+        .iter()
+        .any(|(name, rescan_config)| {
+            rescan_config.enabled
+                && !config
+                    .schedule
+                    .rescan
+                    .iter()
+                    .any(|schedule| schedule.profile == *name)
+        });
 
     // used by the sender loop
     let scanner_seed = scanner.seed;
@@ -101,7 +167,7 @@ async fn main() -> eyre::Result<()> {
 
     let has_ended = Arc::new(AtomicBool::new(false));
 
-    // we pick a different strategy category each scan
+    // the scheduler picks a strategy category for each scan
     let mut strategy_categories = vec![];
     if config.scanner.enabled {
         strategy_categories.push(StrategyCategory::Normal);
@@ -112,6 +178,21 @@ async fn main() -> eyre::Result<()> {
     if config.fingerprinting.enabled {
         strategy_categories.push(StrategyCategory::Fingerprint);
     }
+    if config.query.enabled {
+        strategy_categories.push(StrategyCategory::Query);
+    }
+    if config.login_probe.enabled {
+        strategy_categories.push(StrategyCategory::LoginProbe);
+    }
+    if config.fml_ping.enabled {
+        strategy_categories.push(StrategyCategory::FmlPing);
+    }
+    if config.virtual_hosts.enabled {
+        strategy_categories.push(StrategyCategory::VirtualHosts);
+    }
+    if config.protocol_probe.enabled {
+        strategy_categories.push(StrategyCategory::ProtocolProbe);
+    }
 
     if config.debug.only_scan_addr.is_some() {
         info!(
@@ -121,63 +202,139 @@ async fn main() -> eyre::Result<()> {
         exclude_ranges = Ipv4Ranges::default();
     }
 
-    if strategy_categories.is_empty() {
+    let mut scheduler = if config.debug.only_scan_addr.is_some() {
+        Scheduler::new(&Default::default(), &strategy_categories, Utc::now())
+    } else {
+        Scheduler::new(&config.schedule, &strategy_categories, Utc::now())
+    };
+
+    if scheduler.is_empty() && !config.schedule.rescan.iter().any(|s| s.concurrent) {
         panic!(
-            "Scanner, rescanner, and fingerprinting are all disabled in the config. You should probably at least enable scanner."
+            "Scanner, rescanner, fingerprinting, querying, login probing, FML pinging, virtual host probing, and protocol probing are all disabled in the config. You should probably at least enable scanner."
         );
     }
 
     // the protocol set here will be overwritten later so it doesn't actually matter
-    let protocol: Arc<RwLock<Box<dyn protocols::Protocol>>> =
-        Arc::new(RwLock::new(Box::new(minecraft_protocol.clone())));
-
-    let shared_process_data: Arc<Mutex<SharedData>> = Arc::new(Mutex::new(SharedData {
-        database: database.clone(),
-        queue: VecDeque::new(),
-        // we use the cache to check if someone just joined a server, so this
-        // will always stay empty if snipe mode is off
-        cached_players_for_sniping: HashMap::new(),
+    let protocol = Arc::new(RwLock::new(protocol_registry(
+        &config,
+        minecraft_protocol.clone(),
+    )));
 
-        total_new: 0,
-        total_new_on_default_port: 0,
-        revived: 0,
-        results: 0,
-
-        is_processing: false,
-    }));
+    let rate_feedback = Arc::new(RateFeedback::default());
+    let scan_sender = ScanSender::spawn(
+        scanner_writer,
+        config.rate,
+        config
+            .adaptive_rate
+            .enabled
+            .then(|| AdaptiveRate::new(&config.adaptive_rate, config.rate, rate_feedback.clone())),
+        config
+            .destination_limit
+            .enabled
+            .then(|| DestinationLimiter::new(&config.destination_limit)),
+    );
+    let mut concurrent_lanes = Vec::new();
+    let shared_process_data = Arc::new(Mutex::new(SharedData::new(
+        database.clone(),
+        rate_feedback.clone(),
+    )));
+
+    // rescan profiles that run at the same time as everything else get their own
+    // protocol and processing, so they're a separate lane in the receiver
+    let mut concurrent_rescans = Vec::new();
+    for schedule in config.schedule.rescan.iter().filter(|s| s.concurrent) {
+        let lane = ReceiverLane {
+            seed: rand::random(),
+            protocol: Arc::new(RwLock::new(protocol_registry(
+                &config,
+                minecraft_protocol.clone(),
+            ))),
+            shared_process_data: Arc::new(Mutex::new(SharedData::new(
+                database.clone(),
+                rate_feedback.clone(),
+            ))),
+            syn_acks: Arc::new(RwLock::new(None)),
+        };
+        concurrent_rescans.push((
+            schedule.clone(),
+            lane.seed,
+            lane.protocol.clone(),
+            lane.shared_process_data.clone(),
+            lane.syn_acks.clone(),
+        ));
+        concurrent_lanes.push(lane);
+    }
 
-    let mut receiver = ScannerReceiver {
+    let syn_acks = Arc::new(RwLock::new(None));
+    let mut lanes = vec![ReceiverLane {
+        seed: scanner_seed,
         protocol: protocol.clone(),
         shared_process_data: shared_process_data.clone(),
+        syn_acks: syn_acks.clone(),
+    }];
+    lanes.extend(concurrent_lanes);
+    let mut receiver = ScannerReceiver {
+        lanes,
         scanner,
         has_ended: has_ended.clone(),
+        rate_feedback,
+        canaries: config.canaries.targets.iter().copied().collect(),
         simulate_rx_loss: config.debug.simulate_rx_loss,
     };
-    let recv_loop_thread = thread::spawn(move || {
-        receiver.recv_loop(Duration::from_secs(config.ping_timeout_secs.unwrap_or(60)))
-    });
+    let recv_loop_thread = thread::spawn(move || receiver.recv_loop());
 
-    let mut processing_task = ProcessingTask::new(shared_process_data.clone(), config.clone());
+    // responses are handled by the protocol they came from, so this keeps running
+    // when the protocol changes
+    tokio::spawn(process_pings(shared_process_data.clone(), config.clone()));
 
     // make sure the strategies in config.scanner.strategies are valid
     let scan_strategies = config.scanner.strategies.as_ref().map(|strategies| {
         strategies
             .iter()
             .map(|strat| {
-                ScanStrategy::from_str(strat).unwrap_or_else(|_| {
+                strategy_picker.find(strat).unwrap_or_else(|| {
                     panic!("invalid strategy {strat:?} in config.scanner.strategies")
                 })
             })
             .collect::<Vec<_>>()
     });
 
+    let exclude_ranges = Arc::new(exclude_ranges);
+    for (schedule, seed, protocol, shared_process_data, syn_acks) in concurrent_rescans {
+        let ctx = ScanContext {
+            exclude_ranges: exclude_ranges.clone(),
+            database: database.clone(),
+            scan_sender: scan_sender.clone(),
+            config: config.clone(),
+            session: SessionOptions {
+                name: schedule.profile.clone(),
+                seed,
+                priority: schedule.priority,
+                rate_share: schedule.rate_share,
+                scan_duration_secs: config.scan_duration_secs.unwrap_or(60 * 5),
+            },
+            protocol,
+            shared_process_data,
+            syn_acks,
+        };
+        tokio::spawn(run_concurrent_rescan(ctx, schedule));
+    }
+
     let mut ctx = ScanContext {
         exclude_ranges,
         database,
-        scanner_writer,
+        scan_sender,
+        session: SessionOptions {
+            name: "main".to_owned(),
+            seed: scanner_seed,
+            priority: config.schedule.priority,
+            rate_share: config.schedule.rate_share,
+            scan_duration_secs: config.scan_duration_secs.unwrap_or(60 * 5),
+        },
         config,
-        scanner_seed,
+        protocol,
         shared_process_data,
+        syn_acks,
     };
 
     loop {
@@ -185,17 +342,31 @@ async fn main() -> eyre::Result<()> {
 
         let mut ranges = ScanRanges::default();
 
-        let strategy_category = strategy_categories[i % strategy_categories.len()];
-        i += 1;
+        let now = Utc::now();
+        let scheduled_scan = match scheduler.next(now) {
+            Ok(scheduled_scan) => scheduled_scan,
+            Err(wait_time) => {
+                println!("nothing to scan, waiting {}s", wait_time.num_seconds());
+                tokio::time::sleep(wait_time.to_std().unwrap_or_default()).await;
+                continue;
+            }
+        };
+        let strategy_category = scheduled_scan.category;
+
+        let quiet_rate = scheduler.quiet_rate(now);
+        if let Some(quiet_rate) = quiet_rate {
+            println!("in quiet hours, limiting rate to {quiet_rate} pps");
+        }
+        ctx.scan_sender.set_rate_cap(quiet_rate);
 
         // if the strategy is none then that means it's a special strategy (either
         // rescanning or fingerprinting)
-        let mut strategy: Option<ScanStrategy> = None;
+        let mut strategy: Option<StrategyId> = None;
         match strategy_category {
             StrategyCategory::Normal => {
                 let chosen_strategy = strategy_picker.pick_strategy(scan_strategies.clone());
 
-                println!("chosen strategy: {chosen_strategy:?}");
+                println!("chosen strategy: {}", strategy_picker.name(chosen_strategy));
 
                 let get_ranges_start = Instant::now();
                 ranges.extend(
@@ -207,25 +378,33 @@ async fn main() -> eyre::Result<()> {
                 println!("get_ranges took {:?}", get_ranges_end - get_ranges_start);
 
                 strategy = Some(chosen_strategy);
-                *protocol.write() = Box::new(minecraft_protocol.clone());
-                processing_task.set_protocol::<protocols::Minecraft>();
+                *ctx.protocol.write() = protocol_registry(&ctx.config, minecraft_protocol.clone());
             }
             StrategyCategory::Rescan => {
-                println!("chosen strategy: rescanning");
-
                 // add the ranges we're rescanning
-                for rescan_config in [
-                    &ctx.config.rescan,
-                    &ctx.config.rescan2,
-                    &ctx.config.rescan3,
-                    &ctx.config.rescan4,
-                    &ctx.config.rescan5,
-                ] {
-                    maybe_rescan_with_config(&ctx.database, &mut ranges, rescan_config).await?;
+                if let Some(profile) = &scheduled_scan.rescan_profile {
+                    println!("chosen strategy: rescanning with {profile}");
+                } else {
+                    println!("chosen strategy: rescanning");
+                }
+                let mut legacy_servers = Vec::new();
+                for (name, rescan_config) in rescan_profiles(&ctx.config) {
+                    let is_chosen = match &scheduled_scan.rescan_profile {
+                        Some(profile) => profile == name,
+                        None => !ctx.config.schedule.rescan.iter().any(|s| s.profile == name),
+                    };
+                    if is_chosen {
+                        maybe_rescan_with_config(
+                            &ctx.database,
+                            &mut ranges,
+                            &mut legacy_servers,
+                            rescan_config,
+                        )
+                        .await?;
+                    }
                 }
 
-                *protocol.write() = Box::new(minecraft_protocol.clone());
-                processing_task.set_protocol::<protocols::Minecraft>();
+                *ctx.protocol.write() = rescan_protocol_registry(&ctx.config, legacy_servers);
             }
             StrategyCategory::Fingerprint => {
                 println!("chosen strategy: fingerprinting");
@@ -233,25 +412,220 @@ async fn main() -> eyre::Result<()> {
                 let mut fingerprint_ranges = Vec::new();
                 let mut fingerprint_protocol_versions = HashMap::new();
                 for (addr, protocol_version) in
-                    matscan::strategies::fingerprint::get_addrs_and_protocol_versions(&ctx.database)
-                        .await?
-                        .into_iter()
-                        .collect::<Vec<_>>()
+                    matscan::strategies::fingerprint::get_addrs_and_protocol_versions(
+                        &ctx.database,
+                        &ctx.config.fingerprinting,
+                    )
+                    .await?
                 {
                     fingerprint_ranges.push(ScanRange::single(*addr.ip(), addr.port()));
                     fingerprint_protocol_versions.insert(addr, protocol_version);
                 }
-                ranges.extend(fingerprint_ranges);
+                let fingerprint_ranges = ScanRanges::from(fingerprint_ranges);
+
+                let rounds = ctx
+                    .config
+                    .fingerprinting
+                    .probes
+                    .iter()
+                    .map(|&probe| {
+                        (
+                            format!("fingerprinting with the {} probe", probe.name()),
+                            fingerprint_ranges.clone(),
+                            protocols::MinecraftFingerprinting::new(
+                                fingerprint_protocol_versions.clone(),
+                                probe,
+                            ),
+                        )
+                    })
+                    .collect();
+                perform_rounds(&ctx, rounds, &mut strategy_picker).await;
+                continue;
+            }
+            StrategyCategory::Query => {
+                println!("chosen strategy: querying");
+
+                // this is done over udp with regular sockets, so it doesn't go through the
+                // scanner
+                matscan::query::query_servers(
+                    &ctx.database,
+                    &ctx.config.query,
+                    &ctx.exclude_ranges,
+                    quiet_rate.map_or(ctx.config.rate, |rate| rate.min(ctx.config.rate)),
+                )
+                .await?;
+                continue;
+            }
+            StrategyCategory::LoginProbe => {
+                println!("chosen strategy: login probing");
+
+                let mut login_probe_ranges = Vec::new();
+                let mut login_probe_protocol_versions = HashMap::new();
+                for (addr, protocol_version) in
+                    matscan::strategies::login_probe::get_addrs_and_protocol_versions(
+                        &ctx.database,
+                        &ctx.config.login_probe,
+                    )
+                    .await?
+                {
+                    login_probe_ranges.push(ScanRange::single(*addr.ip(), addr.port()));
+                    login_probe_protocol_versions.insert(addr, protocol_version);
+                }
+                ranges.extend(login_probe_ranges);
+
+                let username = &ctx.config.login_probe.username;
+                *ctx.protocol.write() = protocol_registry(
+                    &ctx.config,
+                    protocols::MinecraftLogin::new(
+                        username,
+                        offline_uuid(username),
+                        login_probe_protocol_versions,
+                    ),
+                );
+            }
+            StrategyCategory::FmlPing => {
+                println!("chosen strategy: fml pinging");
+
+                let fml_ranges = ScanRanges::from(
+                    matscan::strategies::fml_ping::get_ranges(&ctx.database, &ctx.config.fml_ping)
+                        .await?,
+                );
+
+                let rounds = ctx
+                    .config
+                    .fml_ping
+                    .markers
+                    .iter()
+                    .map(|&fml_marker| {
+                        (
+                            format!("pinging with the {} marker", fml_marker.name()),
+                            fml_ranges.clone(),
+                            protocols::Minecraft::new_with_fml_marker(
+                                &ctx.config.target.addr,
+                                ctx.config.target.port,
+                                ctx.config.target.protocol_version,
+                                fml_marker,
+                            ),
+                        )
+                    })
+                    .collect();
+                perform_rounds(&ctx, rounds, &mut strategy_picker).await;
+                continue;
+            }
+            StrategyCategory::VirtualHosts => {
+                println!("chosen strategy: virtual host probing");
+
+                if !ctx.config.virtual_hosts.domains.is_empty() {
+                    ctx.database
+                        .add_known_domains(&ctx.config.virtual_hosts.domains, "config")
+                        .await?;
+                }
+
+                let targets = matscan::strategies::virtual_hosts::get_targets(
+                    &ctx.database,
+                    &ctx.config.virtual_hosts,
+                )
+                .await?;
+                // a server can have more than one known domain, but we can only send one
+                // at a time
+                let rounds = matscan::strategies::virtual_hosts::into_rounds(targets);
+
+                let rounds = rounds
+                    .into_iter()
+                    .enumerate()
+                    .map(|(round_index, hostnames)| {
+                        let round_ranges = hostnames
+                            .keys()
+                            .map(|addr| ScanRange::single(*addr.ip(), addr.port()))
+                            .collect::<Vec<_>>();
+                        (
+                            format!(
+                                "virtual host round {round_index}: {} targets",
+                                hostnames.len()
+                            ),
+                            round_ranges.into(),
+                            minecraft_protocol.clone().with_hostnames(hostnames),
+                        )
+                    })
+                    .collect();
+                perform_rounds(&ctx, rounds, &mut strategy_picker).await;
+                continue;
+            }
+            StrategyCategory::ProtocolProbe => {
+                println!("chosen strategy: protocol probing");
+
+                let probe_ranges = ScanRanges::from(
+                    matscan::strategies::protocol_probe::get_ranges(
+                        &ctx.database,
+                        &ctx.config.protocol_probe,
+                    )
+                    .await?,
+                );
 
-                *protocol.write() = Box::new(protocols::MinecraftFingerprinting::new(
-                    fingerprint_protocol_versions,
-                ));
-                processing_task.set_protocol::<protocols::MinecraftFingerprinting>();
+                let rounds = ctx
+                    .config
+                    .protocol_probe
+                    .protocol_versions
+                    .iter()
+                    .map(|&protocol_version| {
+                        (
+                            format!("pinging with protocol version {protocol_version}"),
+                            probe_ranges.clone(),
+                            protocols::Minecraft::new_protocol_probe(
+                                &ctx.config.target.addr,
+                                ctx.config.target.port,
+                                protocol_version,
+                            ),
+                        )
+                    })
+                    .collect();
+                perform_rounds(&ctx, rounds, &mut strategy_picker).await;
+                continue;
             }
         }
 
         perform_scan(&ctx, ranges, strategy, start_time, &mut strategy_picker).await;
 
+        // servers that closed the connection after our handshake might only support
+        // the legacy ping
+        let closed_without_response =
+            mem::take(&mut ctx.shared_process_data.lock().closed_without_response);
+        if ctx.config.legacy_ping.enabled
+            && matches!(
+                strategy_category,
+                StrategyCategory::Normal | StrategyCategory::Rescan
+            )
+            && !closed_without_response.is_empty()
+        {
+            println!(
+                "doing legacy ping for {} servers that closed without responding",
+                closed_without_response.len()
+            );
+            let legacy_ranges = closed_without_response
+                .into_iter()
+                // canaries that didn't respond are already counted as lost
+                .filter(|addr| !ctx.config.canaries.targets.contains(addr))
+                .map(|addr| ScanRange::single(*addr.ip(), addr.port()))
+                .collect::<Vec<_>>();
+
+            *ctx.protocol.write() =
+                protocol_registry(&ctx.config, minecraft_legacy_protocol.clone());
+            perform_scan(
+                &ctx,
+                legacy_ranges.into(),
+                None,
+                Instant::now(),
+                &mut strategy_picker,
+            )
+            .await;
+            // we don't want to do a legacy ping for servers that didn't respond to the
+            // legacy ping
+            ctx.shared_process_data
+                .lock()
+                .closed_without_response
+                .clear();
+        }
+
         if ctx.config.debug.exit_on_done {
             println!("exit_on_done is true, exiting");
             break;
@@ -267,18 +641,25 @@ async fn main() -> eyre::Result<()> {
 }
 
 struct ScanContext {
-    exclude_ranges: Ipv4Ranges,
+    exclude_ranges: Arc<Ipv4Ranges>,
     database: Database,
-    scanner_writer: StatelessTcpWriteHalf,
+    scan_sender: ScanSender,
     config: Config,
-    scanner_seed: u64,
+    /// The protocols that the receiver lane uses for this context's scans.
+    protocol: Arc<RwLock<ProtocolRegistry>>,
+    /// The options for the sessions that are submitted to the sender. The seed
+    /// decides which receiver lane handles the responses.
+    session: SessionOptions,
     shared_process_data: Arc<Mutex<SharedData>>,
+    /// Shared with the receiver lane, so it can record the SYN+ACKs for
+    /// sessions that retry.
+    syn_acks: Arc<RwLock<Option<Arc<SynAckBitmap>>>>,
 }
 
 async fn perform_scan(
     ctx: &ScanContext,
     mut ranges: ScanRanges,
-    strategy: Option<ScanStrategy>,
+    strategy: Option<StrategyId>,
     start_time: Instant,
     strategy_picker: &mut StrategyPicker,
 ) {
@@ -318,25 +699,20 @@ async fn perform_scan(
 
     // this just spews out syn packets so it doesn't need to know what protocol
     // we're using
-    let session = ScanSession::new(ranges);
-    let mut scanner_writer = ctx.scanner_writer.clone();
-
-    let max_packets_per_second = ctx.config.rate;
-    let scanner_seed = ctx.scanner_seed;
-    let scan_duration_secs = ctx.config.scan_duration_secs.unwrap_or(60 * 5);
-    let scanner_thread = thread::spawn(move || {
-        session.run(
-            max_packets_per_second,
-            &mut scanner_writer,
-            scanner_seed,
-            scan_duration_secs,
-        )
-    });
-
-    // wait until the scanner thread is done
-    while !scanner_thread.is_finished() {
-        tokio::time::sleep(Duration::from_millis(100)).await;
-    }
+    let session = ScanSession::new(ranges)
+        .with_canaries(&ctx.config.canaries.targets)
+        .with_retries(&ctx.config.retries);
+    *ctx.syn_acks.write() = session.syn_acks();
+    // wait until the sender is done with our session
+    let summary = match wait_for_session(ctx, session).await {
+        Ok(summary) => summary,
+        Err(err) => {
+            eprintln!("aborting scan for {}: {err}", ctx.session.name);
+            error!("Aborting scan for {}: {err}", ctx.session.name);
+            ctx.syn_acks.write().take();
+            return;
+        }
+    };
     println!("waiting for processing to finish...");
 
     let processing_start = Instant::now();
@@ -353,18 +729,138 @@ async fn perform_scan(
         tokio::time::sleep(Duration::from_secs(sleep_secs)).await;
     }
 
-    // the thread should've finished by now so it'll join instantly
-    println!("joining scanner thread");
-    let packets_sent = scanner_thread.join().unwrap();
+    if let Some(syn_acks) = ctx.syn_acks.write().take()
+        && let Some(missing_before_retries) = summary.missing_before_retries
+    {
+        let recovered = missing_before_retries.saturating_sub(syn_acks.missing());
+        println!(
+            "sent {} retry syns to {missing_before_retries} targets that didn't reply, {recovered} of them replied",
+            summary.retries_sent
+        );
+        info!(
+            "Sent {} retry SYNs to {missing_before_retries} targets that didn't reply, {recovered} of them replied",
+            summary.retries_sent
+        );
+    }
 
     let mut shared_process_data = ctx.shared_process_data.lock();
+    let canary_report = shared_process_data
+        .canaries
+        .take_report(summary.canaries_sent);
     process_results(
         &mut shared_process_data,
         start_time,
         strategy,
         strategy_picker,
-        packets_sent,
+        summary.packets_sent - summary.canaries_sent - summary.retries_sent,
+        canary_report.loss(),
     );
+    drop(shared_process_data);
+    report_canaries(&ctx.config.canaries, &ctx.session.name, canary_report);
+}
+
+/// Submit the session to the sender and wait until it's done with it.
+async fn wait_for_session(ctx: &ScanContext, session: ScanSession) -> eyre::Result<SessionSummary> {
+    let session_handle = ctx.scan_sender.submit(session, ctx.session.clone())?;
+    loop {
+        if let Some(summary) = session_handle.try_summary()? {
+            return Ok(summary);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Do a scan for each round, one after another. Follow-ups that send more than
+/// one thing to the same target are split into rounds, since a target can only
+/// use one protocol in each scan.
+async fn perform_rounds<P: Protocol + ProcessableProtocol>(
+    ctx: &ScanContext,
+    rounds: Vec<(String, ScanRanges, P)>,
+    strategy_picker: &mut StrategyPicker,
+) {
+    for (description, ranges, protocol) in rounds {
+        println!("{description}");
+        *ctx.protocol.write() = protocol_registry(&ctx.config, protocol);
+        perform_scan(ctx, ranges, None, Instant::now(), strategy_picker).await;
+    }
+    // legacy pings are only done for the main scans
+    ctx.shared_process_data
+        .lock()
+        .closed_without_response
+        .clear();
+}
+
+/// Print how many of the canaries replied, and send an alert if too many of
+/// them didn't.
+fn report_canaries(config: &CanaryConfig, session_name: &str, report: CanaryReport) {
+    if report.sent == 0 {
+        return;
+    }
+    println!("{report}");
+    info!("Canaries for {session_name}: {report}");
+
+    if let Some(alert_loss) = config.alert_loss
+        && let Some(loss) = report.loss()
+        && loss > alert_loss
+    {
+        let message = format!(
+            "matscan is losing packets: {:.1}% of the canaries didn't reply in the {session_name} scan ({report})",
+            loss * 100.
+        );
+        println!("{BOLD}{message}{RESET}");
+        warn!("{message}");
+        if let Some(webhook_url) = &config.webhook_url {
+            tokio::spawn(send_to_webhook(webhook_url.clone(), message));
+        }
+    }
+}
+
+/// A registry that uses the given protocol for everything except the
+/// canaries, which always get a normal server list ping. Targets can also be
+/// tagged with "legacy" to get the legacy ping.
+fn protocol_registry<P: Protocol + ProcessableProtocol>(
+    config: &Config,
+    protocol: P,
+) -> ProtocolRegistry {
+    let mut registry = ProtocolRegistry::new(protocol)
+        .with_tag(
+            "canary",
+            protocols::Minecraft::new(
+                &config.target.addr,
+                config.target.port,
+                config.target.protocol_version,
+            ),
+        )
+        .with_tag(
+            "legacy",
+            protocols::MinecraftLegacy::new(&config.target.addr, config.target.port),
+        );
+    registry.tag_targets("canary", config.canaries.targets.iter().copied());
+    registry
+}
+
+/// A registry for rescans, which uses the normal server list ping for most
+/// servers and the legacy ping for the ones that only responded to that last
+/// time. This way they don't need a legacy ping after the scan.
+fn rescan_protocol_registry(
+    config: &Config,
+    legacy_servers: Vec<SocketAddrV4>,
+) -> ProtocolRegistry {
+    let mut registry = protocol_registry(
+        config,
+        protocols::Minecraft::new(
+            &config.target.addr,
+            config.target.port,
+            config.target.protocol_version,
+        ),
+    );
+    registry.tag_targets(
+        "legacy",
+        legacy_servers
+            .into_iter()
+            .filter(|addr| !config.canaries.targets.contains(addr)),
+    );
+    registry
 }
 
 /// Print the results of the scan, reset the counters, and update
@@ -372,9 +868,10 @@ async fn perform_scan(
 fn process_results(
     shared_process_data: &mut SharedData,
     start_time: Instant,
-    strategy: Option<ScanStrategy>,
+    strategy: Option<StrategyId>,
     strategy_picker: &mut StrategyPicker,
     packets_sent: u64,
+    canary_loss: Option<f64>,
 ) {
     let total_new = shared_process_data.total_new;
     let total_new_on_default_port = shared_process_data.total_new_on_default_port;
@@ -391,12 +888,13 @@ fn process_results(
     let elapsed_secs = elapsed.as_secs();
 
     if let Some(strategy) = strategy {
+        let strategy_name = strategy_picker.name(strategy);
         let added_per_minute = ((total_new + revived) as f64 / elapsed.as_secs_f64()) * 60.0;
         println!(
-            "ok finished adding to db after {BOLD}{elapsed_secs}{RESET} seconds (strat: {BOLD}{strategy:?}{RESET}, {YELLOW}updated {BOLD}{results}{RESET}{YELLOW}/{packets_sent}{RESET}, {GREEN}revived {BOLD}{revived}{RESET}, {BLUE}added {total_new}{RESET}, {BOLD}{added_per_minute:.2}{RESET} new per minute)",
+            "ok finished adding to db after {BOLD}{elapsed_secs}{RESET} seconds (strat: {BOLD}{strategy_name}{RESET}, {YELLOW}updated {BOLD}{results}{RESET}{YELLOW}/{packets_sent}{RESET}, {GREEN}revived {BOLD}{revived}{RESET}, {BLUE}added {total_new}{RESET}, {BOLD}{added_per_minute:.2}{RESET} new per minute)",
         );
         info!(
-            "Finished adding to database after {elapsed_secs} seconds. Strat: {strategy:?}, updated {results}/{packets_sent}, revived {revived}, added {total_new}, {added_per_minute:.2} new per minute",
+            "Finished adding to database after {elapsed_secs} seconds. Strat: {strategy_name}, updated {results}/{packets_sent}, revived {revived}, added {total_new}, {added_per_minute:.2} new per minute",
         );
 
         // prioritize finding servers on the default port since they're more likely to
@@ -410,16 +908,16 @@ fn process_results(
             total_new_on_default_port as f64 * TOTAL_NEW_ON_DEFAULT_PORT_MULTIPLIER;
         let revived_score = revived as f64 * REVIVED_MULTIPLIER;
 
-        let unnormalized_score = total_new_score + revived_score + total_new_on_default_port_score;
-
-        // score ends up being servers per hour-ish
-        // (we add 30 seconds so if a strategy finishes very quickly it's not super
-        // biased towards it)
-        let score = (unnormalized_score * 3600.0 / (elapsed.as_secs_f64() + 30.)).round() as usize;
+        let mut score = total_new_score + revived_score + total_new_on_default_port_score;
+        // a strategy shouldn't look worse just because our network was dropping
+        // packets while it ran
+        if let Some(canary_loss) = canary_loss {
+            score /= (1. - canary_loss).max(0.25);
+        }
         println!(
-            "got score {score} from {unnormalized_score} = {total_new_score} + {revived_score} + {total_new_on_default_port_score}"
+            "got score {score:.1} = {total_new_score} + {revived_score} + {total_new_on_default_port_score}"
         );
-        strategy_picker.update_strategy(strategy, score);
+        strategy_picker.update_strategy(strategy, score, elapsed, packets_sent);
     } else {
         let percent_replied = (results as f64 / packets_sent as f64) * 100.0;
         println!(
@@ -431,39 +929,80 @@ fn process_results(
     }
 }
 
-/// Get targets to rescan based on the given config and add them to ranges
+/// The rescan configs and the names they have in the config file.
+fn rescan_profiles(config: &Config) -> [(&'static str, &RescanConfig); 5] {
+    [
+        ("rescan", &config.rescan),
+        ("rescan2", &config.rescan2),
+        ("rescan3", &config.rescan3),
+        ("rescan4", &config.rescan4),
+        ("rescan5", &config.rescan5),
+    ]
+}
+
+/// Get targets to rescan based on the given config and add them to ranges, and
+/// the servers that only responded to the legacy ping to legacy_servers.
 async fn maybe_rescan_with_config(
     database: &Database,
     ranges: &mut ScanRanges,
+    legacy_servers: &mut Vec<SocketAddrV4>,
     rescan: &RescanConfig,
 ) -> eyre::Result<()> {
     if rescan.enabled {
-        ranges.extend(matscan::strategies::rescan::get_ranges(database, rescan).await?);
+        let (rescan_ranges, rescan_legacy_servers) =
+            matscan::strategies::rescan::get_targets(database, rescan).await?;
+        ranges.extend(rescan_ranges);
+        legacy_servers.extend(rescan_legacy_servers);
     }
     Ok(())
 }
 
-pub struct ProcessingTask {
-    pub shared_process_data: Arc<Mutex<SharedData>>,
-    pub config: Config,
-    join_handle: Option<tokio::task::JoinHandle<()>>,
-}
+/// Rescan with the profile every time its schedule says to, while the other
+/// scans are running.
+async fn run_concurrent_rescan(ctx: ScanContext, schedule: RescanScheduleConfig) {
+    tokio::spawn(process_pings(
+        ctx.shared_process_data.clone(),
+        ctx.config.clone(),
+    ));
+    // only used for normal scans, so it won't be updated
+    let mut strategy_picker = StrategyPicker::new(&ctx.config);
+
+    let (_, rescan_config) = rescan_profiles(&ctx.config)
+        .into_iter()
+        .find(|(name, _)| *name == schedule.profile)
+        .expect("rescan profiles are checked on startup");
 
-impl ProcessingTask {
-    pub fn new(shared_process_data: Arc<Mutex<SharedData>>, config: Config) -> Self {
-        Self {
-            shared_process_data,
-            config,
-            join_handle: None,
-        }
-    }
-    pub fn set_protocol<P: matscan::processing::ProcessableProtocol>(&mut self) {
-        if let Some(join_handle) = &mut self.join_handle {
-            join_handle.abort();
+    loop {
+        let now = Utc::now();
+        let Some(next_run) = schedule.cron.next_after(now) else {
+            println!("{} will never run again", schedule.profile);
+            return;
+        };
+        tokio::time::sleep((next_run - now).to_std().unwrap_or_default()).await;
+
+        println!(
+            "chosen strategy: rescanning with {} (concurrent)",
+            schedule.profile
+        );
+        let mut ranges = ScanRanges::default();
+        let mut legacy_servers = Vec::new();
+        if let Err(err) = maybe_rescan_with_config(
+            &ctx.database,
+            &mut ranges,
+            &mut legacy_servers,
+            rescan_config,
+        )
+        .await
+        {
+            eprintln!("failed to get ranges for {}: {err}", schedule.profile);
+            continue;
         }
-        let shared_process_data = self.shared_process_data.clone();
-        let join_handle =
-            tokio::task::spawn(process_pings::<P>(shared_process_data, self.config.clone()));
-        self.join_handle = Some(join_handle);
+        *ctx.protocol.write() = rescan_protocol_registry(&ctx.config, legacy_servers);
+        perform_scan(&ctx, ranges, None, Instant::now(), &mut strategy_picker).await;
+        // legacy pings are only done for the main scans
+        ctx.shared_process_data
+            .lock()
+            .closed_without_response
+            .clear();
     }
 }
@@ -3,6 +3,7 @@
 use std::{
     io, mem,
     os::unix::io::{AsRawFd, RawFd},
+    time::Duration,
 };
 
 #[repr(C)]
@@ -43,6 +44,17 @@ pub const SIOCGIFINDEX: libc::c_ulong = 0x8933;
 pub const ETH_P_ALL: libc::c_short = 0x0003;
 pub const ETH_P_IEEE802154: libc::c_short = 0x00F6;
 
+/// The counters from `PACKET_STATISTICS`. The kernel resets them every time
+/// they're read, so these are the counts since the last call.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PacketStatistics {
+    /// The number of packets that were received by the socket.
+    pub packets: u32,
+    /// The number of packets that were dropped because the socket's buffer
+    /// was full.
+    pub drops: u32,
+}
+
 #[derive(Debug)]
 pub struct RawSocket {
     protocol: libc::c_short,
@@ -126,6 +138,25 @@ impl RawSocket {
         }
     }
 
+    /// Wait until there's a packet to receive, or until the timeout. Returns
+    /// whether there's a packet.
+    pub fn poll_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.lower,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let res = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+        if res == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        Ok(res > 0)
+    }
+
     pub fn send(&mut self, buffer: &[u8]) -> io::Result<usize> {
         unsafe {
             let len = libc::send(
@@ -141,6 +172,32 @@ impl RawSocket {
         }
     }
 
+    pub fn packet_statistics(&self) -> io::Result<PacketStatistics> {
+        let mut stats = libc::tpacket_stats {
+            tp_packets: 0,
+            tp_drops: 0,
+        };
+        let mut len = mem::size_of::<libc::tpacket_stats>() as libc::socklen_t;
+        unsafe {
+            let res = libc::getsockopt(
+                self.lower,
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut stats as *mut libc::tpacket_stats as *mut libc::c_void,
+                &mut len,
+            );
+            if res == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // tp_packets includes the dropped packets
+        Ok(PacketStatistics {
+            packets: stats.tp_packets.saturating_sub(stats.tp_drops),
+            drops: stats.tp_drops,
+        })
+    }
+
     pub fn send_blocking(&mut self, buffer: &[u8]) {
         loop {
             match self.send(buffer) {
@@ -1,11 +1,11 @@
 use std::{
-    io::Write,
+    io::{self, Write},
     net::{IpAddr, Ipv4Addr, SocketAddrV4},
     time::Duration,
 };
 
 use pnet::{
-    datalink::{self, Channel, DataLinkReceiver, NetworkInterface},
+    datalink::{self, NetworkInterface},
     packet::{
         FromPacket, Packet,
         ethernet::EthernetPacket,
@@ -18,10 +18,10 @@ use pnet::{
 use tracing::{trace, warn};
 
 use super::{
-    raw_sockets::RawSocket,
+    raw_sockets::{PacketStatistics, RawSocket},
     tcp_template::{self, TemplatePacket},
 };
-use crate::{config::Config, net::tcp_template::TemplatePacketRepr, scanner::SourcePort};
+use crate::{config::Config, net::tcp_template::TemplatePacketRepr, scanner::PortRange};
 
 pub const ETH_HEADER_LEN: usize = 14;
 
@@ -73,7 +73,7 @@ pub struct StatelessTcp {
 #[derive(Clone)]
 pub struct StatelessTcpWriteHalf {
     source_ip: Ipv4Addr,
-    source_port: SourcePort,
+    source_port: PortRange,
 
     gateway_mac: Option<MacAddr>,
     interface_mac: Option<MacAddr>,
@@ -92,11 +92,12 @@ pub struct StatelessTcpWriteHalf {
 
 pub struct StatelessTcpReadHalf {
     interface_mac: Option<MacAddr>,
-    source_port: SourcePort,
+    source_port: PortRange,
 
-    // tx: Box<dyn DataLinkSender>,
     #[cfg(not(feature = "benchmark"))]
-    rx: Box<dyn DataLinkReceiver>,
+    rx: RawSocket,
+    #[cfg(not(feature = "benchmark"))]
+    rx_buffer: Vec<u8>,
 }
 
 impl StatelessTcp {
@@ -115,21 +116,9 @@ impl StatelessTcp {
             None
         };
 
-        // Create a channel to receive on
+        // a separate socket to receive on, so we can get the drop counters for it
         #[cfg(not(feature = "benchmark"))]
-        let (_tx, rx) = match datalink::channel(
-            &interface,
-            datalink::Config {
-                read_timeout: Some(Duration::ZERO),
-                // read_timeout: Some(Duration::from_secs(1)),
-                ..Default::default()
-            },
-            // Config::default(),
-        ) {
-            Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
-            Ok(_) => panic!("unhandled channel type"),
-            Err(e) => panic!("unable to create channel: {}", e),
-        };
+        let rx = RawSocket::new(&interface.name).unwrap();
 
         let interface_ipv4 = match interface.ips.iter().find(|ip| ip.is_ipv4()).unwrap().ip() {
             IpAddr::V4(ip) => ip,
@@ -187,6 +176,8 @@ impl StatelessTcp {
                 interface_mac,
                 #[cfg(not(feature = "benchmark"))]
                 rx,
+                #[cfg(not(feature = "benchmark"))]
+                rx_buffer: vec![0; 65536],
             },
             write: write_half,
         }
@@ -352,8 +343,9 @@ impl StatelessTcpReadHalf {
     pub fn recv(&mut self) -> Option<(Ipv4, Tcp)> {
         #[cfg(not(feature = "benchmark"))]
         loop {
-            match self.rx.next() {
-                Ok(packet) => {
+            match self.rx.recv(&mut self.rx_buffer) {
+                Ok(len) => {
+                    let packet = &self.rx_buffer[..len];
                     let payload_for_ipv4 = if self.interface_mac.is_some() {
                         let ethernet = EthernetPacket::new(packet).unwrap();
                         ethernet.payload().to_vec()
@@ -375,6 +367,27 @@ impl StatelessTcpReadHalf {
         #[cfg(feature = "benchmark")]
         None
     }
+
+    /// Block until there's a packet to receive, or until the timeout.
+    pub fn wait_readable(&self, timeout: Duration) {
+        #[cfg(not(feature = "benchmark"))]
+        if let Err(err) = self.rx.poll_readable(timeout) {
+            // don't spin if polling is broken somehow
+            warn!("failed to poll the socket: {err}");
+            std::thread::sleep(timeout);
+        }
+        #[cfg(feature = "benchmark")]
+        std::thread::sleep(timeout);
+    }
+
+    /// The packets received and dropped by the kernel since the last time this
+    /// was called.
+    pub fn packet_statistics(&self) -> io::Result<PacketStatistics> {
+        #[cfg(not(feature = "benchmark"))]
+        return self.rx.packet_statistics();
+        #[cfg(feature = "benchmark")]
+        Ok(PacketStatistics::default())
+    }
 }
 
 #[derive(Debug)]
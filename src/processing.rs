@@ -1,15 +1,18 @@
 pub mod minecraft;
 pub mod minecraft_fingerprinting;
+pub mod minecraft_legacy;
+pub mod minecraft_login;
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     mem,
     net::SocketAddrV4,
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
 };
 
 use chrono::{NaiveDateTime, TimeDelta, Utc};
+use futures_util::future::BoxFuture;
 use parking_lot::Mutex;
 use rustc_hash::FxHashSet;
 use sqlx::Row;
@@ -19,17 +22,28 @@ use crate::{
     config::Config,
     database::{Database, PgU16, PgU32},
     processing::minecraft::SamplePlayer,
+    scanner::{canary::CanaryResults, throttle::RateFeedback},
     terminal_colors::*,
 };
 
 pub struct SharedData {
     pub database: Database,
-    /// The queue of servers to process, along with their server list ping
-    /// response.
-    pub queue: VecDeque<(SocketAddrV4, Vec<u8>)>,
+    /// The queue of servers to process, along with how to process them and
+    /// their response.
+    pub queue: VecDeque<(SocketAddrV4, ResponseHandler, Vec<u8>)>,
     /// Data from the previous scan, used for identifying players that just
     /// joined or left a server.
     pub cached_players_for_sniping: HashMap<SocketAddrV4, Vec<SamplePlayer>>,
+    /// Servers that acknowledged our payload and then closed the connection
+    /// without sending anything, or that sent a legacy kick packet. For the
+    /// Minecraft protocol, these are usually pre-1.7 servers that only
+    /// understand the legacy ping.
+    pub closed_without_response: HashSet<SocketAddrV4>,
+    /// Counters that the scanner uses to adjust its rate, if adaptive rate is
+    /// enabled.
+    pub rate_feedback: Arc<RateFeedback>,
+    /// The canaries that responded in the current scan.
+    pub canaries: CanaryResults,
 
     pub total_new: usize,
     pub total_new_on_default_port: usize,
@@ -40,8 +54,33 @@ pub struct SharedData {
     pub is_processing: bool,
 }
 
-pub trait ProcessableProtocol: Send + 'static {
+impl SharedData {
+    pub fn new(database: Database, rate_feedback: Arc<RateFeedback>) -> Self {
+        Self {
+            database,
+            queue: VecDeque::new(),
+            // we use the cache to check if someone just joined a server, so this
+            // will always stay empty if snipe mode is off
+            cached_players_for_sniping: HashMap::new(),
+            closed_without_response: HashSet::new(),
+            rate_feedback,
+            canaries: CanaryResults::default(),
+
+            total_new: 0,
+            total_new_on_default_port: 0,
+            revived: 0,
+            results: 0,
+
+            is_processing: false,
+        }
+    }
+}
+
+pub trait ProcessableProtocol: Send + Sync + 'static {
+    /// Handle a response. This is called on the same protocol that sent the
+    /// payload, so it knows what was sent to the target.
     fn handle_response(
+        &self,
         shared: Arc<Mutex<SharedData>>,
         config: Arc<Config>,
         target: SocketAddrV4,
@@ -50,11 +89,34 @@ pub trait ProcessableProtocol: Send + 'static {
     ) -> impl std::future::Future<Output = eyre::Result<()>> + std::marker::Send;
 }
 
-/// A task that processes pings from the queue.
-pub async fn process_pings<P>(shared: Arc<Mutex<SharedData>>, config: Config)
-where
-    P: ProcessableProtocol + Send + 'static,
-{
+/// [`ProcessableProtocol::handle_response`] for a protocol, so responses from
+/// different protocols can be in the same queue.
+pub type ResponseHandler = Arc<
+    dyn Fn(
+            Arc<Mutex<SharedData>>,
+            Arc<Config>,
+            SocketAddrV4,
+            Box<[u8]>,
+            Database,
+        ) -> BoxFuture<'static, eyre::Result<()>>
+        + Send
+        + Sync,
+>;
+
+pub fn response_handler<P: ProcessableProtocol>(protocol: Arc<P>) -> ResponseHandler {
+    Arc::new(move |shared, config, target, data, database| {
+        let protocol = protocol.clone();
+        Box::pin(async move {
+            protocol
+                .handle_response(shared, config, target, data, database)
+                .await
+        })
+    })
+}
+
+/// A task that processes pings from the queue. Each response is handled by
+/// the protocol that it came from.
+pub async fn process_pings(shared: Arc<Mutex<SharedData>>, config: Config) {
     let database = shared.lock().database.clone();
     loop {
         if shared.lock().queue.is_empty() {
@@ -74,7 +136,7 @@ where
         let config = Arc::new(config.clone());
 
         let batch_contents = shared.lock().queue.drain(..).collect::<Vec<_>>();
-        for (target, data) in batch_contents {
+        for (target, handler, data) in batch_contents {
             // don't handle the server twice in the same chunk of CHUNK_SIZE
             if updating_servers_in_chunk.contains(&target) {
                 continue;
@@ -84,7 +146,7 @@ where
             let shared_clone = shared.clone();
             let config_clone = config.clone();
             let database_clone = database.clone();
-            let future = P::handle_response(
+            let future = handler(
                 shared_clone,
                 config_clone,
                 target,
@@ -133,6 +195,7 @@ async fn handle_response_futures(
     let now = Utc::now();
     for (addr, handle_response_future) in futures {
         tasks.push(async move {
+            let started = Instant::now();
             let mut processed_server_status = if let Ok(row) =
                 sqlx::query("SELECT last_pinged FROM servers WHERE ip = $1 AND port = $2")
                     .bind(PgU32(addr.ip().to_bits()))
@@ -157,18 +220,29 @@ async fn handle_response_futures(
                 processed_server_status = ProcessedServerStatus::Error;
             }
 
-            (addr, processed_server_status)
+            (addr, processed_server_status, started.elapsed())
         });
     }
 
     let resolved_statuses = futures_util::future::join_all(tasks).await;
 
+    let average_latency = resolved_statuses
+        .iter()
+        .map(|(_, _, latency)| *latency)
+        .sum::<Duration>()
+        / resolved_statuses.len() as u32;
+    shared
+        .lock()
+        .rate_feedback
+        .db_latency_micros
+        .store(average_latency.as_micros() as u64, Ordering::Relaxed);
+
     let mut updated_count = 0;
     let mut updated_but_not_revived_count = 0;
     let mut inserted_count = 0;
     let mut inserted_on_default_port_count = 0;
     let mut revived_count = 0;
-    for (addr, resolved_status) in resolved_statuses {
+    for (addr, resolved_status, _) in resolved_statuses {
         match resolved_status {
             ProcessedServerStatus::Added => {
                 updated_count += 1;
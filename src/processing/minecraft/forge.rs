@@ -0,0 +1,272 @@
+//! Extracting the mod list from Forge and NeoForge server list pings.
+
+use std::{
+    collections::HashSet,
+    io::{Cursor, Read},
+};
+
+use eyre::{OptionExt, bail};
+use simd_json::{
+    OwnedValue,
+    derived::{ValueObjectAccess, ValueObjectAccessAsArray, ValueObjectAccessAsScalar},
+};
+
+use crate::{database::sanitize_text_for_postgres, scanner::protocols::read_varint};
+
+/// The start of the version that Forge uses for mods that are only required on
+/// the server (the rest is a bunch of emojis).
+const IGNORE_SERVER_ONLY_PREFIX: &str = "OHNOES";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerMod {
+    pub id: String,
+    /// None if the server didn't tell us the version, or if the mod is
+    /// server-side only.
+    pub version: Option<String>,
+}
+
+pub struct ModList {
+    pub mods: Vec<ServerMod>,
+    /// Forge stops adding mods to the ping once it gets too big, so the list
+    /// might be incomplete.
+    pub is_truncated: Option<bool>,
+}
+
+/// Get the list of mods from the `forgeData` (1.13+) or `modinfo` (older)
+/// fields of a server list ping.
+///
+/// Channels are only used to decode the compressed data, since they don't
+/// tell us anything about which mods are installed that the mod list doesn't.
+pub fn parse_mod_list(v: &OwnedValue) -> ModList {
+    let mut mods = Vec::new();
+    let mut is_truncated = None;
+
+    let forge_data = v.get("forgeData");
+    if let Some(d) = forge_data.get_str("d") {
+        // 1.18.1+ compresses the mods and channels into a string
+        match decode_optimized(d).and_then(|data| parse_optimized_forge_data(&data)) {
+            Ok(list) => {
+                mods = list.mods;
+                is_truncated = list.is_truncated;
+            }
+            Err(err) => tracing::debug!("failed to decode forgeData.d: {err}"),
+        }
+    }
+    if let Some(forge_mods) = forge_data.get_array("mods") {
+        for m in forge_mods {
+            if let Some(id) = m.get_str("modId") {
+                mods.push(ServerMod {
+                    id: id.to_owned(),
+                    version: m.get_str("modmarker").map(str::to_owned),
+                });
+            }
+        }
+    }
+    if let Some(truncated) = forge_data.get_bool("truncated") {
+        is_truncated = Some(truncated);
+    }
+
+    if let Some(mod_list) = v.get("modinfo").get_array("modList") {
+        for m in mod_list {
+            if let Some(id) = m.get_str("modid") {
+                mods.push(ServerMod {
+                    id: id.to_owned(),
+                    version: m.get_str("version").map(str::to_owned),
+                });
+            }
+        }
+    }
+
+    let mut seen_ids = HashSet::new();
+    let mods = mods
+        .into_iter()
+        .map(|m| ServerMod {
+            id: sanitize_text_for_postgres(&m.id),
+            version: m
+                .version
+                .filter(|v| !v.starts_with(IGNORE_SERVER_ONLY_PREFIX))
+                .map(|v| sanitize_text_for_postgres(&v)),
+        })
+        .filter(|m| !m.id.is_empty() && seen_ids.insert(m.id.clone()))
+        .collect();
+
+    ModList { mods, is_truncated }
+}
+
+/// Decode the string from `forgeData.d`. The first two characters are the
+/// length of the data in bytes, and every character after that has 15 bits of
+/// the data.
+pub fn decode_optimized(s: &str) -> eyre::Result<Vec<u8>> {
+    let mut chars = s.encode_utf16();
+    let size0 = chars.next().ok_or_eyre("Missing size")? as usize;
+    let size1 = chars.next().ok_or_eyre("Missing size")? as usize;
+    let size = size0 | (size1 << 15);
+    if size > s.len() * 2 {
+        bail!("Size {size} is too big for a string of length {}", s.len());
+    }
+
+    let mut data = Vec::with_capacity(size);
+    let mut buffer: u32 = 0;
+    let mut bits_in_buffer = 0;
+    for c in chars {
+        while bits_in_buffer >= 8 {
+            data.push(buffer as u8);
+            buffer >>= 8;
+            bits_in_buffer -= 8;
+        }
+        buffer |= ((c & 0x7fff) as u32) << bits_in_buffer;
+        bits_in_buffer += 15;
+    }
+    // write the leftover bits
+    while data.len() < size {
+        data.push(buffer as u8);
+        buffer >>= 8;
+    }
+    data.truncate(size);
+
+    Ok(data)
+}
+
+fn parse_optimized_forge_data(data: &[u8]) -> eyre::Result<ModList> {
+    let mut stream = Cursor::new(data);
+
+    let is_truncated = read_u8(&mut stream)? != 0;
+    let mut mods_size = [0; 2];
+    stream.read_exact(&mut mods_size)?;
+    let mods_size = u16::from_be_bytes(mods_size);
+
+    let mut mods = Vec::with_capacity(mods_size as usize);
+    for _ in 0..mods_size {
+        let channel_size_and_version_flag =
+            read_varint(&mut stream).ok_or_eyre("Missing channel size")?;
+        let channel_size = channel_size_and_version_flag >> 1;
+        let is_ignore_server_only = channel_size_and_version_flag & 0b1 != 0;
+
+        let id = read_utf(&mut stream)?;
+        let version = if is_ignore_server_only {
+            None
+        } else {
+            Some(read_utf(&mut stream)?)
+        };
+        for _ in 0..channel_size {
+            // channel path, version, and whether it's required
+            read_utf(&mut stream)?;
+            read_utf(&mut stream)?;
+            read_u8(&mut stream)?;
+        }
+
+        mods.push(ServerMod { id, version });
+    }
+    // the channels that don't belong to a mod are at the end, but we don't need
+    // them
+
+    Ok(ModList {
+        mods,
+        is_truncated: Some(is_truncated),
+    })
+}
+
+fn read_u8(stream: &mut Cursor<&[u8]>) -> eyre::Result<u8> {
+    let mut byte = [0];
+    stream.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_utf(stream: &mut Cursor<&[u8]>) -> eyre::Result<String> {
+    let length = read_varint(stream).ok_or_eyre("Missing string length")?;
+    if length < 0 {
+        bail!("Negative string length");
+    }
+    let mut bytes = vec![0; length as usize];
+    stream.read_exact(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::protocols::write_varint;
+
+    fn encode_optimized(data: &[u8]) -> String {
+        let mut chars = vec![
+            (data.len() & 0x7fff) as u16,
+            ((data.len() >> 15) & 0x7fff) as u16,
+        ];
+        let mut buffer: u32 = 0;
+        let mut bits_in_buffer = 0;
+        for &byte in data {
+            if bits_in_buffer >= 15 {
+                chars.push((buffer & 0x7fff) as u16);
+                buffer >>= 15;
+                bits_in_buffer -= 15;
+            }
+            buffer |= (byte as u32) << bits_in_buffer;
+            bits_in_buffer += 8;
+        }
+        if bits_in_buffer > 0 {
+            chars.push((buffer & 0x7fff) as u16);
+        }
+        String::from_utf16(&chars).unwrap()
+    }
+
+    fn write_utf(data: &mut Vec<u8>, s: &str) {
+        write_varint(data, s.len() as i32);
+        data.extend_from_slice(s.as_bytes());
+    }
+
+    #[test]
+    fn test_decode_optimized_forge_data() {
+        let mut data = vec![
+            // truncated
+            0x00, // 2 mods
+            0x00, 0x02,
+        ];
+        // forge with one channel
+        write_varint(&mut data, 1 << 1);
+        write_utf(&mut data, "forge");
+        write_utf(&mut data, "47.2.0");
+        write_utf(&mut data, "tier_sorting");
+        write_utf(&mut data, "1.0");
+        data.push(0x01);
+        // a server-only mod
+        write_varint(&mut data, 0b1);
+        write_utf(&mut data, "spark");
+        // no non-mod channels
+        write_varint(&mut data, 0);
+
+        let decoded = decode_optimized(&encode_optimized(&data)).unwrap();
+        assert_eq!(decoded, data);
+
+        let mut json = format!(
+            r#"{{"forgeData":{{"channels":[],"mods":[],"fmlNetworkVersion":3,"d":{}}}}}"#,
+            serde_json::to_string(&encode_optimized(&data)).unwrap()
+        )
+        .into_bytes();
+        let v = simd_json::to_owned_value(&mut json).unwrap();
+        let list = parse_mod_list(&v);
+        assert_eq!(list.is_truncated, Some(false));
+        assert_eq!(
+            list.mods,
+            vec![
+                ServerMod {
+                    id: "forge".to_string(),
+                    version: Some("47.2.0".to_string()),
+                },
+                ServerMod {
+                    id: "spark".to_string(),
+                    version: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_old_forge_mod_list() {
+        let mut json = br#"{"modinfo":{"type":"FML","modList":[{"modid":"mcp","version":"9.42"},{"modid":"FML","version":"8.0.99.99"},{"modid":"mcp","version":"9.42"}]}}"#.to_vec();
+        let v = simd_json::to_owned_value(&mut json).unwrap();
+        let list = parse_mod_list(&v);
+        assert_eq!(list.is_truncated, None);
+        assert_eq!(list.mods.len(), 2);
+        assert_eq!(list.mods[1].id, "FML");
+    }
+}
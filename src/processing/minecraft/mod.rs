@@ -1,6 +1,9 @@
 pub mod anti_abuse;
+pub mod forge;
 pub mod passive_fingerprint;
+pub mod protocol_probe;
 pub mod snipe;
+pub mod virtual_host;
 
 use std::{
     collections::{HashSet, hash_map::DefaultHasher},
@@ -14,7 +17,7 @@ use parking_lot::Mutex;
 use serde::Deserialize;
 use sha2::Digest;
 use simd_json::derived::{ValueObjectAccess, ValueObjectAccessAsArray, ValueObjectAccessAsScalar};
-use sqlx::{Arguments, Postgres, QueryBuilder, postgres::PgArguments};
+use sqlx::{Arguments, Postgres, QueryBuilder, Row, postgres::PgArguments};
 use tracing::error;
 use uuid::Uuid;
 
@@ -23,8 +26,14 @@ use crate::{
     config::Config,
     database::{CachedIpHash, Database, PgU16, PgU32, sanitize_text_for_postgres},
     processing::minecraft::{
-        passive_fingerprint::{PassiveMinecraftFingerprint, generate_passive_fingerprint},
+        forge::{ServerMod, parse_mod_list},
+        passive_fingerprint::{
+            PassiveMinecraftFingerprint, SoftwareSignals, classify_software,
+            generate_passive_fingerprint,
+        },
+        protocol_probe::insert_protocol_probe_to_db,
         snipe::maybe_log_sniped,
+        virtual_host::insert_virtual_host_to_db,
     },
     scanner::protocols,
 };
@@ -50,6 +59,9 @@ pub struct PingResponse {
     pub previews_chat: Option<bool>,
 
     pub fingerprint: PassiveMinecraftFingerprint,
+    /// Whether this response came from a pre-1.7 server list ping, see
+    /// [`protocols::MinecraftLegacy`].
+    pub is_legacy: bool,
 
     // non-vanilla fields
 
@@ -57,6 +69,12 @@ pub struct PingResponse {
     pub prevents_chat_reports: Option<bool>,
     // forge
     pub forgedata_fml_network_version: Option<i32>,
+    pub forgedata_is_truncated: Option<bool>,
+    /// The mods from forgeData or modinfo, see [`forge::parse_mod_list`].
+    pub mods: Vec<ServerMod>,
+    /// The FML marker that we sent in the handshake, if it made the server
+    /// send its mods.
+    pub fml_marker: Option<protocols::FmlMarker>,
     // old forge servers
     pub modinfo_type: Option<String>,
     // neoforged
@@ -76,25 +94,25 @@ pub const ANONYMOUS_PLAYER_NAME: &str = "Anonymous Player";
 
 impl ProcessableProtocol for protocols::Minecraft {
     async fn handle_response(
+        &self,
         shared: Arc<Mutex<SharedData>>,
         config: Arc<Config>,
         target: SocketAddrV4,
         data: Box<[u8]>,
         db: Database,
     ) -> eyre::Result<()> {
-        let ping_res = parse_ping_response_json(&data)?;
+        let mut ping_res = parse_ping_response_json(&data)?;
+        if !ping_res.mods.is_empty() {
+            ping_res.fml_marker = self.fml_marker();
+        }
+
+        ensure_allowed(&db, &target, &ping_res)?;
 
-        if !anti_abuse::should_insert(&ping_res) {
-            bail!("Disallowed server response")
+        if let Some(domain) = self.virtual_host(target) {
+            return insert_virtual_host_to_db(&db, &target, domain, &ping_res).await;
         }
-        if let Some(&allowed_port) = db
-            .shared
-            .lock()
-            .aliased_ips_to_allowed_port
-            .get(target.ip())
-            && target.port() != allowed_port
-        {
-            bail!("Aliased server on disallowed port");
+        if let Some(sent_protocol) = self.probe_protocol_version() {
+            return insert_protocol_probe_to_db(&db, &target, sent_protocol, &ping_res).await;
         }
 
         if config.snipe.enabled {
@@ -118,6 +136,30 @@ impl ProcessableProtocol for protocols::Minecraft {
     }
 }
 
+/// Returns an error if the server shouldn't be inserted into the database,
+/// either because of [`anti_abuse`] or because it's an aliased server on a
+/// disallowed port.
+pub fn ensure_allowed(
+    db: &Database,
+    target: &SocketAddrV4,
+    ping_res: &PingResponse,
+) -> eyre::Result<()> {
+    if !anti_abuse::should_insert(ping_res) {
+        bail!("Disallowed server response")
+    }
+    if let Some(&allowed_port) = db
+        .shared
+        .lock()
+        .aliased_ips_to_allowed_port
+        .get(target.ip())
+        && target.port() != allowed_port
+    {
+        bail!("Aliased server on disallowed port");
+    }
+
+    Ok(())
+}
+
 pub fn parse_ping_response_json(d: &[u8]) -> eyre::Result<PingResponse> {
     let d = String::from_utf8_lossy(d);
     let d = sanitize_text_for_postgres(&d);
@@ -220,6 +262,7 @@ pub fn parse_ping_response_json(d: &[u8]) -> eyre::Result<PingResponse> {
     let prevents_chat_reports = v.get_bool("preventsChatReports");
     let forge_data = v.get("forgeData");
     let forgedata_fml_network_version = forge_data.get_i32("fmlNetworkVersion");
+    let mod_list = parse_mod_list(&v);
     let mod_info = v.get("modinfo");
     let modinfo_type = mod_info.get_str("type").map(sanitize_text_for_postgres);
     let is_modded = v.get_bool("isModded");
@@ -248,10 +291,14 @@ pub fn parse_ping_response_json(d: &[u8]) -> eyre::Result<PingResponse> {
         enforces_secure_chat,
 
         fingerprint,
+        is_legacy: false,
 
         previews_chat,
         prevents_chat_reports,
         forgedata_fml_network_version,
+        forgedata_is_truncated: mod_list.is_truncated,
+        mods: mod_list.mods,
+        fml_marker: None,
         modinfo_type,
         is_modded,
         modpackdata_project_id,
@@ -338,7 +385,9 @@ pub async fn insert_server_to_db(
     qb.field("ip", PgU32(target.ip().to_bits()));
     qb.field("port", PgU16(target.port()));
     qb.field("last_pinged", now);
-    qb.field("is_online_mode", r.is_online_mode);
+    // an empty sample doesn't tell us anything, so don't forget what the login
+    // probe found
+    qb.field_or_keep("is_online_mode", r.is_online_mode);
     qb.field("favicon_hash", r.favicon_hash);
     qb.field("description_json", r.description_json.clone());
     qb.field("description_plaintext", r.description_plaintext.clone());
@@ -356,12 +405,27 @@ pub async fn insert_server_to_db(
     );
     qb.field("fingerprint_is_empty_sample", r.fingerprint.empty_sample);
     qb.field("fingerprint_is_empty_favicon", r.fingerprint.empty_favicon);
+    qb.field("is_legacy_ping", r.is_legacy);
+    let (software, software_confidence) = classify_software(&SoftwareSignals {
+        version_name: r.version_name.as_deref(),
+        field_order: r.fingerprint.field_order.as_deref(),
+        is_empty_sample: r.fingerprint.empty_sample,
+        forgedata_fml_network_version: r.forgedata_fml_network_version,
+        modinfo_type: r.modinfo_type.as_deref(),
+        is_modded: r.is_modded,
+    });
+    qb.field("software", software.as_str());
+    qb.field("software_confidence", software_confidence);
 
     qb.field("prevents_chat_reports", r.prevents_chat_reports);
     qb.field(
         "forgedata_fml_network_version",
         r.forgedata_fml_network_version,
     );
+    qb.field("forgedata_is_truncated", r.forgedata_is_truncated);
+    if let Some(fml_marker) = r.fml_marker {
+        qb.field("fml_marker", fml_marker.name());
+    }
     qb.field("modinfo_type", r.modinfo_type.clone());
     qb.field("is_modded", r.is_modded);
     qb.field("modpackdata_project_id", r.modpackdata_project_id);
@@ -374,8 +438,12 @@ pub async fn insert_server_to_db(
     }
 
     let mut qb = qb.into_querybuilder();
-    let query = qb.build();
-    query.execute(&mut *txn).await?;
+    // this is checked here so most pings don't need another query to clear out
+    // the mods
+    qb.push(
+        " RETURNING EXISTS (SELECT 1 FROM server_mods WHERE server_ip = servers.ip AND server_port = servers.port)",
+    );
+    let had_mods = qb.build().fetch_one(&mut *txn).await?.get::<bool, _>(0);
 
     // insert players in bulk
     if !r.player_sample.is_empty() {
@@ -399,6 +467,30 @@ pub async fn insert_server_to_db(
         query.execute(&mut *txn).await?;
     }
 
+    // replace the mods, since they might've been removed. forge servers only send
+    // their mods if we used the right fml marker, so we keep the old mods if this
+    // is still a forge server that didn't send any.
+    let is_forge = r.forgedata_fml_network_version.is_some() || r.modinfo_type.is_some();
+    if had_mods && (!r.mods.is_empty() || !is_forge) {
+        sqlx::query("DELETE FROM server_mods WHERE server_ip = $1 AND server_port = $2")
+            .bind(PgU32(target.ip().to_bits()))
+            .bind(PgU16(target.port()))
+            .execute(&mut *txn)
+            .await?;
+    }
+    if !r.mods.is_empty() {
+        let mut query_builder =
+            QueryBuilder::new("INSERT INTO server_mods (server_ip, server_port, mod_id, version) ");
+        query_builder.push_values(&r.mods, |mut b, server_mod| {
+            b.push_bind(PgU32(target.ip().to_bits()))
+                .push_bind(PgU16(target.port()))
+                .push_bind(server_mod.id.clone())
+                .push_bind(server_mod.version.clone());
+        });
+        let query = query_builder.build();
+        query.execute(&mut *txn).await?;
+    }
+
     txn.commit().await?;
     Ok(())
 }
@@ -410,6 +502,8 @@ pub async fn insert_server_to_db(
 struct InsertServerQueryBuilder<'a> {
     pub qb: QueryBuilder<'a, Postgres>,
     pub field_names: Vec<String>,
+    /// Fields that keep their old value when the new one is null.
+    pub keep_if_null: HashSet<String>,
     pub arguments: PgArguments,
 }
 impl<'a> InsertServerQueryBuilder<'a> {
@@ -417,6 +511,7 @@ impl<'a> InsertServerQueryBuilder<'a> {
         Self {
             qb: QueryBuilder::new("INSERT INTO servers ("),
             field_names: Vec::new(),
+            keep_if_null: HashSet::new(),
             arguments: PgArguments::default(),
         }
     }
@@ -436,6 +531,17 @@ impl<'a> InsertServerQueryBuilder<'a> {
         };
     }
 
+    /// Same as [`Self::field`], but the old value is kept if the new one is
+    /// null.
+    pub fn field_or_keep(
+        &mut self,
+        name: &str,
+        value: impl sqlx::Encode<'a, Postgres> + sqlx::Type<sqlx::Postgres> + 'a,
+    ) {
+        self.keep_if_null.insert(name.to_string());
+        self.field(name, value);
+    }
+
     pub fn into_querybuilder(mut self) -> QueryBuilder<'a, Postgres> {
         self.qb.push(") VALUES (");
         let mut first = true;
@@ -461,8 +567,13 @@ impl<'a> InsertServerQueryBuilder<'a> {
                 self.qb.push(", ");
             }
             self.qb.push(name);
-            self.qb.push(" = EXCLUDED.");
-            self.qb.push(name);
+            if self.keep_if_null.contains(name) {
+                self.qb
+                    .push(format!(" = COALESCE(EXCLUDED.{name}, servers.{name})"));
+            } else {
+                self.qb.push(" = EXCLUDED.");
+                self.qb.push(name);
+            }
         }
         QueryBuilder::with_arguments(self.qb.into_sql(), self.arguments)
     }
@@ -479,6 +590,13 @@ fn make_ping_response_hash(ping_res: &PingResponse) -> eyre::Result<u64> {
     Ok(hasher.finish())
 }
 
+/// The uuid that offline-mode servers give to a player with this username.
+pub fn offline_uuid(username: &str) -> Uuid {
+    let mut hasher = md5::Md5::new();
+    hasher.update(format!("OfflinePlayer:{username}").as_bytes());
+    uuid::Builder::from_md5_bytes(hasher.finalize().into()).into_uuid()
+}
+
 fn make_favicon_hash(favicon: &str) -> [u8; 16] {
     let mut hasher = sha2::Sha256::new();
     hasher.update(favicon.as_bytes());
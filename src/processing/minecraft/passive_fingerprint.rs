@@ -1,3 +1,7 @@
+use sqlx::{Postgres, QueryBuilder, Row};
+
+use crate::database::{Database, PgU16, PgU32};
+
 pub struct PassiveMinecraftFingerprint {
     pub incorrect_order: bool,
     pub field_order: Option<String>,
@@ -100,3 +104,288 @@ pub fn generate_passive_fingerprint(data: &str) -> eyre::Result<PassiveMinecraft
         empty_favicon,
     })
 }
+
+/// The server software, as guessed from the server list ping alone.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Software {
+    Vanilla,
+    Paper,
+    Purpur,
+    Folia,
+    Pufferfish,
+    Spigot,
+    Velocity,
+    BungeeCord,
+    Waterfall,
+    Forge,
+    NeoForge,
+    /// Forge with the Bukkit API, like Mohist or Arclight.
+    ForgeBukkitHybrid,
+    /// Not vanilla, but we don't know what it is.
+    Custom,
+    Unknown,
+}
+
+impl Software {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Software::Vanilla => "vanilla",
+            Software::Paper => "paper",
+            Software::Purpur => "purpur",
+            Software::Folia => "folia",
+            Software::Pufferfish => "pufferfish",
+            Software::Spigot => "spigot",
+            Software::Velocity => "velocity",
+            Software::BungeeCord => "bungeecord",
+            Software::Waterfall => "waterfall",
+            Software::Forge => "forge",
+            Software::NeoForge => "neoforge",
+            Software::ForgeBukkitHybrid => "forge_bukkit_hybrid",
+            Software::Custom => "custom",
+            Software::Unknown => "unknown",
+        }
+    }
+}
+
+/// The passive signals we have about a server, as stored in the `servers`
+/// table.
+#[derive(Default)]
+pub struct SoftwareSignals<'a> {
+    pub version_name: Option<&'a str>,
+    pub field_order: Option<&'a str>,
+    pub is_empty_sample: bool,
+    pub forgedata_fml_network_version: Option<i32>,
+    pub modinfo_type: Option<&'a str>,
+    pub is_modded: Option<bool>,
+}
+
+struct SoftwareRule {
+    software: Software,
+    /// From 0 to 1, how sure we are that a server that matches the rule is
+    /// running this software.
+    confidence: f32,
+    matches: fn(&SoftwareSignals) -> bool,
+}
+
+fn version_name_starts_with(signals: &SoftwareSignals, prefixes: &[&str]) -> bool {
+    signals.version_name.is_some_and(|v| {
+        prefixes
+            .iter()
+            .any(|p| v.get(..p.len()).is_some_and(|s| s.eq_ignore_ascii_case(p)))
+    })
+}
+
+/// Checked in order, the first rule that matches wins.
+const SOFTWARE_RULES: &[SoftwareRule] = &[
+    // hybrids also send forge data, so they have to be checked first
+    SoftwareRule {
+        software: Software::ForgeBukkitHybrid,
+        confidence: 0.9,
+        matches: |s| version_name_starts_with(s, &["Mohist", "Arclight", "Magma", "Ketting"]),
+    },
+    // only neoforge sends isModded
+    SoftwareRule {
+        software: Software::NeoForge,
+        confidence: 0.9,
+        matches: |s| s.is_modded == Some(true),
+    },
+    SoftwareRule {
+        software: Software::Forge,
+        confidence: 0.9,
+        matches: |s| s.forgedata_fml_network_version.is_some() || s.modinfo_type == Some("FML"),
+    },
+    SoftwareRule {
+        software: Software::Velocity,
+        confidence: 0.8,
+        matches: |s| version_name_starts_with(s, &["Velocity"]),
+    },
+    SoftwareRule {
+        software: Software::Waterfall,
+        confidence: 0.8,
+        matches: |s| version_name_starts_with(s, &["Waterfall"]),
+    },
+    SoftwareRule {
+        software: Software::BungeeCord,
+        confidence: 0.8,
+        matches: |s| version_name_starts_with(s, &["BungeeCord"]),
+    },
+    SoftwareRule {
+        software: Software::Purpur,
+        confidence: 0.8,
+        matches: |s| version_name_starts_with(s, &["Purpur"]),
+    },
+    SoftwareRule {
+        software: Software::Folia,
+        confidence: 0.8,
+        matches: |s| version_name_starts_with(s, &["Folia"]),
+    },
+    SoftwareRule {
+        software: Software::Pufferfish,
+        confidence: 0.8,
+        matches: |s| version_name_starts_with(s, &["Pufferfish"]),
+    },
+    SoftwareRule {
+        software: Software::Paper,
+        confidence: 0.8,
+        matches: |s| version_name_starts_with(s, &["Paper"]),
+    },
+    SoftwareRule {
+        software: Software::Spigot,
+        confidence: 0.8,
+        matches: |s| version_name_starts_with(s, &["Spigot", "CraftBukkit"]),
+    },
+    // bungeecord-based proxies say which versions they support, like "1.8.x-1.21.x"
+    SoftwareRule {
+        software: Software::BungeeCord,
+        confidence: 0.6,
+        matches: |s| s.version_name.is_some_and(|v| v.contains(".x-")),
+    },
+    // vanilla always sends the fields in the same order and never sends an empty
+    // sample
+    SoftwareRule {
+        software: Software::Custom,
+        confidence: 0.6,
+        matches: |s| s.field_order.is_some() || s.is_empty_sample,
+    },
+    // a plain version name like "1.21.4" is what vanilla sends, but plugins can
+    // change it
+    SoftwareRule {
+        software: Software::Vanilla,
+        confidence: 0.4,
+        matches: |s| {
+            s.version_name
+                .is_some_and(|v| !v.is_empty() && v.chars().all(|c| c.is_ascii_digit() || c == '.'))
+        },
+    },
+];
+
+/// Guess the software from the passive signals, returning the software and
+/// how confident we are (from 0 to 1).
+pub fn classify_software(signals: &SoftwareSignals) -> (Software, f32) {
+    SOFTWARE_RULES
+        .iter()
+        .find(|rule| (rule.matches)(signals))
+        .map(|rule| (rule.software, rule.confidence))
+        .unwrap_or((Software::Unknown, 0.))
+}
+
+/// Classify every server in the database again, for when the rules change.
+pub async fn backfill_software(database: &Database) -> eyre::Result<()> {
+    const BATCH_SIZE: i64 = 10_000;
+
+    // 0.0.0.0:0 can't be in the database, so this doesn't skip anything
+    let mut last_ip = PgU32(0);
+    let mut last_port = PgU16(0);
+    let mut updated = 0;
+    loop {
+        let rows = sqlx::query(
+            "
+            SELECT ip, port, version_name, fingerprint_field_order, fingerprint_is_empty_sample,
+                forgedata_fml_network_version, modinfo_type, is_modded
+            FROM servers
+            WHERE (ip, port) > ($1, $2)
+            ORDER BY ip, port
+            LIMIT $3
+            ",
+        )
+        .bind(last_ip)
+        .bind(last_port)
+        .bind(BATCH_SIZE)
+        .fetch_all(&database.pool)
+        .await?;
+        let Some(last_row) = rows.last() else {
+            break;
+        };
+        last_ip = last_row.get(0);
+        last_port = last_row.get(1);
+
+        let mut qb: QueryBuilder<'_, Postgres> = QueryBuilder::new(
+            "UPDATE servers SET software = v.software, software_confidence = v.confidence FROM (",
+        );
+        qb.push_values(&rows, |mut b, row| {
+            let (software, confidence) = classify_software(&SoftwareSignals {
+                version_name: row.get(2),
+                field_order: row.get(3),
+                is_empty_sample: row.get(4),
+                forgedata_fml_network_version: row.get(5),
+                modinfo_type: row.get(6),
+                is_modded: row.get(7),
+            });
+            b.push_bind(row.get::<PgU32, _>(0))
+                .push_bind(row.get::<PgU16, _>(1))
+                .push_bind(software.as_str())
+                .push_bind(confidence);
+        });
+        qb.push(
+            ") AS v (ip, port, software, confidence) WHERE servers.ip = v.ip AND servers.port = v.port",
+        );
+        qb.build().execute(&database.pool).await?;
+
+        updated += rows.len();
+        println!("classified {updated} servers");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_software() {
+        let classify = |signals: SoftwareSignals| classify_software(&signals).0;
+
+        assert_eq!(
+            classify(SoftwareSignals {
+                version_name: Some("Paper 1.21.4"),
+                ..Default::default()
+            }),
+            Software::Paper
+        );
+        assert_eq!(
+            classify(SoftwareSignals {
+                version_name: Some("Velocity 3.3.0-SNAPSHOT"),
+                ..Default::default()
+            }),
+            Software::Velocity
+        );
+        assert_eq!(
+            classify(SoftwareSignals {
+                version_name: Some("1.8.x-1.21.x"),
+                ..Default::default()
+            }),
+            Software::BungeeCord
+        );
+        assert_eq!(
+            classify(SoftwareSignals {
+                version_name: Some("1.20.1"),
+                forgedata_fml_network_version: Some(3),
+                ..Default::default()
+            }),
+            Software::Forge
+        );
+        assert_eq!(
+            classify(SoftwareSignals {
+                version_name: Some("1.21.4"),
+                ..Default::default()
+            }),
+            Software::Vanilla
+        );
+        assert_eq!(
+            classify(SoftwareSignals {
+                version_name: Some("1.21.4"),
+                is_empty_sample: true,
+                ..Default::default()
+            }),
+            Software::Custom
+        );
+        assert_eq!(
+            classify(SoftwareSignals {
+                version_name: Some("§cMaintenance"),
+                ..Default::default()
+            }),
+            Software::Unknown
+        );
+    }
+}
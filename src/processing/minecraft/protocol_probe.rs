@@ -0,0 +1,60 @@
+use std::net::SocketAddrV4;
+
+use super::PingResponse;
+use crate::database::{Database, PgU16, PgU32};
+
+/// Store the version that the server reported when we pinged it with
+/// `sent_protocol`, and update the range of protocol versions it supports.
+pub async fn insert_protocol_probe_to_db(
+    db: &Database,
+    target: &SocketAddrV4,
+    sent_protocol: i32,
+    r: &PingResponse,
+) -> eyre::Result<()> {
+    let mut txn = db.pool.begin().await?;
+
+    sqlx::query(
+        "
+        INSERT INTO server_protocol_probes (server_ip, server_port, sent_protocol, reported_protocol, version_name, last_pinged)
+        VALUES ($1, $2, $3, $4, $5, NOW())
+        ON CONFLICT (server_ip, server_port, sent_protocol) DO UPDATE SET
+            reported_protocol = EXCLUDED.reported_protocol,
+            version_name = EXCLUDED.version_name,
+            last_pinged = EXCLUDED.last_pinged
+        ",
+    )
+    .bind(PgU32(target.ip().to_bits()))
+    .bind(PgU16(target.port()))
+    .bind(sent_protocol)
+    .bind(r.version_protocol)
+    .bind(&r.version_name)
+    .execute(&mut *txn)
+    .await?;
+
+    // servers that support more than one version usually report the client's
+    // version back to it, and anything else reports the same version every time
+    sqlx::query(
+        "
+        UPDATE servers SET
+            min_supported_protocol = LEAST(p.min_echoed, servers.version_protocol),
+            max_supported_protocol = GREATEST(p.max_echoed, servers.version_protocol),
+            is_multi_version = p.distinct_reported > 1
+        FROM (
+            SELECT
+                MIN(sent_protocol) FILTER (WHERE reported_protocol = sent_protocol) AS min_echoed,
+                MAX(sent_protocol) FILTER (WHERE reported_protocol = sent_protocol) AS max_echoed,
+                COUNT(DISTINCT reported_protocol) AS distinct_reported
+            FROM server_protocol_probes
+            WHERE server_ip = $1 AND server_port = $2
+        ) p
+        WHERE ip = $1 AND port = $2
+        ",
+    )
+    .bind(PgU32(target.ip().to_bits()))
+    .bind(PgU16(target.port()))
+    .execute(&mut *txn)
+    .await?;
+
+    txn.commit().await?;
+    Ok(())
+}
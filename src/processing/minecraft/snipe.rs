@@ -121,7 +121,7 @@ pub fn maybe_log_sniped(
 
 
 
-async fn send_to_webhook(webhook_url: String, message: String) {
+pub async fn send_to_webhook(webhook_url: String, message: String) {
     let client = reqwest::Client::new();
     if let Err(e) = client
         .post(webhook_url)
@@ -0,0 +1,92 @@
+use std::net::SocketAddrV4;
+
+use sqlx::Row;
+
+use super::PingResponse;
+use crate::database::{Database, PgU16, PgU32};
+
+/// Store the response we got by sending `domain` in the handshake, if it's
+/// different from the one the server gives for our default hostname.
+pub async fn insert_virtual_host_to_db(
+    db: &Database,
+    target: &SocketAddrV4,
+    domain: &str,
+    r: &PingResponse,
+) -> eyre::Result<()> {
+    let mut txn = db.pool.begin().await?;
+
+    let default_response = sqlx::query(
+        "SELECT description_plaintext, version_name, max_players, favicon_hash FROM servers WHERE ip = $1 AND port = $2",
+    )
+    .bind(PgU32(target.ip().to_bits()))
+    .bind(PgU16(target.port()))
+    .fetch_optional(&mut *txn)
+    .await?;
+    let Some(default_response) = default_response else {
+        // the virtual_hosts table references servers
+        return Ok(());
+    };
+    let is_same_as_default = default_response.get::<String, _>(0) == r.description_plaintext
+        && default_response.get::<Option<String>, _>(1) == r.version_name
+        && default_response.get::<Option<i32>, _>(2) == r.max_players
+        && default_response.get::<Option<Vec<u8>>, _>(3).as_deref()
+            == r.favicon_hash.as_ref().map(|h| h.as_slice());
+    if is_same_as_default {
+        // the server doesn't route by hostname, or at least not for this domain
+        sqlx::query(
+            "DELETE FROM virtual_hosts WHERE server_ip = $1 AND server_port = $2 AND domain = $3",
+        )
+        .bind(PgU32(target.ip().to_bits()))
+        .bind(PgU16(target.port()))
+        .bind(domain)
+        .execute(&mut *txn)
+        .await?;
+        txn.commit().await?;
+        return Ok(());
+    }
+
+    if r.favicon.is_some() {
+        sqlx::query(
+            r#"
+            INSERT INTO favicons (hash, data)
+            VALUES ($1, $2)
+            ON CONFLICT (hash) DO NOTHING
+            "#,
+        )
+        .bind(r.favicon_hash)
+        .bind(r.favicon.clone())
+        .execute(&mut *txn)
+        .await?;
+    }
+
+    sqlx::query(
+        "
+        INSERT INTO virtual_hosts (server_ip, server_port, domain, last_pinged, description_json, description_plaintext, version_name, version_protocol, online_players, max_players, favicon_hash)
+        VALUES ($1, $2, $3, NOW(), $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT (server_ip, server_port, domain) DO UPDATE SET
+            last_pinged = EXCLUDED.last_pinged,
+            description_json = EXCLUDED.description_json,
+            description_plaintext = EXCLUDED.description_plaintext,
+            version_name = EXCLUDED.version_name,
+            version_protocol = EXCLUDED.version_protocol,
+            online_players = EXCLUDED.online_players,
+            max_players = EXCLUDED.max_players,
+            favicon_hash = EXCLUDED.favicon_hash
+        ",
+    )
+    .bind(PgU32(target.ip().to_bits()))
+    .bind(PgU16(target.port()))
+    .bind(domain)
+    .bind(&r.description_json)
+    .bind(&r.description_plaintext)
+    .bind(&r.version_name)
+    .bind(r.version_protocol)
+    .bind(r.online_players)
+    .bind(r.max_players)
+    .bind(r.favicon_hash)
+    .execute(&mut *txn)
+    .await?;
+
+    txn.commit().await?;
+    Ok(())
+}
@@ -2,85 +2,201 @@ use std::{
     fmt::Display,
     net::SocketAddrV4,
     sync::{Arc, LazyLock},
-    time::SystemTime,
 };
 
-use bson::{Bson, doc};
 use parking_lot::Mutex;
 use regex::Regex;
 
 use super::{ProcessableProtocol, SharedData};
-use crate::{config::Config, database::Database, scanner::protocols};
+use crate::{
+    config::Config,
+    database::{Database, PgU16, PgU32, sanitize_text_for_postgres},
+    scanner::protocols,
+};
 
 static VANILLA_ERROR_REGEX: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new(r"java\.io\.IOException: Packet (?:\d+|login)\/\d+ \(([^)]+)\)").unwrap()
 });
+/// BungeeCord tells outdated clients the whole range of versions it supports,
+/// like "1.8.x-1.21.x".
+static BUNGEE_VERSION_RANGE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\d+\.\d+\.x-\d+\.\d+\.x").unwrap());
+
+/// Strings that identify the software if they show up anywhere in the
+/// response. These are checked in order, before looking at vanilla-style
+/// errors, since forks and proxies often include the vanilla messages too.
+const SIGNATURES: &[(&str, ServerType)] = &[
+    // proxies
+    ("velocity.error.", ServerType::Velocity),
+    ("velocity.kick.", ServerType::Velocity),
+    ("com.velocitypowered", ServerType::Velocity),
+    ("net.md_5.bungee", ServerType::BungeeCord),
+    ("io.github.waterfallmc", ServerType::BungeeCord),
+    (
+        "Username contains invalid characters",
+        ServerType::BungeeCord,
+    ),
+    // paper forks
+    ("org.purpurmc", ServerType::Purpur),
+    ("io.papermc.paper.threadedregions", ServerType::Folia),
+    ("gg.pufferfish", ServerType::PaperFork),
+    ("io.papermc", ServerType::Paper),
+    ("org.spigotmc", ServerType::Spigot),
+    ("net.glowstone", ServerType::Glowstone),
+    ("net.minestom", ServerType::Minestom),
+    // rust
+    ("valence_protocol", ServerType::Valence),
+    ("azalea_protocol", ServerType::Azalea),
+    ("feather_protocol", ServerType::Feather),
+    // mcprotocollib (and things like geyser's java server emulation that use it)
+    ("com.github.steveice10", ServerType::McProtocolLib),
+    ("org.geysermc.mcprotocollib", ServerType::McProtocolLib),
+];
+
+/// The maximum number of characters of the server's response that we store.
+const MAX_ERROR_SNIPPET_LENGTH: usize = 1024;
 
-#[derive(Eq, PartialEq)]
-enum ServerType {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServerType {
     Vanilla,
     Fabric,
     Forge,
     Paper,
+    /// A fork of Paper that isn't Purpur or Folia, like Pufferfish.
+    PaperFork,
+    Purpur,
+    Folia,
+    Spigot,
+    Glowstone,
+    Minestom,
+    Velocity,
+    BungeeCord,
+    Valence,
+    Azalea,
+    Feather,
+    McProtocolLib,
     NodeMinecraftProtocol,
     Empty,
     Unknown,
 }
 
+impl ServerType {
+    /// Whether this tells us what the software is.
+    pub fn is_known(&self) -> bool {
+        !matches!(self, ServerType::Empty | ServerType::Unknown)
+    }
+}
+
 impl ProcessableProtocol for protocols::MinecraftFingerprinting {
     async fn handle_response(
+        &self,
         _shared: Arc<Mutex<SharedData>>,
         _config: Arc<Config>,
         target: SocketAddrV4,
         data: Box<[u8]>,
-        _db: Database,
+        db: Database,
     ) -> eyre::Result<()> {
+        let probe = self.probe();
+        let server_type = classify_fingerprint_response(&data);
+
         let data_string = String::from_utf8_lossy(&data);
-        let server_type = if let Some(packet_name) = VANILLA_ERROR_REGEX
-            .captures(&data_string)
-            .and_then(|c| c.get(1))
-            .map(|m| m.as_str())
-        {
-            match packet_name {
-                "PacketLoginInStart" => ServerType::Paper,
-                "ServerboundHelloPacket" => ServerType::Forge,
-                _ => {
-                    // starts with class_ means fabric
-                    if packet_name.starts_with("class_") {
-                        ServerType::Fabric
-                    }
-                    // 2-3 random letters means vanilla
-                    else if packet_name.len() >= 2 && packet_name.len() <= 3 {
-                        ServerType::Vanilla
-                    } else {
-                        ServerType::Unknown
-                    }
+        println!(
+            "fingerprinted {target} as {server_type} with {}: {data_string:?}",
+            probe.name()
+        );
+
+        let error_snippet = sanitize_text_for_postgres(
+            &data_string
+                .chars()
+                .take(MAX_ERROR_SNIPPET_LENGTH)
+                .collect::<String>(),
+        );
+
+        let mut txn = db.pool.begin().await?;
+        sqlx::query(
+            "
+            INSERT INTO server_fingerprint_probes (server_ip, server_port, probe, software, response, last_probed)
+            VALUES ($1, $2, $3, $4, $5, NOW())
+            ON CONFLICT (server_ip, server_port, probe) DO UPDATE SET
+                software = EXCLUDED.software,
+                response = EXCLUDED.response,
+                last_probed = EXCLUDED.last_probed
+            ",
+        )
+        .bind(PgU32(target.ip().to_bits()))
+        .bind(PgU16(target.port()))
+        .bind(probe.name())
+        .bind(server_type.to_string())
+        .bind(&error_snippet)
+        .execute(&mut *txn)
+        .await?;
+        // only replace the software if this probe told us something, since most
+        // probes get an empty response from most servers
+        sqlx::query(
+            "
+            UPDATE servers SET
+                last_active_fingerprint = NOW(),
+                active_fingerprint_software = CASE WHEN $5 THEN $3 ELSE COALESCE(active_fingerprint_software, $3) END,
+                active_fingerprint_error = $4
+            WHERE ip = $1 AND port = $2
+            ",
+        )
+        .bind(PgU32(target.ip().to_bits()))
+        .bind(PgU16(target.port()))
+        .bind(server_type.to_string())
+        .bind(error_snippet)
+        .bind(server_type.is_known())
+        .execute(&mut *txn)
+        .await?;
+        txn.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Guess the server software from how it responded to one of our invalid
+/// packets.
+pub fn classify_fingerprint_response(data: &[u8]) -> ServerType {
+    let data_string = String::from_utf8_lossy(data);
+
+    for (signature, server_type) in SIGNATURES {
+        if data_string.contains(signature) {
+            return *server_type;
+        }
+    }
+    if BUNGEE_VERSION_RANGE_REGEX.is_match(&data_string) {
+        return ServerType::BungeeCord;
+    }
+
+    if let Some(packet_name) = VANILLA_ERROR_REGEX
+        .captures(&data_string)
+        .and_then(|c| c.get(1))
+        .map(|m| m.as_str())
+    {
+        match packet_name {
+            "PacketLoginInStart" => ServerType::Paper,
+            "ServerboundHelloPacket" => ServerType::Forge,
+            _ => {
+                // starts with class_ means fabric
+                if packet_name.starts_with("class_") {
+                    ServerType::Fabric
+                }
+                // 2-3 random letters means vanilla
+                else if packet_name.len() >= 2 && packet_name.len() <= 3 {
+                    ServerType::Vanilla
+                } else {
+                    ServerType::Unknown
                 }
             }
-        } else if data_string.contains("Forge") {
-            ServerType::Forge
-        } else if data.starts_with(&[0x03, 0x03, 0x80, 0x02]) {
-            ServerType::NodeMinecraftProtocol
-        } else if data.is_empty() {
-            ServerType::Empty
-        } else {
-            ServerType::Unknown
-        };
-
-        println!("fingerprinted {target} as {server_type}: {data_string:?}");
-
-        let mut mongo_update = doc! {
-            "fingerprint.activeMinecraft.timestamp": Bson::DateTime(bson::DateTime::from_system_time(SystemTime::now())),
-        };
-        if server_type != ServerType::Unknown {
-            mongo_update.insert(
-                "fingerprint.activeMinecraft.software",
-                server_type.to_string(),
-            );
         }
-
-        // TODO: re-implement active fingerprinting again
-        Ok(())
+    } else if data_string.contains("Forge") {
+        ServerType::Forge
+    } else if data.starts_with(&[0x03, 0x03, 0x80, 0x02]) {
+        ServerType::NodeMinecraftProtocol
+    } else if data.is_empty() {
+        ServerType::Empty
+    } else {
+        ServerType::Unknown
     }
 }
 
@@ -91,9 +207,128 @@ impl Display for ServerType {
             ServerType::Fabric => "fabric",
             ServerType::Forge => "forge",
             ServerType::Paper => "paper",
+            ServerType::PaperFork => "paper_fork",
+            ServerType::Purpur => "purpur",
+            ServerType::Folia => "folia",
+            ServerType::Spigot => "spigot",
+            ServerType::Glowstone => "glowstone",
+            ServerType::Minestom => "minestom",
+            ServerType::Velocity => "velocity",
+            ServerType::BungeeCord => "bungeecord",
+            ServerType::Valence => "valence",
+            ServerType::Azalea => "azalea",
+            ServerType::Feather => "feather",
+            ServerType::McProtocolLib => "mcprotocollib",
             ServerType::NodeMinecraftProtocol => "node_minecraft_protocol",
             ServerType::Empty => "empty",
             ServerType::Unknown => "unknown",
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Error messages that servers have sent back to our probes, and what they
+    /// should be classified as.
+    const CORPUS: &[(&str, ServerType)] = &[
+        (
+            "java.io.IOException: Packet login/0 (PacketLoginInStart) was larger than I expected, found 1 bytes extra whilst reading packet 0",
+            ServerType::Paper,
+        ),
+        (
+            "java.io.IOException: Packet login/0 (class_2915) was larger than I expected, found 1 bytes extra whilst reading packet 0",
+            ServerType::Fabric,
+        ),
+        (
+            "java.io.IOException: Packet login/0 (aax) was larger than I expected, found 1 bytes extra whilst reading packet 0",
+            ServerType::Vanilla,
+        ),
+        (
+            "java.io.IOException: Packet 2/0 (ServerboundHelloPacket) was larger than I expected, found 1 bytes extra whilst reading packet 0",
+            ServerType::Forge,
+        ),
+        (
+            r#"{"translate":"velocity.error.modern-forwarding-needs-new-client"}"#,
+            ServerType::Velocity,
+        ),
+        (
+            r#"{"text":"§cUsername contains invalid characters."}"#,
+            ServerType::BungeeCord,
+        ),
+        (
+            r#"{"text":"§cOutdated client! Please use 1.8.x-1.21.x"}"#,
+            ServerType::BungeeCord,
+        ),
+        (
+            "io.netty.handler.codec.DecoderException: net.md_5.bungee.protocol.OverflowPacketException: Cannot receive string longer than 255",
+            ServerType::BungeeCord,
+        ),
+        (
+            "Internal Exception: java.lang.IllegalStateException: org.purpurmc.purpur.network.ServerboundBeehivePayload",
+            ServerType::Purpur,
+        ),
+        (
+            "Internal Exception: io.papermc.paper.threadedregions.RegionizedServer$WorldLevelData",
+            ServerType::Folia,
+        ),
+        (
+            "Internal Exception: gg.pufferfish.pufferfish.PufferfishConfig",
+            ServerType::PaperFork,
+        ),
+        (
+            "Internal Exception: org.spigotmc.SpigotConfig",
+            ServerType::Spigot,
+        ),
+        (
+            "net.glowstone.net.codec.login.LoginStartCodec: Username is empty",
+            ServerType::Glowstone,
+        ),
+        (
+            "net.minestom.server.network.packet.client.login.ClientLoginStartPacket: invalid username",
+            ServerType::Minestom,
+        ),
+        (
+            "failed to decode packet with id 0x00: valence_protocol::packets::login::LoginHelloC2s",
+            ServerType::Valence,
+        ),
+        (
+            "azalea_protocol::packets::login::s_hello: Leftover data after reading packet",
+            ServerType::Azalea,
+        ),
+        (
+            "feather_protocol::io: unexpected end of packet",
+            ServerType::Feather,
+        ),
+        (
+            "com.github.steveice10.packetlib.tcp.TcpPacketCodec: Bad packet id",
+            ServerType::McProtocolLib,
+        ),
+        (
+            "org.geysermc.mcprotocollib.network.tcp.TcpPacketCodec: Bad packet id",
+            ServerType::McProtocolLib,
+        ),
+        ("", ServerType::Empty),
+        ("Connection reset", ServerType::Unknown),
+    ];
+
+    #[test]
+    fn test_classify_fingerprint_corpus() {
+        for (response, expected) in CORPUS {
+            assert_eq!(
+                classify_fingerprint_response(response.as_bytes()),
+                *expected,
+                "{response:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_classify_node_minecraft_protocol() {
+        assert_eq!(
+            classify_fingerprint_response(&[0x03, 0x03, 0x80, 0x02, 0x05]),
+            ServerType::NodeMinecraftProtocol
+        );
+    }
+}
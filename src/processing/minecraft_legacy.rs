@@ -0,0 +1,142 @@
+use std::{net::SocketAddrV4, sync::Arc};
+
+use eyre::{OptionExt, bail};
+use parking_lot::Mutex;
+
+use super::{ProcessableProtocol, SharedData};
+use crate::{
+    config::Config,
+    database::{Database, sanitize_text_for_postgres},
+    processing::minecraft::{
+        PingResponse, ensure_allowed, insert_server_to_db,
+        passive_fingerprint::PassiveMinecraftFingerprint,
+    },
+    scanner::protocols,
+};
+
+impl ProcessableProtocol for protocols::MinecraftLegacy {
+    async fn handle_response(
+        &self,
+        _shared: Arc<Mutex<SharedData>>,
+        _config: Arc<Config>,
+        target: SocketAddrV4,
+        data: Box<[u8]>,
+        db: Database,
+    ) -> eyre::Result<()> {
+        let ping_res = parse_legacy_ping_response(&String::from_utf8_lossy(&data))?;
+
+        ensure_allowed(&db, &target, &ping_res)?;
+
+        insert_server_to_db(&db, &target, &ping_res).await
+    }
+}
+
+/// Parse a `§1\0protocol\0version\0motd\0online\0max` string, as returned by
+/// [`protocols::MinecraftLegacy`].
+pub fn parse_legacy_ping_response(d: &str) -> eyre::Result<PingResponse> {
+    let Some(d) = d.strip_prefix("§1\0") else {
+        bail!("Legacy ping response is missing the §1 prefix");
+    };
+    let mut parts = d.split('\0');
+
+    let version_protocol = parts
+        .next()
+        .ok_or_eyre("Missing protocol version")?
+        .parse::<i32>()
+        .ok();
+    let version_name = parts.next().ok_or_eyre("Missing version name")?;
+    let motd = parts.next().ok_or_eyre("Missing motd")?;
+    let online_players = parts
+        .next()
+        .ok_or_eyre("Missing online players")?
+        .parse::<i32>()
+        .ok();
+    let max_players = parts
+        .next()
+        .ok_or_eyre("Missing max players")?
+        .parse::<i32>()
+        .ok();
+
+    let description_json = sanitize_text_for_postgres(&serde_json::to_string(
+        &serde_json::json!({ "text": motd }),
+    )?);
+    let description_plaintext = sanitize_text_for_postgres(&strip_legacy_formatting(motd));
+
+    Ok(PingResponse {
+        description_json,
+        description_plaintext,
+        version_name: Some(sanitize_text_for_postgres(version_name)),
+        version_protocol,
+
+        favicon: None,
+        favicon_hash: None,
+
+        online_players,
+        max_players,
+        // the legacy ping doesn't have a sample
+        is_online_mode: None,
+        player_sample: Vec::new(),
+        is_fake_sample: false,
+
+        enforces_secure_chat: None,
+        previews_chat: None,
+
+        fingerprint: PassiveMinecraftFingerprint {
+            incorrect_order: false,
+            field_order: None,
+            empty_sample: false,
+            empty_favicon: false,
+        },
+        is_legacy: true,
+
+        prevents_chat_reports: None,
+        forgedata_fml_network_version: None,
+        forgedata_is_truncated: None,
+        mods: Vec::new(),
+        fml_marker: None,
+        modinfo_type: None,
+        is_modded: None,
+        modpackdata_project_id: None,
+        modpackdata_name: None,
+        modpackdata_version: None,
+    })
+}
+
+/// Remove § formatting codes from the string.
+fn strip_legacy_formatting(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '§' {
+            // skip the formatting code
+            chars.next();
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_legacy_ping_response() {
+        let res =
+            parse_legacy_ping_response("§1\x0078\x001.6.4\x00§aA Minecraft §lServer\x003\x0020")
+                .unwrap();
+        assert!(res.is_legacy);
+        assert_eq!(res.version_protocol, Some(78));
+        assert_eq!(res.version_name.as_deref(), Some("1.6.4"));
+        assert_eq!(res.description_plaintext, "A Minecraft Server");
+        assert_eq!(res.description_json, r#"{"text":"§aA Minecraft §lServer"}"#);
+        assert_eq!(res.online_players, Some(3));
+        assert_eq!(res.max_players, Some(20));
+    }
+
+    #[test]
+    fn test_parse_truncated_legacy_ping_response() {
+        assert!(parse_legacy_ping_response("§1\x0078\x001.6.4").is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use std::{io::Cursor, net::SocketAddrV4, sync::Arc};
+
+use eyre::{OptionExt, bail};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use tracing::debug;
+
+use super::{ProcessableProtocol, SharedData};
+use crate::{
+    config::Config,
+    database::{Database, PgU16, PgU32, sanitize_text_for_postgres},
+    scanner::protocols::{self, read_varint},
+};
+
+/// What happened when we tried to join the server.
+#[derive(Debug, Eq, PartialEq)]
+pub enum LoginOutcome {
+    /// The server sent an encryption request, so it checks with Mojang.
+    OnlineMode,
+    /// The server let us in without authenticating (or asked for encryption
+    /// without authenticating).
+    OfflineMode,
+    Whitelisted,
+    Banned,
+    OutdatedClient,
+    OutdatedServer,
+    /// The server is a backend behind a Velocity proxy with modern forwarding.
+    VelocityForwarding,
+    /// The server is a backend behind a BungeeCord proxy with IP forwarding.
+    BungeeForwarding,
+    /// We were kicked for some other reason.
+    Kicked,
+}
+
+impl LoginOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginOutcome::OnlineMode => "online_mode",
+            LoginOutcome::OfflineMode => "offline_mode",
+            LoginOutcome::Whitelisted => "whitelisted",
+            LoginOutcome::Banned => "banned",
+            LoginOutcome::OutdatedClient => "outdated_client",
+            LoginOutcome::OutdatedServer => "outdated_server",
+            LoginOutcome::VelocityForwarding => "velocity_forwarding",
+            LoginOutcome::BungeeForwarding => "bungee_forwarding",
+            LoginOutcome::Kicked => "kicked",
+        }
+    }
+
+    /// Whether this outcome tells us if the server is in online mode.
+    pub fn is_online_mode(&self) -> Option<bool> {
+        match self {
+            LoginOutcome::OnlineMode => Some(true),
+            LoginOutcome::OfflineMode => Some(false),
+            _ => None,
+        }
+    }
+}
+
+pub struct LoginProbeResponse {
+    pub outcome: LoginOutcome,
+    /// The disconnect message as chat JSON, if we were kicked.
+    pub kick_reason: Option<String>,
+}
+
+impl ProcessableProtocol for protocols::MinecraftLogin {
+    async fn handle_response(
+        &self,
+        _shared: Arc<Mutex<SharedData>>,
+        _config: Arc<Config>,
+        target: SocketAddrV4,
+        data: Box<[u8]>,
+        db: Database,
+    ) -> eyre::Result<()> {
+        let res = parse_login_response(&data)?;
+        debug!("login probe for {target}: {:?}", res.outcome);
+
+        sqlx::query(
+            "
+            UPDATE servers SET
+                last_login_probe = NOW(),
+                login_probe_outcome = $3,
+                login_probe_kick_reason = $4,
+                is_online_mode = COALESCE($5, is_online_mode)
+            WHERE ip = $1 AND port = $2
+            ",
+        )
+        .bind(PgU32(target.ip().to_bits()))
+        .bind(PgU16(target.port()))
+        .bind(res.outcome.as_str())
+        .bind(res.kick_reason)
+        .bind(res.outcome.is_online_mode())
+        .execute(&db.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Parse the first packet that the server sent after our login start, as
+/// returned by [`protocols::MinecraftLogin`].
+pub fn parse_login_response(data: &[u8]) -> eyre::Result<LoginProbeResponse> {
+    let mut stream = Cursor::new(data);
+    let packet_id = read_varint(&mut stream).ok_or_eyre("Missing packet id")?;
+
+    let outcome = match packet_id {
+        // disconnect
+        0x00 => {
+            let kick_reason = sanitize_text_for_postgres(&read_string(&mut stream)?);
+            let outcome = classify_kick_reason(&kick_reason);
+            return Ok(LoginProbeResponse {
+                outcome,
+                kick_reason: Some(kick_reason),
+            });
+        }
+        // encryption request
+        0x01 => {
+            // server id, public key, and verify token
+            read_string(&mut stream)?;
+            for _ in 0..2 {
+                skip_byte_array(&mut stream)?;
+            }
+            // 1.20.5+ servers can ask for encryption without authenticating
+            match data.get(stream.position() as usize) {
+                Some(0x00) => LoginOutcome::OfflineMode,
+                _ => LoginOutcome::OnlineMode,
+            }
+        }
+        // login success, or set compression which is only sent after encryption if
+        // there is any, so the server skipped it
+        0x02 | 0x03 => LoginOutcome::OfflineMode,
+        // login plugin request
+        0x04 => {
+            // message id
+            read_varint(&mut stream).ok_or_eyre("Missing message id")?;
+            let channel = read_string(&mut stream)?;
+            if channel == "velocity:player_info" {
+                LoginOutcome::VelocityForwarding
+            } else {
+                // some plugin that wants to talk to the client before it joins, the vanilla
+                // client would just say it doesn't understand and continue with the login
+                LoginOutcome::OfflineMode
+            }
+        }
+        _ => bail!("Unknown login packet id {packet_id}"),
+    };
+
+    Ok(LoginProbeResponse {
+        outcome,
+        kick_reason: None,
+    })
+}
+
+fn read_string(stream: &mut Cursor<&[u8]>) -> eyre::Result<String> {
+    let length = read_varint(stream).ok_or_eyre("Missing string length")?;
+    let start = stream.position() as usize;
+    let Some(bytes) = stream.get_ref().get(start..start + length.max(0) as usize) else {
+        bail!("String is longer than the packet");
+    };
+    stream.set_position((start + bytes.len()) as u64);
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn skip_byte_array(stream: &mut Cursor<&[u8]>) -> eyre::Result<()> {
+    let length = read_varint(stream).ok_or_eyre("Missing array length")?;
+    if length < 0 {
+        bail!("Negative array length {length}");
+    }
+    let Some(end) = stream
+        .position()
+        .checked_add(length as u64)
+        .filter(|&end| end <= stream.get_ref().len() as u64)
+    else {
+        bail!("Array is longer than the packet");
+    };
+    stream.set_position(end);
+    Ok(())
+}
+
+/// Figure out why we were kicked from the disconnect message.
+pub fn classify_kick_reason(kick_reason_json: &str) -> LoginOutcome {
+    // vanilla messages are translatable so we can check the key
+    if kick_reason_json.contains("multiplayer.disconnect.not_whitelisted") {
+        return LoginOutcome::Whitelisted;
+    }
+    if kick_reason_json.contains("multiplayer.disconnect.banned") {
+        return LoginOutcome::Banned;
+    }
+    if kick_reason_json.contains("multiplayer.disconnect.outdated_client")
+        || kick_reason_json.contains("multiplayer.disconnect.incompatible")
+    {
+        return LoginOutcome::OutdatedClient;
+    }
+    if kick_reason_json.contains("multiplayer.disconnect.outdated_server") {
+        return LoginOutcome::OutdatedServer;
+    }
+
+    let plaintext = serde_json::from_str::<serde_json::Value>(kick_reason_json)
+        .ok()
+        .and_then(|v| azalea_chat::FormattedText::deserialize(&v).ok())
+        .map(|t| t.to_string())
+        .unwrap_or_else(|| kick_reason_json.to_owned())
+        .to_lowercase();
+
+    if plaintext.contains("whitelist") || plaintext.contains("white-list") {
+        LoginOutcome::Whitelisted
+    } else if plaintext.contains("banned") {
+        LoginOutcome::Banned
+    } else if plaintext.contains("outdated client") || plaintext.contains("incompatible client") {
+        LoginOutcome::OutdatedClient
+    } else if plaintext.contains("outdated server") {
+        LoginOutcome::OutdatedServer
+    } else if plaintext.contains("ip forwarding") || plaintext.contains("bungeecord") {
+        // "If you wish to use IP forwarding, please enable it in your BungeeCord config
+        // as well!"
+        LoginOutcome::BungeeForwarding
+    } else if plaintext.contains("velocity") {
+        // "This server requires you to connect with Velocity."
+        LoginOutcome::VelocityForwarding
+    } else {
+        LoginOutcome::Kicked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_kick_reason() {
+        assert_eq!(
+            classify_kick_reason(r#"{"translate":"multiplayer.disconnect.not_whitelisted"}"#),
+            LoginOutcome::Whitelisted
+        );
+        assert_eq!(
+            classify_kick_reason(r#"{"text":"You are not white-listed on this server!"}"#),
+            LoginOutcome::Whitelisted
+        );
+        assert_eq!(
+            classify_kick_reason(
+                r#"{"translate":"multiplayer.disconnect.outdated_client","with":["1.21.4"]}"#
+            ),
+            LoginOutcome::OutdatedClient
+        );
+        assert_eq!(
+            classify_kick_reason(
+                r#"{"text":"If you wish to use IP forwarding, please enable it in your BungeeCord config as well!"}"#
+            ),
+            LoginOutcome::BungeeForwarding
+        );
+        assert_eq!(
+            classify_kick_reason(r#""This server requires you to connect with Velocity.""#),
+            LoginOutcome::VelocityForwarding
+        );
+        assert_eq!(
+            classify_kick_reason(r#"{"text":"Server is restarting"}"#),
+            LoginOutcome::Kicked
+        );
+    }
+
+    #[test]
+    fn test_parse_encryption_request() {
+        // empty server id, 2 byte public key, 1 byte verify token
+        let res = parse_login_response(&[0x01, 0x00, 0x02, 0xaa, 0xbb, 0x01, 0xcc]).unwrap();
+        assert_eq!(res.outcome, LoginOutcome::OnlineMode);
+        let res = parse_login_response(&[0x01, 0x00, 0x02, 0xaa, 0xbb, 0x01, 0xcc, 0x00]).unwrap();
+        assert_eq!(res.outcome, LoginOutcome::OfflineMode);
+
+        // public key with a negative length
+        assert!(parse_login_response(&[0x01, 0x00, 0xff, 0xff, 0xff, 0xff, 0x0f]).is_err());
+        // public key that's longer than the packet
+        assert!(parse_login_response(&[0x01, 0x00, 0x05, 0xaa, 0xbb, 0x01, 0xcc]).is_err());
+    }
+}
@@ -0,0 +1,409 @@
+//! The GameSpy4 query protocol, which is exposed by servers that have
+//! `enable-query=true` in their server.properties.
+//!
+//! Unlike the server list ping, this is done over UDP with regular sockets
+//! since we only query servers that we already know about.
+//!
+//! https://minecraft.wiki/w/Query
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use eyre::{bail, eyre};
+use futures_util::StreamExt;
+use sqlx::Row;
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+use crate::{
+    config::QueryConfig,
+    database::{Database, PgU16, PgU32, sanitize_text_for_postgres},
+    processing::minecraft::offline_uuid,
+    scanner::targets::Ipv4Ranges,
+    strategies::follow_up::pick_and_mark,
+};
+
+const MAGIC: [u8; 2] = [0xfe, 0xfd];
+const PACKET_TYPE_HANDSHAKE: u8 = 0x09;
+const PACKET_TYPE_STAT: u8 = 0x00;
+
+/// The response to a full stat request.
+#[derive(Debug, Default, PartialEq)]
+pub struct QueryResponse {
+    pub motd: Option<String>,
+    pub gametype: Option<String>,
+    pub version: Option<String>,
+    /// The server software, like "CraftBukkit on Bukkit 1.20.4-R0.1-SNAPSHOT".
+    /// This is the part of the `plugins` field before the colon.
+    pub software: Option<String>,
+    /// The plugins with their versions, like "WorldEdit 7.2.15".
+    pub plugins: Vec<String>,
+    pub map: Option<String>,
+    pub online_players: Option<i32>,
+    pub max_players: Option<i32>,
+    pub hostport: Option<u16>,
+    pub hostip: Option<String>,
+    /// The full list of player names, unlike the sample in the server list
+    /// ping which is limited to 12 players.
+    pub players: Vec<String>,
+}
+
+pub fn build_handshake_request(session_id: i32) -> Vec<u8> {
+    let mut buffer = MAGIC.to_vec();
+    buffer.push(PACKET_TYPE_HANDSHAKE);
+    buffer.extend_from_slice(&session_id.to_be_bytes());
+    buffer
+}
+
+/// Returns the challenge token from the server's handshake response.
+pub fn parse_handshake_response(data: &[u8], session_id: i32) -> eyre::Result<i32> {
+    let data = parse_header(data, PACKET_TYPE_HANDSHAKE, session_id)?;
+    let (token, _) = read_string(data).ok_or_else(|| eyre!("Missing challenge token"))?;
+    Ok(token.trim().parse::<i32>()?)
+}
+
+pub fn build_full_stat_request(session_id: i32, challenge_token: i32) -> Vec<u8> {
+    let mut buffer = MAGIC.to_vec();
+    buffer.push(PACKET_TYPE_STAT);
+    buffer.extend_from_slice(&session_id.to_be_bytes());
+    buffer.extend_from_slice(&challenge_token.to_be_bytes());
+    // padding, this is what makes it a full stat instead of a basic stat
+    buffer.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+    buffer
+}
+
+pub fn parse_full_stat_response(data: &[u8], session_id: i32) -> eyre::Result<QueryResponse> {
+    let data = parse_header(data, PACKET_TYPE_STAT, session_id)?;
+
+    // "splitnum\0\x80\0"
+    let Some(mut data) = data.get(11..) else {
+        bail!("Full stat response is too short");
+    };
+
+    let mut kv = HashMap::new();
+    loop {
+        let (key, rest) = read_string(data).ok_or_else(|| eyre!("Unterminated key"))?;
+        if key.is_empty() {
+            data = rest;
+            break;
+        }
+        let (value, rest) = read_string(rest).ok_or_else(|| eyre!("Unterminated value"))?;
+        kv.insert(key, value);
+        data = rest;
+    }
+
+    // "\x01player_\0\0"
+    let mut players = Vec::new();
+    if let Some(mut data) = data.get(10..) {
+        while let Some((name, rest)) = read_string(data) {
+            if name.is_empty() {
+                break;
+            }
+            players.push(sanitize_text_for_postgres(&name));
+            data = rest;
+        }
+    }
+
+    let get = |k: &str| {
+        kv.get(k)
+            .filter(|v| !v.is_empty())
+            .map(|v| sanitize_text_for_postgres(v))
+    };
+
+    // vanilla just sends an empty string, bukkit-based servers send
+    // "<software>: <plugin>; <plugin>"
+    let (software, plugins) = match get("plugins") {
+        Some(plugins_string) => match plugins_string.split_once(':') {
+            Some((software, plugins)) => (
+                Some(software.trim().to_owned()),
+                plugins
+                    .split(';')
+                    .map(|p| p.trim().to_owned())
+                    .filter(|p| !p.is_empty())
+                    .collect(),
+            ),
+            None => (Some(plugins_string.trim().to_owned()), Vec::new()),
+        },
+        None => (None, Vec::new()),
+    };
+
+    Ok(QueryResponse {
+        motd: get("hostname"),
+        gametype: get("gametype"),
+        version: get("version"),
+        software,
+        plugins,
+        map: get("map"),
+        online_players: get("numplayers").and_then(|v| v.parse().ok()),
+        max_players: get("maxplayers").and_then(|v| v.parse().ok()),
+        hostport: get("hostport").and_then(|v| v.parse().ok()),
+        hostip: get("hostip"),
+        players,
+    })
+}
+
+/// Check the packet type and session id, and return the rest of the packet.
+fn parse_header(data: &[u8], packet_type: u8, session_id: i32) -> eyre::Result<&[u8]> {
+    if data.len() < 5 {
+        bail!("Query response is too short");
+    }
+    if data[0] != packet_type {
+        bail!("Expected packet type {packet_type}, got {}", data[0]);
+    }
+    let received_session_id = i32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+    if received_session_id != session_id {
+        bail!("Session id mismatch");
+    }
+    Ok(&data[5..])
+}
+
+/// Read a null-terminated string, and return it along with the remaining data.
+fn read_string(data: &[u8]) -> Option<(String, &[u8])> {
+    let end = data.iter().position(|&b| b == 0)?;
+    Some((
+        String::from_utf8_lossy(&data[..end]).into_owned(),
+        &data[end + 1..],
+    ))
+}
+
+/// Do the handshake and full stat request.
+pub async fn query_server(target: SocketAddrV4, timeout: Duration) -> eyre::Result<QueryResponse> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(target).await?;
+
+    // the server only reads the lower 4 bits of each byte
+    let session_id = rand::random::<i32>() & 0x0f0f0f0f;
+    let mut buffer = vec![0; 65536];
+
+    socket.send(&build_handshake_request(session_id)).await?;
+    let len = tokio::time::timeout(timeout, socket.recv(&mut buffer)).await??;
+    let challenge_token = parse_handshake_response(&buffer[..len], session_id)?;
+
+    socket
+        .send(&build_full_stat_request(session_id, challenge_token))
+        .await?;
+    let len = tokio::time::timeout(timeout, socket.recv(&mut buffer)).await??;
+    parse_full_stat_response(&buffer[..len], session_id)
+}
+
+/// Query servers that were pinged recently and haven't been queried recently,
+/// and store the results.
+///
+/// This doesn't go through the scanner, so the excluded ranges and the rate
+/// (in packets per second) have to be passed in.
+pub async fn query_servers(
+    database: &Database,
+    config: &QueryConfig,
+    exclude_ranges: &Ipv4Ranges,
+    rate: u64,
+) -> eyre::Result<()> {
+    let mut targets = pick_and_mark(database, "last_query_attempt", None, &config.follow_up)
+        .await?
+        .into_iter()
+        .map(|(target, _)| target)
+        .collect::<Vec<_>>();
+    let count_before_exclude = targets.len();
+    {
+        let shared = database.shared.lock();
+        targets.retain(|target| {
+            if exclude_ranges.contains(*target.ip()) {
+                return false;
+            }
+            // ips that have the same server on every port are only scanned on the
+            // allowed port
+            match shared.aliased_ips_to_allowed_port.get(target.ip()) {
+                Some(&allowed_port) => target.port() == allowed_port,
+                None => true,
+            }
+        });
+    }
+    println!(
+        "querying {} servers ({} excluded)",
+        targets.len(),
+        count_before_exclude - targets.len()
+    );
+
+    // every query is a handshake and a full stat request
+    let send_interval = Duration::from_secs_f64(2. / rate.max(1) as f64);
+    let start = tokio::time::Instant::now();
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let mut responses = futures_util::stream::iter(targets.into_iter().enumerate())
+        .map(|(i, target)| async move {
+            tokio::time::sleep_until(start + send_interval * i as u32).await;
+            (target, query_server(target, timeout).await)
+        })
+        .buffer_unordered(config.concurrency);
+
+    let mut successful: usize = 0;
+    let mut failed: usize = 0;
+    while let Some((target, res)) = responses.next().await {
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                debug!("query failed for {target}: {err}");
+                failed += 1;
+                continue;
+            }
+        };
+        successful += 1;
+        if let Err(err) = insert_query_response_to_db(database, &target, &res).await {
+            eprintln!("failed to insert query response for {target}: {err}");
+        }
+    }
+
+    println!("finished querying ({successful} successful, {failed} failed)");
+    info!("Finished querying servers ({successful} successful, {failed} failed)");
+
+    Ok(())
+}
+
+pub async fn insert_query_response_to_db(
+    db: &Database,
+    target: &SocketAddrV4,
+    r: &QueryResponse,
+) -> eyre::Result<()> {
+    let mut txn = db.pool.begin().await?;
+
+    let Some(row) = sqlx::query(
+        "
+        UPDATE servers SET
+            last_queried = NOW(),
+            last_query_attempt = NOW(),
+            query_software = $3,
+            query_plugins = $4,
+            query_map = $5,
+            query_hostip = $6
+        WHERE ip = $1 AND port = $2
+        RETURNING is_online_mode
+        ",
+    )
+    .bind(PgU32(target.ip().to_bits()))
+    .bind(PgU16(target.port()))
+    .bind(&r.software)
+    .bind(&r.plugins)
+    .bind(&r.map)
+    .bind(&r.hostip)
+    .fetch_optional(&mut *txn)
+    .await?
+    else {
+        // the server was deleted while we were querying it
+        return Ok(());
+    };
+    let is_online_mode = row.get::<Option<bool>, _>(0);
+
+    if !r.players.is_empty() {
+        // we only get usernames from the query, so update the players that we
+        // already know about
+        sqlx::query(
+            "
+            UPDATE server_players SET last_seen = NOW()
+            WHERE server_ip = $1 AND server_port = $2 AND username = ANY($3)
+            ",
+        )
+        .bind(PgU32(target.ip().to_bits()))
+        .bind(PgU16(target.port()))
+        .bind(&r.players)
+        .execute(&mut *txn)
+        .await?;
+
+        match is_online_mode {
+            Some(false) => {
+                // offline-mode uuids are derived from the username
+                let uuids = r
+                    .players
+                    .iter()
+                    .map(|name| offline_uuid(name))
+                    .collect::<Vec<_>>();
+                sqlx::query(
+                    "
+                    INSERT INTO server_players (server_ip, server_port, uuid, username, online_mode, last_seen)
+                    SELECT $1, $2, uuid, username, false, NOW() FROM UNNEST($3::uuid[], $4::text[]) AS p (uuid, username)
+                    ON CONFLICT (server_ip, server_port, uuid) DO UPDATE SET last_seen = EXCLUDED.last_seen, username = EXCLUDED.username
+                    ",
+                )
+                .bind(PgU32(target.ip().to_bits()))
+                .bind(PgU16(target.port()))
+                .bind(uuids)
+                .bind(&r.players)
+                .execute(&mut *txn)
+                .await?;
+            }
+            Some(true) => {
+                // look up the uuids of players that we've seen on other online-mode
+                // servers
+                sqlx::query(
+                    "
+                    INSERT INTO server_players (server_ip, server_port, uuid, username, online_mode, last_seen)
+                    SELECT DISTINCT ON (username) $1, $2, uuid, username, true, NOW() FROM server_players
+                    WHERE username = ANY($3) AND online_mode
+                    ORDER BY username, last_seen DESC
+                    ON CONFLICT (server_ip, server_port, uuid) DO UPDATE SET last_seen = EXCLUDED.last_seen
+                    ",
+                )
+                .bind(PgU32(target.ip().to_bits()))
+                .bind(PgU16(target.port()))
+                .bind(&r.players)
+                .execute(&mut *txn)
+                .await?;
+            }
+            None => {}
+        }
+    }
+
+    txn.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_handshake_response() {
+        let session_id = 0x01020304;
+        let mut data = vec![PACKET_TYPE_HANDSHAKE, 0x01, 0x02, 0x03, 0x04];
+        data.extend_from_slice(b"9513307\0");
+        assert_eq!(
+            parse_handshake_response(&data, session_id).unwrap(),
+            9513307
+        );
+        assert!(parse_handshake_response(&data, 1).is_err());
+    }
+
+    #[test]
+    fn test_parse_full_stat_response() {
+        let session_id = 1;
+        let mut data = vec![PACKET_TYPE_STAT, 0x00, 0x00, 0x00, 0x01];
+        data.extend_from_slice(b"splitnum\0\x80\0");
+        data.extend_from_slice(
+            b"hostname\0A Minecraft Server\0gametype\0SMP\0game_id\0MINECRAFT\0\
+            version\x001.20.4\0plugins\0Paper on 1.20.4: WorldEdit 7.2.15; Essentials 2.20.1\0\
+            map\0world\0numplayers\x002\0maxplayers\x0020\0hostport\x0025565\0hostip\x00127.0.0.1\0\0",
+        );
+        data.extend_from_slice(b"\x01player_\0\0");
+        data.extend_from_slice(b"Notch\0jeb_\0\0");
+
+        assert_eq!(
+            parse_full_stat_response(&data, session_id).unwrap(),
+            QueryResponse {
+                motd: Some("A Minecraft Server".to_owned()),
+                gametype: Some("SMP".to_owned()),
+                version: Some("1.20.4".to_owned()),
+                software: Some("Paper on 1.20.4".to_owned()),
+                plugins: vec![
+                    "WorldEdit 7.2.15".to_owned(),
+                    "Essentials 2.20.1".to_owned()
+                ],
+                map: Some("world".to_owned()),
+                online_players: Some(2),
+                max_players: Some(20),
+                hostport: Some(25565),
+                hostip: Some("127.0.0.1".to_owned()),
+                players: vec!["Notch".to_owned(), "jeb_".to_owned()],
+            }
+        );
+    }
+}
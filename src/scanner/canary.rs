@@ -0,0 +1,116 @@
+//! Servers that we know are up, which are sent to in every scan so we can tell
+//! how much of the packet loss is from our own network.
+
+use std::{collections::HashSet, fmt, net::SocketAddrV4};
+
+/// The indexes in a session of `count` targets where the canaries should be
+/// sent, spread out so they're affected by loss in every part of the scan.
+pub fn spread_positions(count: u64, canaries: usize) -> Vec<u64> {
+    (0..canaries as u64)
+        .map(|i| count * (i + 1) / (canaries as u64 + 1))
+        .collect()
+}
+
+/// What we got back from the canaries in the current scan.
+#[derive(Default)]
+pub struct CanaryResults {
+    syn_acks: HashSet<SocketAddrV4>,
+    responses: HashSet<SocketAddrV4>,
+}
+
+impl CanaryResults {
+    pub fn record_syn_ack(&mut self, address: SocketAddrV4) {
+        self.syn_acks.insert(address);
+    }
+
+    pub fn record_response(&mut self, address: SocketAddrV4) {
+        self.responses.insert(address);
+    }
+
+    /// Make a report for the scan that just finished and reset the results.
+    pub fn take_report(&mut self, sent: u64) -> CanaryReport {
+        let report = CanaryReport {
+            sent,
+            syn_acks: self.syn_acks.len() as u64,
+            responses: self.responses.len() as u64,
+        };
+        self.syn_acks.clear();
+        self.responses.clear();
+        report
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CanaryReport {
+    pub sent: u64,
+    pub syn_acks: u64,
+    pub responses: u64,
+}
+
+impl CanaryReport {
+    /// The fraction of canaries that didn't reply with a SYN+ACK, which means
+    /// our SYN or their SYN+ACK was lost. None if no canaries were sent.
+    pub fn handshake_loss(&self) -> Option<f64> {
+        (self.sent > 0).then(|| 1. - (self.syn_acks.min(self.sent) as f64 / self.sent as f64))
+    }
+
+    /// The fraction of canaries that replied with a SYN+ACK but never sent a
+    /// full response, which means our payload or their response was lost.
+    pub fn response_loss(&self) -> Option<f64> {
+        (self.syn_acks > 0)
+            .then(|| 1. - (self.responses.min(self.syn_acks) as f64 / self.syn_acks as f64))
+    }
+
+    /// The fraction of canaries that we didn't get a response from.
+    pub fn loss(&self) -> Option<f64> {
+        (self.sent > 0).then(|| 1. - (self.responses.min(self.sent) as f64 / self.sent as f64))
+    }
+}
+
+impl fmt::Display for CanaryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} canaries replied ({}/{} syn+acks",
+            self.responses, self.sent, self.syn_acks, self.sent
+        )?;
+        if let Some(handshake_loss) = self.handshake_loss() {
+            write!(f, ", {:.1}% handshake loss", handshake_loss * 100.)?;
+        }
+        if let Some(response_loss) = self.response_loss() {
+            write!(f, ", {:.1}% response loss", response_loss * 100.)?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_canary_report() {
+        assert_eq!(spread_positions(100, 3), vec![25, 50, 75]);
+        assert_eq!(spread_positions(0, 2), vec![0, 0]);
+
+        let mut results = CanaryResults::default();
+        let canary = |i| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, i), 25565);
+        for i in 0..3 {
+            results.record_syn_ack(canary(i));
+        }
+        // retransmitted syn+acks are only counted once
+        results.record_syn_ack(canary(0));
+        results.record_response(canary(0));
+        results.record_response(canary(1));
+
+        let report = results.take_report(4);
+        assert_eq!(report.handshake_loss(), Some(0.25));
+        assert!((report.response_loss().unwrap() - 1. / 3.).abs() < 1e-9);
+        assert_eq!(report.loss(), Some(0.5));
+
+        // the results are reset for the next scan
+        assert_eq!(results.take_report(0).loss(), None);
+    }
+}
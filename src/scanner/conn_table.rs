@@ -0,0 +1,252 @@
+//! The connections that we're in the middle of a conversation with, with
+//! limits on how many there can be and how much data they can buffer.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use tracing::trace;
+
+use super::{ConnState, timer_wheel::TimerWheel};
+use crate::config::ConnLimitsConfig;
+
+/// Counters for the connections that were closed early because of the limits.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ConnTableStats {
+    /// Connections that were closed to make room for other connections, or
+    /// because all the connections together were buffering too much.
+    pub evictions: u64,
+    /// Connections that were closed because they sent more than
+    /// `max_conn_bytes` without finishing their response.
+    pub oversized: u64,
+}
+
+pub struct ConnTable {
+    conns: HashMap<SocketAddrV4, ConnState>,
+    limits: ConnLimitsConfig,
+    ping_timeout: Duration,
+
+    /// When each connection should be dropped. Connections that were already
+    /// closed are left in here and ignored when they expire.
+    timeouts: TimerWheel<SocketAddrV4>,
+    /// The connections in the order they were last used, along with the value
+    /// of `last_used` they had then. Entries that don't match the connection's
+    /// current `last_used` are stale and skipped.
+    lru: VecDeque<(SocketAddrV4, u64)>,
+    next_use: u64,
+
+    /// The sum of every connection's `buffered_bytes`.
+    buffered_bytes: usize,
+    /// Connections that were evicted and should be sent an RST.
+    evicted: Vec<(SocketAddrV4, ConnState)>,
+    stats: ConnTableStats,
+}
+
+impl ConnTable {
+    pub fn new(limits: &ConnLimitsConfig, ping_timeout: Duration) -> Self {
+        Self {
+            conns: HashMap::new(),
+            limits: limits.clone(),
+            ping_timeout,
+            timeouts: TimerWheel::new(Duration::from_millis(10)),
+            lru: VecDeque::new(),
+            next_use: 0,
+            buffered_bytes: 0,
+            evicted: Vec::new(),
+            stats: ConnTableStats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    /// The number of bytes that all the connections are buffering.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    pub fn stats(&self) -> ConnTableStats {
+        self.stats
+    }
+
+    /// Get a connection and mark it as the most recently used one.
+    pub fn get_mut(&mut self, address: &SocketAddrV4) -> Option<&mut ConnState> {
+        let conn = self.conns.get_mut(address)?;
+        conn.last_used = self.next_use;
+        self.lru.push_back((*address, self.next_use));
+        self.next_use += 1;
+        self.compact_lru();
+        self.conns.get_mut(address)
+    }
+
+    /// Add a connection. If that makes us go over the connection limit, the
+    /// least recently used connection is evicted.
+    pub fn insert(&mut self, address: SocketAddrV4, mut conn: ConnState) {
+        if let Some(old_conn) = self.conns.remove(&address) {
+            self.buffered_bytes -= old_conn.buffered_bytes;
+        }
+        while self.conns.len() >= self.limits.max_conns && self.evict_lru() {}
+
+        self.timeouts
+            .insert(conn.started + self.ping_timeout, address);
+        conn.last_used = self.next_use;
+        self.lru.push_back((address, self.next_use));
+        self.next_use += 1;
+        self.buffered_bytes += conn.buffered_bytes;
+        self.conns.insert(address, conn);
+    }
+
+    pub fn remove(&mut self, address: &SocketAddrV4) -> Option<ConnState> {
+        let conn = self.conns.remove(address)?;
+        self.buffered_bytes -= conn.buffered_bytes;
+        Some(conn)
+    }
+
+    /// Update how many bytes the connection is buffering after the protocol
+    /// got new data. If all the connections together are buffering too much,
+    /// the least recently used ones are evicted.
+    ///
+    /// Returns false if the connection is buffering more than one connection
+    /// is allowed to. It should be closed in that case.
+    pub fn update_buffered_bytes(&mut self, address: &SocketAddrV4) -> bool {
+        let Some(conn) = self.conns.get_mut(address) else {
+            return true;
+        };
+        let buffered_bytes = conn.protocol_state.buffer.len() + conn.protocol_state.saved.len();
+        self.buffered_bytes = self.buffered_bytes - conn.buffered_bytes + buffered_bytes;
+        conn.buffered_bytes = buffered_bytes;
+
+        if buffered_bytes > self.limits.max_conn_bytes {
+            trace!("{address} sent {buffered_bytes} bytes without finishing its response");
+            self.stats.oversized += 1;
+            return false;
+        }
+
+        while self.buffered_bytes > self.limits.max_total_bytes && self.evict_lru() {}
+        true
+    }
+
+    /// The connections that were evicted since the last time this was called.
+    /// They should be sent an RST, since the server still thinks they're
+    /// open.
+    pub fn take_evicted(&mut self) -> Vec<(SocketAddrV4, ConnState)> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Drop the connections that have been open for longer than the ping
+    /// timeout.
+    pub fn expire(&mut self, now: Instant) {
+        let conns = &mut self.conns;
+        let buffered_bytes = &mut self.buffered_bytes;
+        let ping_timeout = self.ping_timeout;
+        self.timeouts.advance(now, |address| {
+            // the connection might've been closed and then opened again since the
+            // timer was added
+            if let Some(conn) = conns.get(&address)
+                && now - conn.started >= ping_timeout
+            {
+                trace!("dropping connection to {address} because it took too long");
+                *buffered_bytes -= conn.buffered_bytes;
+                conns.remove(&address);
+            }
+        });
+    }
+
+    /// Evict the least recently used connection. Returns false if there was
+    /// nothing to evict.
+    fn evict_lru(&mut self) -> bool {
+        while let Some((address, last_used)) = self.lru.pop_front() {
+            if self
+                .conns
+                .get(&address)
+                .is_some_and(|conn| conn.last_used == last_used)
+            {
+                trace!("evicting connection to {address}");
+                let conn = self.remove(&address).unwrap();
+                self.evicted.push((address, conn));
+                self.stats.evictions += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Remove the stale entries from `lru` if there are too many of them.
+    fn compact_lru(&mut self) {
+        if self.lru.len() > self.conns.len() * 2 + 1024 {
+            let conns = &self.conns;
+            self.lru.retain(|(address, last_used)| {
+                conns
+                    .get(address)
+                    .is_some_and(|conn| conn.last_used == *last_used)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::scanner::protocols::ConnProtocolState;
+
+    fn conn() -> ConnState {
+        ConnState {
+            lane: 0,
+            protocol_state: ConnProtocolState::default(),
+            remote_seq: 0,
+            local_seq: 0,
+            local_port: 61000,
+            started: Instant::now(),
+            fin_sent: false,
+            last_used: 0,
+            buffered_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn test_conn_table_limits() {
+        let mut table = ConnTable::new(
+            &ConnLimitsConfig {
+                max_conns: 2,
+                max_conn_bytes: 100,
+                max_total_bytes: 150,
+            },
+            Duration::from_secs(60),
+        );
+        let addr = |i| SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, i), 25565);
+
+        table.insert(addr(1), conn());
+        table.insert(addr(2), conn());
+        // using the first one makes the second one the least recently used
+        table.get_mut(&addr(1)).unwrap();
+        table.insert(addr(3), conn());
+        let evicted = table.take_evicted();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].0, addr(2));
+
+        table.get_mut(&addr(3)).unwrap().protocol_state.buffer = vec![0; 101];
+        assert!(!table.update_buffered_bytes(&addr(3)));
+        assert_eq!(table.stats().oversized, 1);
+        table.remove(&addr(3));
+        assert_eq!(table.buffered_bytes(), 0);
+
+        // going over the total evicts the least recently used connection
+        table.get_mut(&addr(1)).unwrap().protocol_state.buffer = vec![0; 80];
+        assert!(table.update_buffered_bytes(&addr(1)));
+        table.insert(addr(4), conn());
+        table.get_mut(&addr(4)).unwrap().protocol_state.buffer = vec![0; 80];
+        assert!(table.update_buffered_bytes(&addr(4)));
+        assert_eq!(table.take_evicted()[0].0, addr(1));
+        assert_eq!(table.buffered_bytes(), 80);
+        assert_eq!(table.stats().evictions, 2);
+    }
+}
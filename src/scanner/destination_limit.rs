@@ -0,0 +1,204 @@
+//! Makes sure we don't send too many packets to the same IP or /24, since
+//! that's what gets us abuse reports.
+
+use std::{
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::config::DestinationLimitConfig;
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Clone, Copy)]
+struct BucketLimit {
+    /// Tokens added per second.
+    rate: f64,
+    /// The maximum number of tokens a bucket can hold.
+    burst: f64,
+}
+
+impl BucketLimit {
+    fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            // a bucket has to be able to hold at least one token or nothing would ever be sent
+            burst: burst.max(1) as f64,
+        }
+    }
+
+    fn refill(&self, bucket: &mut TokenBucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        bucket.last_refill = now;
+    }
+
+    /// How long until the bucket has a token, or zero if it already has one.
+    fn wait_time(&self, bucket: &TokenBucket) -> Duration {
+        if bucket.tokens >= 1. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1. - bucket.tokens) / self.rate)
+        }
+    }
+
+    fn is_full(&self, bucket: &TokenBucket, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens + elapsed.as_secs_f64() * self.rate >= self.burst
+    }
+}
+
+pub struct DestinationLimiter {
+    ip_limit: BucketLimit,
+    slash24_limit: BucketLimit,
+
+    ip_buckets: FxHashMap<u32, TokenBucket>,
+    slash24_buckets: FxHashMap<u32, TokenBucket>,
+
+    last_purge: Instant,
+}
+
+impl DestinationLimiter {
+    pub fn new(config: &DestinationLimitConfig) -> Self {
+        Self {
+            ip_limit: BucketLimit::new(config.per_ip_rate, config.per_ip_burst),
+            slash24_limit: BucketLimit::new(config.per_slash24_rate, config.per_slash24_burst),
+            ip_buckets: FxHashMap::default(),
+            slash24_buckets: FxHashMap::default(),
+            last_purge: Instant::now(),
+        }
+    }
+
+    /// Take a token for sending a packet to the IP. If the IP or its /24 is
+    /// over budget, nothing is taken and the time until there'll be a token
+    /// again is returned.
+    pub fn try_acquire(&mut self, ip: Ipv4Addr, now: Instant) -> Result<(), Duration> {
+        let (ip_limit, slash24_limit) = (self.ip_limit, self.slash24_limit);
+        let (ip_bucket, slash24_bucket) = self.buckets(ip, now);
+
+        let wait_time = ip_limit
+            .wait_time(ip_bucket)
+            .max(slash24_limit.wait_time(slash24_bucket));
+        if !wait_time.is_zero() {
+            return Err(wait_time);
+        }
+
+        ip_bucket.tokens -= 1.;
+        slash24_bucket.tokens -= 1.;
+        Ok(())
+    }
+
+    /// Take a token for sending a packet to the IP even if the IP or its /24
+    /// is over budget, and return how long to wait before sending it. The
+    /// buckets go into debt, so packets that have to wait for the same bucket
+    /// are spread out at its rate instead of all being ready at once.
+    pub fn reserve(&mut self, ip: Ipv4Addr, now: Instant) -> Duration {
+        let (ip_limit, slash24_limit) = (self.ip_limit, self.slash24_limit);
+        let (ip_bucket, slash24_bucket) = self.buckets(ip, now);
+
+        let wait_time = ip_limit
+            .wait_time(ip_bucket)
+            .max(slash24_limit.wait_time(slash24_bucket));
+        ip_bucket.tokens -= 1.;
+        slash24_bucket.tokens -= 1.;
+        wait_time
+    }
+
+    /// The refilled buckets for the IP and its /24.
+    fn buckets(&mut self, ip: Ipv4Addr, now: Instant) -> (&mut TokenBucket, &mut TokenBucket) {
+        if now - self.last_purge > Duration::from_secs(1) {
+            self.purge(now);
+        }
+
+        let ip = ip.to_bits();
+        let ip_bucket = self.ip_buckets.entry(ip).or_insert(TokenBucket {
+            tokens: self.ip_limit.burst,
+            last_refill: now,
+        });
+        let slash24_bucket = self
+            .slash24_buckets
+            .entry(ip & 0xffffff00)
+            .or_insert(TokenBucket {
+                tokens: self.slash24_limit.burst,
+                last_refill: now,
+            });
+        self.ip_limit.refill(ip_bucket, now);
+        self.slash24_limit.refill(slash24_bucket, now);
+        (ip_bucket, slash24_bucket)
+    }
+
+    /// Remove the buckets that would be full by now, since they're the same as
+    /// not having a bucket.
+    fn purge(&mut self, now: Instant) {
+        let ip_limit = self.ip_limit;
+        let slash24_limit = self.slash24_limit;
+        self.ip_buckets
+            .retain(|_, bucket| !ip_limit.is_full(bucket, now));
+        self.slash24_buckets
+            .retain(|_, bucket| !slash24_limit.is_full(bucket, now));
+        self.last_purge = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destination_limiter() {
+        let mut limiter = DestinationLimiter::new(&DestinationLimitConfig {
+            enabled: true,
+            per_ip_rate: 10,
+            per_ip_burst: 2,
+            per_slash24_rate: 100,
+            per_slash24_burst: 3,
+        });
+        let now = Instant::now();
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        assert!(limiter.try_acquire(ip, now).is_ok());
+        assert!(limiter.try_acquire(ip, now).is_ok());
+        // out of tokens for the ip, and the next one comes in 100ms
+        let wait_time = limiter.try_acquire(ip, now).unwrap_err();
+        assert!(wait_time > Duration::from_millis(99) && wait_time <= Duration::from_millis(100));
+
+        // another ip in the same /24 only has one token left in the /24's bucket
+        let neighbor = Ipv4Addr::new(1, 2, 3, 5);
+        assert!(limiter.try_acquire(neighbor, now).is_ok());
+        assert!(limiter.try_acquire(neighbor, now).is_err());
+        assert!(limiter.try_acquire(Ipv4Addr::new(1, 2, 4, 5), now).is_ok());
+
+        assert!(
+            limiter
+                .try_acquire(ip, now + Duration::from_millis(100))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_destination_limiter_reserve() {
+        let mut limiter = DestinationLimiter::new(&DestinationLimitConfig {
+            enabled: true,
+            per_ip_rate: 10,
+            per_ip_burst: 1,
+            per_slash24_rate: 100,
+            per_slash24_burst: 100,
+        });
+        let now = Instant::now();
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        assert_eq!(limiter.reserve(ip, now), Duration::ZERO);
+        // each packet that has to wait is ready 100ms after the previous one
+        let wait_times = (0..3).map(|_| limiter.reserve(ip, now)).collect::<Vec<_>>();
+        for (i, wait_time) in wait_times.into_iter().enumerate() {
+            let expected = Duration::from_millis(100 * (i as u64 + 1));
+            assert!(wait_time > expected - Duration::from_millis(1) && wait_time <= expected);
+        }
+        assert!(limiter.try_acquire(ip, now).is_err());
+    }
+}
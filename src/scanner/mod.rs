@@ -1,42 +1,50 @@
+pub mod canary;
+pub mod conn_table;
+pub mod destination_limit;
 pub mod protocols;
+pub mod retry;
+pub mod sender;
 pub mod targets;
 pub mod throttle;
+pub mod timer_wheel;
 
 use std::{
-    borrow::BorrowMut,
-    collections::{HashMap, HashSet, hash_map::DefaultHasher},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet, hash_map::DefaultHasher},
     hash::{Hash, Hasher},
     net::SocketAddrV4,
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
-    thread,
     time::{Duration, Instant},
 };
 
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
 use perfect_rand::PerfectRng;
 use pnet::packet::tcp::TcpFlags;
 use serde::Deserialize;
-use tracing::{trace, warn};
+use tracing::{info, trace, warn};
 
 use self::{
-    protocols::Protocol,
+    conn_table::{ConnTable, ConnTableStats},
+    destination_limit::DestinationLimiter,
+    protocols::{Protocol, ProtocolRegistry},
+    retry::SynAckBitmap,
     targets::{ScanRanges, StaticScanRanges},
-    throttle::Throttler,
+    throttle::RateFeedback,
 };
 use crate::{
-    config::Config,
+    config::{Config, RetryConfig},
     net::tcp::{StatelessTcp, StatelessTcpWriteHalf},
     processing::SharedData,
-    scanner::protocols::{ParseResponseError, Response},
+    scanner::protocols::{ConnProtocolState, Response, Step},
 };
 
 pub struct Scanner {
     pub seed: u64,
     pub client: StatelessTcp,
-    pub conns: HashMap<SocketAddrV4, ConnState>,
+    pub conns: ConnTable,
 }
 
 pub struct ActiveFingerprintingData {
@@ -57,50 +65,80 @@ impl Scanner {
         Scanner {
             seed,
             client,
-            conns: HashMap::<SocketAddrV4, ConnState>::new(),
+            conns: ConnTable::new(
+                &config.conn_limits,
+                Duration::from_secs(config.ping_timeout_secs.unwrap_or(60)),
+            ),
         }
     }
 
-    pub fn purge_old_conns(&mut self, ping_timeout: Duration) {
-        let now = Instant::now();
-        let mut to_delete = Vec::new();
-        for (addr, conn) in &mut self.conns {
-            if now - conn.started > ping_timeout {
-                trace!("dropping connection to {addr} because it took too long");
-                // if it took longer than 60 seconds to reply, then drop the connection
-                to_delete.push(*addr)
-            }
-        }
-        for key in &to_delete {
-            self.conns.remove(key);
+    /// Send an RST to the connections that were evicted from the connection
+    /// table.
+    fn close_evicted_conns(&mut self) {
+        for (address, conn) in self.conns.take_evicted() {
+            self.client
+                .write
+                .send_rst(address, conn.local_port, conn.local_seq, conn.remote_seq);
         }
     }
 }
 
-pub struct ScannerReceiver {
-    pub protocol: Arc<RwLock<Box<dyn Protocol>>>,
+/// The protocol and processing for one of the scan sessions that can be
+/// running at the same time. Responses are matched to a lane by the seed of
+/// their SYN cookie.
+pub struct ReceiverLane {
+    pub seed: u64,
+    pub protocol: Arc<RwLock<ProtocolRegistry>>,
     pub shared_process_data: Arc<Mutex<SharedData>>,
+    /// Where the SYN+ACKs are recorded for the session that's sending for this
+    /// lane, if it's going to retry the targets that didn't reply.
+    pub syn_acks: Arc<RwLock<Option<Arc<SynAckBitmap>>>>,
+}
+
+pub struct ScannerReceiver {
+    pub lanes: Vec<ReceiverLane>,
     pub scanner: Scanner,
     pub has_ended: Arc<AtomicBool>,
+    pub rate_feedback: Arc<RateFeedback>,
+    /// Their responses are recorded in [`SharedData::canaries`] instead of
+    /// being processed.
+    pub canaries: HashSet<SocketAddrV4>,
 
     pub simulate_rx_loss: f32,
 }
 
 impl ScannerReceiver {
-    pub fn recv_loop(&mut self, ping_timeout: Duration) {
+    pub fn recv_loop(&mut self) {
         let mut received_from_ips = HashSet::<SocketAddrV4>::new();
         let mut syn_acks_received: usize = 0;
         let mut connections_started: usize = 0;
 
-        let mut last_purge = Instant::now();
+        let mut last_rate_feedback_update = Instant::now();
+        let mut last_conn_stats_print = Instant::now();
+        let mut last_conn_stats = ConnTableStats::default();
 
         loop {
             if self.has_ended.load(Ordering::Relaxed) {
                 break;
             }
 
-            // println!("switched to recv loop");
-            let protocol = self.protocol.read();
+            // the timeout is short so connections expire on time and we notice when
+            // we should stop
+            self.scanner
+                .client
+                .read
+                .wait_readable(Duration::from_millis(10));
+
+            let protocols = self
+                .lanes
+                .iter()
+                .map(|lane| lane.protocol.read())
+                .collect::<Vec<_>>();
+            let syn_acks = self
+                .lanes
+                .iter()
+                .map(|lane| lane.syn_acks.read().clone())
+                .collect::<Vec<_>>();
             while let Some((ipv4, tcp)) = self.scanner.client.read.recv() {
                 let address = SocketAddrV4::new(ipv4.source, tcp.source);
 
@@ -113,14 +151,37 @@ impl ScannerReceiver {
                     // RST
                     trace!("RST :( {}", address);
 
-                    if self.scanner.conns.contains_key(&address) {
+                    if let Some(conn) = self.scanner.conns.remove(&address) {
                         // the rst might have significance for this protocol
-                        if let Ok(data) = protocol.parse_response(Response::Rst) {
-                            self.shared_process_data
-                                .lock()
-                                .queue
-                                .push_back((address, data));
+                        let registered = protocols[conn.lane].get(address);
+                        if let Ok(data) = registered.protocol.parse_response(Response::Rst) {
+                            let mut shared_process_data =
+                                self.lanes[conn.lane].shared_process_data.lock();
+                            if self.canaries.contains(&address) {
+                                shared_process_data.canaries.record_response(address);
+                            } else {
+                                shared_process_data.queue.push_back((
+                                    address,
+                                    registered.handler.clone(),
+                                    data,
+                                ));
+                            }
                         }
+                    } else if tcp.flags & TcpFlags::ACK != 0
+                        && !self.canaries.contains(&address)
+                        && let Some(lane) = lane_for_acked_payload(
+                            &self.lanes,
+                            &protocols,
+                            address,
+                            tcp.acknowledgement,
+                        )
+                    {
+                        trace!("RST after our payload without a response {address}");
+                        self.lanes[lane]
+                            .shared_process_data
+                            .lock()
+                            .closed_without_response
+                            .insert(address);
                     }
 
                     continue;
@@ -135,7 +196,6 @@ impl ScannerReceiver {
                                 conn.local_seq,
                                 tcp.sequence + 1,
                             );
-                            conn.fin_sent = true;
                         } else {
                             self.scanner.client.write.send_ack(
                                 address,
@@ -145,24 +205,42 @@ impl ScannerReceiver {
                             );
                         }
 
-                        if conn.data.is_empty() {
-                            trace!("FIN with no data :( {}:{}", ipv4.source, tcp.source);
+                        trace!("FIN {}:{}", ipv4.source, tcp.source);
+                        self.scanner.conns.remove(&address);
+                    } else {
+                        if let Some(lane) = lane_for_acked_payload(
+                            &self.lanes,
+                            &protocols,
+                            address,
+                            tcp.acknowledgement,
+                        ) {
+                            trace!("FIN after our payload without a response {address}");
+                            let mut shared_process_data =
+                                self.lanes[lane].shared_process_data.lock();
                             // if there was no data then parse that as a response
-                            if let Ok(data) = protocol.parse_response(Response::Data(vec![])) {
-                                self.shared_process_data
-                                    .lock()
-                                    .queue
-                                    .push_back((address, data));
+                            let registered = protocols[lane].get(address);
+                            if let Ok(data) = registered
+                                .protocol
+                                .parse_response(Response::Data(&mut Vec::new()))
+                            {
+                                if self.canaries.contains(&address) {
+                                    shared_process_data.canaries.record_response(address);
+                                } else {
+                                    shared_process_data.queue.push_back((
+                                        address,
+                                        registered.handler.clone(),
+                                        data,
+                                    ));
+                                }
+                            } else if !self.canaries.contains(&address) {
+                                shared_process_data.closed_without_response.insert(address);
                             }
                         } else {
-                            trace!("FIN {}:{}", ipv4.source, tcp.source);
-                            self.scanner.conns.borrow_mut().remove(&address);
+                            trace!(
+                                "FIN with no connection, probably already forgotten by us {}:{}",
+                                ipv4.source, tcp.source
+                            );
                         }
-                    } else {
-                        trace!(
-                            "FIN with no connection, probably already forgotten by us {}:{}",
-                            ipv4.source, tcp.source
-                        );
                         self.scanner.client.write.send_ack(
                             address,
                             tcp.destination,
@@ -181,13 +259,16 @@ impl ScannerReceiver {
                     // verify that the ack is the cookie+1
                     let ack_number = tcp.acknowledgement;
 
-                    let original_cookie = cookie(&address, self.scanner.seed);
-                    let expected_ack = original_cookie + 1;
-                    if ack_number != expected_ack {
-                        trace!(
-                            "cookie mismatch for {address} (expected {expected_ack}, got {ack_number})"
-                        );
+                    let Some(lane) = self
+                        .lanes
+                        .iter()
+                        .position(|lane| cookie(&address, lane.seed).wrapping_add(1) == ack_number)
+                    else {
+                        trace!("cookie mismatch for {address} (got {ack_number})");
                         continue;
+                    };
+                    if let Some(syn_acks) = &syn_acks[lane] {
+                        syn_acks.record(address);
                     }
 
                     // this is optional, real tcp clients usually do send it but it doesn't appear
@@ -200,7 +281,7 @@ impl ScannerReceiver {
                     //     tcp.sequence.wrapping_add(1),
                     // );
 
-                    let payload = protocol.payload(address);
+                    let payload = protocols[lane].get(address).protocol.payload(address);
                     if payload.is_empty() {
                         // this means we're skipping this server, give them an rst
                         self.scanner.client.write.send_rst(
@@ -220,6 +301,14 @@ impl ScannerReceiver {
                     );
 
                     syn_acks_received += 1;
+                    self.rate_feedback.syn_acks.fetch_add(1, Ordering::Relaxed);
+                    if self.canaries.contains(&address) {
+                        self.lanes[lane]
+                            .shared_process_data
+                            .lock()
+                            .canaries
+                            .record_syn_ack(address);
+                    }
                     trace!("syn acks: {syn_acks_received}");
 
                     // println!("ok sent first ACK+data");
@@ -240,9 +329,7 @@ impl ScannerReceiver {
                     }
 
                     // check if it's already in the connections map
-                    let (ping_response, is_tracked) = if let Some(conn) =
-                        self.scanner.conns.get_mut(&address)
-                    {
+                    let lane = if let Some(conn) = self.scanner.conns.get_mut(&address) {
                         let actual_seq = tcp.sequence;
                         let expected_seq = conn.remote_seq;
                         if actual_seq != conn.remote_seq {
@@ -271,147 +358,256 @@ impl ScannerReceiver {
                             continue;
                         }
                         // this means it's adding more data to this connection
-                        conn.data.extend(tcp.payload.clone());
-                        conn.remote_seq = actual_seq + tcp.payload.len() as u32;
-                        (
-                            protocol.parse_response(Response::Data(conn.data.clone())),
-                            true,
-                        )
+                        conn.remote_seq = actual_seq.wrapping_add(tcp.payload.len() as u32);
+
+                        if conn.fin_sent {
+                            // we're already done with this connection, so don't give the data to
+                            // the protocol again
+                            self.scanner.client.write.send_fin(
+                                address,
+                                tcp.destination,
+                                conn.local_seq,
+                                conn.remote_seq,
+                            );
+                            continue;
+                        }
+                        conn.lane
                     } else {
-                        // this means it's the first data packet we got, verify it
-                        let original_cookie = cookie(&address, self.scanner.seed);
-                        // we never send anything other than the SYN and initial ping so this is
-                        // fine
-                        let packet_size = protocol.payload(address).len();
-                        let cookie_offset = (packet_size + 1) as u32;
-
-                        let expected_ack = original_cookie.wrapping_add(cookie_offset);
-                        if actual_ack != expected_ack {
+                        // this means it's the first data packet we got, verify it. we never send
+                        // anything other than the SYN and initial ping before the first response
+                        // so this is fine
+                        let Some(lane) =
+                            lane_for_acked_payload(&self.lanes, &protocols, address, actual_ack)
+                        else {
                             trace!(
-                                "cookie mismatch when reading data for {address} (expected {expected_ack}, got {actual_ack}, initial was {original_cookie})"
+                                "cookie mismatch when reading data for {address} (got {actual_ack})"
                             );
                             continue;
-                        }
+                        };
 
-                        let ping_response =
-                            protocol.parse_response(Response::Data(tcp.payload.clone()));
-                        (ping_response, false)
+                        self.scanner.conns.insert(
+                            address,
+                            ConnState {
+                                lane,
+                                protocol_state: ConnProtocolState::default(),
+                                remote_seq: tcp.sequence.wrapping_add(tcp.payload.len() as u32),
+                                local_seq: tcp.acknowledgement,
+                                local_port: tcp.destination,
+                                started: Instant::now(),
+                                fin_sent: false,
+                                last_used: 0,
+                                buffered_bytes: 0,
+                            },
+                        );
+                        self.scanner.close_evicted_conns();
+                        connections_started += 1;
+                        trace!(
+                            "connection #{connections_started} started (with {}:{})",
+                            ipv4.source, tcp.source
+                        );
+                        lane
                     };
 
-                    match ping_response {
-                        Ok(data) => {
+                    let conn = self.scanner.conns.get_mut(&address).unwrap();
+                    let registered = protocols[lane].get(address);
+                    match registered.protocol.on_data(
+                        address,
+                        &mut conn.protocol_state,
+                        &tcp.payload,
+                    ) {
+                        Step::Done(data) => {
                             let data_string = String::from_utf8_lossy(&data);
                             trace!("\n\n{address} {data_string}");
 
-                            if !is_tracked {
-                                self.scanner.conns.borrow_mut().insert(
+                            let mut shared_process_data =
+                                self.lanes[lane].shared_process_data.lock();
+                            if self.canaries.contains(&address) {
+                                shared_process_data.canaries.record_response(address);
+                            } else {
+                                shared_process_data.queue.push_back((
                                     address,
-                                    ConnState {
-                                        data: tcp.payload.to_vec(),
-                                        remote_seq: tcp
-                                            .sequence
-                                            .wrapping_add(tcp.payload.len() as u32),
-                                        local_seq: tcp.acknowledgement,
-                                        started: Instant::now(),
-                                        // we're about to send a fin
-                                        fin_sent: true,
-                                    },
-                                );
-                                connections_started += 1;
-                                trace!(
-                                    "connection #{connections_started} started and ended immediately (with {}:{})",
-                                    ipv4.source, tcp.source
-                                );
+                                    registered.handler.clone(),
+                                    data,
+                                ));
                             }
-
-                            let conn = self.scanner.conns.get(&address).unwrap();
-
-                            self.shared_process_data
-                                .lock()
-                                .queue
-                                .push_back((address, data));
+                            drop(shared_process_data);
 
                             // next line is unnecessary and causes issues when packets are dropped
                             // self.scanner.client.write.send_ack(
                             //     address,
                             //     tcp.destination,
-                            //     actual_ack,
+                            //     conn.local_seq,
                             //     conn.remote_seq,
                             // );
                             self.scanner.client.write.send_fin(
                                 address,
                                 tcp.destination,
-                                actual_ack,
+                                conn.local_seq,
                                 conn.remote_seq,
                             );
+                            conn.fin_sent = true;
                         }
-                        Err(e) => {
-                            match e {
-                                ParseResponseError::Invalid => {
-                                    trace!("packet error, ignoring");
-                                }
-                                ParseResponseError::Incomplete { .. } => {
-                                    if !is_tracked {
-                                        self.scanner.conns.borrow_mut().insert(
-                                            address,
-                                            ConnState {
-                                                data: tcp.payload.to_vec(),
-                                                remote_seq: tcp
-                                                    .sequence
-                                                    .wrapping_add(tcp.payload.len() as u32),
-                                                local_seq: tcp.acknowledgement,
-                                                started: Instant::now(),
-                                                fin_sent: false,
-                                            },
-                                        );
-                                        connections_started += 1;
-                                        trace!(
-                                            "connection #{connections_started} started (with {}:{})",
-                                            ipv4.source, tcp.source
-                                        );
-                                    }
-
-                                    let conn = self.scanner.conns.get(&address).unwrap();
-                                    // always ack whatever they send
-                                    // a better tcp implementation would only ack every 2 packets or
-                                    // after .5 seconds but this technically still follows the spec
-                                    self.scanner.client.write.send_ack(
-                                        address,
-                                        tcp.destination,
-                                        actual_ack,
-                                        conn.remote_seq,
-                                    );
-                                }
-                            };
+                        Step::Send(data) => {
+                            trace!("sending {} more bytes to {address}", data.len());
+                            self.scanner.client.write.send_data(
+                                address,
+                                tcp.destination,
+                                conn.local_seq,
+                                conn.remote_seq,
+                                &data,
+                            );
+                            conn.local_seq = conn.local_seq.wrapping_add(data.len() as u32);
                         }
+                        Step::NeedMore => {
+                            // always ack whatever they send
+                            // a better tcp implementation would only ack every 2 packets or
+                            // after .5 seconds but this technically still follows the spec
+                            self.scanner.client.write.send_ack(
+                                address,
+                                tcp.destination,
+                                conn.local_seq,
+                                conn.remote_seq,
+                            );
+                        }
+                        Step::Abort => {
+                            trace!("packet error, closing connection to {address}");
+                            self.scanner.client.write.send_rst(
+                                address,
+                                tcp.destination,
+                                conn.local_seq,
+                                conn.remote_seq,
+                            );
+                            let conn = self.scanner.conns.remove(&address).unwrap();
+                            // pre-1.7 servers kick us with a legacy kick packet, anything else
+                            // that isn't valid probably isn't a minecraft server
+                            if conn.protocol_state.buffer.first() == Some(&0xff) {
+                                self.lanes[lane]
+                                    .shared_process_data
+                                    .lock()
+                                    .closed_without_response
+                                    .insert(address);
+                            }
+                        }
+                    }
+
+                    if !self.scanner.conns.update_buffered_bytes(&address) {
+                        let conn = self.scanner.conns.remove(&address).unwrap();
+                        self.scanner.client.write.send_rst(
+                            address,
+                            tcp.destination,
+                            conn.local_seq,
+                            conn.remote_seq,
+                        );
                     }
+                    self.scanner.close_evicted_conns();
                 }
             }
-            drop(protocol);
+            drop(protocols);
 
-            // sleep for 50ms
-            thread::sleep(Duration::from_millis(50));
+            let now = Instant::now();
+            self.scanner.conns.expire(now);
+            if now - last_rate_feedback_update > Duration::from_millis(50) {
+                self.update_rate_feedback();
+                last_rate_feedback_update = now;
+            }
+            if now - last_conn_stats_print > Duration::from_secs(60) {
+                self.print_conn_stats(&mut last_conn_stats);
+                last_conn_stats_print = now;
+            }
+        }
+    }
 
-            if last_purge.elapsed() > Duration::from_secs(60) {
-                self.scanner.purge_old_conns(ping_timeout);
-                last_purge = Instant::now();
+    /// Log how many connections were closed early because of the connection
+    /// limits, if that happened since the last time.
+    fn print_conn_stats(&self, last_conn_stats: &mut ConnTableStats) {
+        let stats = self.scanner.conns.stats();
+        if stats == *last_conn_stats {
+            return;
+        }
+        let message = format!(
+            "connection table: {} open, {} KiB buffered, {} evicted ({} new), {} oversized ({} new)",
+            self.scanner.conns.len(),
+            self.scanner.conns.buffered_bytes() / 1024,
+            stats.evictions,
+            stats.evictions - last_conn_stats.evictions,
+            stats.oversized,
+            stats.oversized - last_conn_stats.oversized,
+        );
+        println!("{message}");
+        info!("{message}");
+        *last_conn_stats = stats;
+    }
+
+    fn update_rate_feedback(&self) {
+        match self.scanner.client.read.packet_statistics() {
+            Ok(stats) => {
+                if stats.drops > 0 {
+                    trace!("kernel dropped {} received packets", stats.drops);
+                }
+                self.rate_feedback
+                    .rx_drops
+                    .fetch_add(stats.drops as u64, Ordering::Relaxed);
             }
+            Err(err) => warn!("failed to get packet statistics: {err}"),
         }
 
-        self.scanner.purge_old_conns(ping_timeout);
+        let queue_len = self
+            .lanes
+            .iter()
+            .map(|lane| lane.shared_process_data.lock().queue.len())
+            .sum();
+        self.rate_feedback
+            .queue_len
+            .store(queue_len, Ordering::Relaxed);
     }
 }
 
 pub struct ScanSession {
     pub rng: PerfectRng,
-    pub ranges: StaticScanRanges,
+    pub ranges: Arc<StaticScanRanges>,
+
+    /// The next index to shuffle. This is different from `packets_sent` if
+    /// sends were deferred.
+    next_index: u64,
+    packets_sent: u64,
+    /// The indexes that were over the destination limit, and when the token
+    /// that was reserved for them can be used.
+    deferred: BinaryHeap<Reverse<(Instant, u64)>>,
+    deferred_count: u64,
+
+    /// The canaries that haven't been sent yet and the value of `next_index`
+    /// they're sent at, with the next one at the end.
+    canaries: Vec<(u64, SocketAddrV4)>,
+    canaries_sent: u64,
+
+    /// The passes over the targets that didn't reply, if retries are enabled
+    /// for this session.
+    retry: Option<RetryState>,
+    retries_sent: u64,
+}
+
+struct RetryState {
+    syn_acks: Arc<SynAckBitmap>,
+    passes_left: u32,
+    delay: Duration,
+    /// When the current pass starts, which is set once everything before it
+    /// was sent.
+    pass_starts_at: Option<Instant>,
+    /// How far we are into the current pass, like `next_index`.
+    next_index: u64,
+    /// The number of targets that hadn't replied when the first pass started.
+    missing_before: Option<u64>,
 }
 
 /// The state stored for active connections. We try to keep this existing for
 /// the shortest amount of time possible.
 pub struct ConnState {
-    /// The data we've received so far.
-    data: Vec<u8>,
+    /// The index of the [`ReceiverLane`] that the connection belongs to.
+    lane: usize,
+
+    /// The protocol's state for this connection, including the data we've
+    /// received that it hasn't consumed yet.
+    protocol_state: ConnProtocolState,
 
     /// The last received sequence number + payload length
     ///
@@ -420,132 +616,303 @@ pub struct ConnState {
     /// aka the next expected starting sequence number.
     remote_seq: u32,
 
-    /// The sequence number we send.
+    /// The sequence number we send. This goes up every time the protocol sends
+    /// more data.
     local_seq: u32,
 
-    /// The time that the connection was created. Connections are closed 30
-    /// seconds after creation (if it wasn't closed earlier).
+    /// Our port for the connection, so we can send an RST if it's evicted.
+    local_port: u16,
+
+    /// The time that the connection was created. Connections are dropped once
+    /// they've been open for longer than the ping timeout.
     started: Instant,
 
     /// Whether we've sent a fin packet.
     fin_sent: bool,
+
+    /// When the connection was last used, for evicting the least recently used
+    /// connections.
+    last_used: u64,
+    /// How much data the protocol is buffering for the connection.
+    buffered_bytes: usize,
+}
+
+/// What happened when a session was asked to send its next SYN.
+pub enum SendOutcome {
+    Sent,
+    /// The target was over the destination limit, so it'll be sent later.
+    Deferred,
+    /// Everything that's left was deferred, and the first one can be sent at
+    /// this time.
+    WaitUntil(Instant),
+    /// There's nothing left to send.
+    Exhausted,
 }
 
 impl ScanSession {
     pub fn new(ranges: ScanRanges) -> Self {
         Self {
             rng: PerfectRng::new(ranges.count() as u64, rand::random(), 3),
-            ranges: ranges.to_static(),
+            ranges: Arc::new(ranges.to_static()),
+            next_index: 0,
+            packets_sent: 0,
+            deferred: BinaryHeap::new(),
+            deferred_count: 0,
+            canaries: Vec::new(),
+            canaries_sent: 0,
+            retry: None,
+            retries_sent: 0,
         }
     }
 
-    /// Run the scanner for `scan_duration_secs` and then sleep for
-    /// `sleep_secs`.
+    /// Send more SYNs to the targets that didn't reply after everything else
+    /// was sent, if retries are enabled and the session is small enough.
+    pub fn with_retries(mut self, config: &RetryConfig) -> Self {
+        if !config.enabled || config.passes == 0 || self.ranges.count as u64 > config.max_targets {
+            return self;
+        }
+        self.retry = Some(RetryState {
+            syn_acks: Arc::new(SynAckBitmap::new(self.ranges.clone())),
+            passes_left: config.passes,
+            delay: Duration::from_millis(config.delay_ms),
+            pass_starts_at: None,
+            next_index: 0,
+            missing_before: None,
+        });
+        self
+    }
+
+    /// The bitmap that the receiver should record SYN+ACKs in, if the session
+    /// is going to retry.
+    pub fn syn_acks(&self) -> Option<Arc<SynAckBitmap>> {
+        self.retry.as_ref().map(|retry| retry.syn_acks.clone())
+    }
+
+    /// Also send to the given canaries, spread out through the session.
+    pub fn with_canaries(mut self, canaries: &[SocketAddrV4]) -> Self {
+        let positions = canary::spread_positions(self.ranges.count as u64, canaries.len());
+        self.canaries = positions
+            .into_iter()
+            .zip(canaries.iter().copied())
+            .collect();
+        self.canaries.reverse();
+        self
+    }
+
+    /// Send a SYN to the next target, using the given seed for the cookie.
     ///
-    /// Returns the number of packets sent.
-    pub fn run(
-        self,
-        max_packets_per_second: u64,
+    /// If `destination_limiter` is given, targets whose IP or /24 is over
+    /// budget are deferred and sent later instead.
+    pub fn send_next(
+        &mut self,
         scanner_writer: &mut StatelessTcpWriteHalf,
         seed: u64,
-        scan_duration_secs: u64,
-    ) -> u64 {
-        let mut throttler = Throttler::new(max_packets_per_second);
-
-        let mut packets_sent: u64 = 0;
-
-        let target_count = u64::min(
-            self.ranges.count as u64,
-            max_packets_per_second * scan_duration_secs,
-        );
+        destination_limiter: Option<&mut DestinationLimiter>,
+    ) -> SendOutcome {
+        if let Some(&(position, canary)) = self.canaries.last()
+            && self.next_index >= position
+        {
+            self.canaries.pop();
+            // canaries are our own servers, so they're not limited
+            trace!("sending syn to canary {canary}");
+            scanner_writer.send_syn(canary, cookie(&canary, seed));
+            self.packets_sent += 1;
+            self.canaries_sent += 1;
+            return SendOutcome::Sent;
+        }
 
-        let start = Instant::now();
+        let now = Instant::now();
+        if let Some(&Reverse((ready_at, index))) = self.deferred.peek()
+            && ready_at <= now
+        {
+            self.deferred.pop();
+            // the destination limiter already reserved a token for it when it was deferred
+            let destination_addr = self.ranges.index(index as usize);
+            trace!("sending deferred syn to {destination_addr}");
+            scanner_writer.send_syn(destination_addr, cookie(&destination_addr, seed));
+            self.packets_sent += 1;
+            return SendOutcome::Sent;
+        }
 
-        let mut packets_sent_last_print = 0;
-        let mut last_print_time = Instant::now();
+        let shuffled_index = if self.next_index < self.ranges.count as u64 {
+            self.next_index += 1;
+            self.rng.shuffle(self.next_index - 1)
+        } else if let Some(&Reverse((ready_at, _))) = self.deferred.peek() {
+            return SendOutcome::WaitUntil(ready_at);
+        } else {
+            return self.send_next_retry(scanner_writer, seed, destination_limiter, now);
+        };
+
+        let destination_addr = self.ranges.index(shuffled_index as usize);
+        if let Some(limiter) = destination_limiter
+            && let wait_time = limiter.reserve(*destination_addr.ip(), now)
+            && !wait_time.is_zero()
+        {
+            trace!("deferring syn to {destination_addr} by {wait_time:?}");
+            self.deferred
+                .push(Reverse((now + wait_time, shuffled_index)));
+            self.deferred_count += 1;
+            return SendOutcome::Deferred;
+        }
 
-        loop {
-            // print info about packets per second every 5 seconds
-            let time_since_last_print = Instant::now() - last_print_time;
-            if packets_sent != 0 && time_since_last_print > Duration::from_secs(5) {
-                let packets_per_second = (packets_sent - packets_sent_last_print) as f64
-                    / (Instant::now() - last_print_time).as_secs_f64();
-
-                let packets_per_info = if packets_per_second > 10_000_000. {
-                    format!("{} mpps", (packets_per_second / 1_000_000.).round() as u64)
-                } else if packets_per_second > 10_000. {
-                    format!("{} kpps", (packets_per_second / 1_000.).round() as u64)
-                } else {
-                    format!("{} pps", packets_per_second.round() as u64)
-                };
-
-                let packets_per_second = throttler.estimated_packets_per_second() as f64;
-                let throttler_packets_per_info = if packets_per_second > 10_000_000. {
-                    format!("{} mpps", (packets_per_second / 1_000_000.).round() as u64)
-                } else if packets_per_second > 10_000. {
-                    format!("{} kpps", (packets_per_second / 1_000.).round() as u64)
-                } else {
-                    format!("{} pps", packets_per_second.round() as u64)
-                };
-                println!(
-                    "packets_sent = {packets_sent} ({packets_per_info}, throttler estimate: {throttler_packets_per_info})"
-                );
-
-                packets_sent_last_print = packets_sent;
-                last_print_time = Instant::now();
-            }
+        trace!("sending syn to {destination_addr}");
+        scanner_writer.send_syn(destination_addr, cookie(&destination_addr, seed));
+        self.packets_sent += 1;
+        SendOutcome::Sent
+    }
 
-            let mut batch_size = throttler.next_batch();
-            if packets_sent + batch_size > target_count {
-                batch_size = target_count - packets_sent;
+    /// Send a SYN to the next target that didn't reply, once the delay after
+    /// the previous pass is over.
+    fn send_next_retry(
+        &mut self,
+        scanner_writer: &mut StatelessTcpWriteHalf,
+        seed: u64,
+        mut destination_limiter: Option<&mut DestinationLimiter>,
+        now: Instant,
+    ) -> SendOutcome {
+        let Some(retry) = &mut self.retry else {
+            return SendOutcome::Exhausted;
+        };
+        while retry.passes_left > 0 {
+            // give the SYN+ACKs from the previous pass time to arrive
+            let starts_at = *retry.pass_starts_at.get_or_insert(now + retry.delay);
+            if now < starts_at {
+                return SendOutcome::WaitUntil(starts_at);
             }
+            retry
+                .missing_before
+                .get_or_insert_with(|| retry.syn_acks.missing());
+
+            while retry.next_index < self.ranges.count as u64 {
+                let shuffled_index = self.rng.shuffle(retry.next_index);
+                if retry.syn_acks.contains(shuffled_index) {
+                    retry.next_index += 1;
+                    continue;
+                }
 
-            // tight packet-sending loop
-            for _ in 0..batch_size {
-                let shuffled_index = self.rng.shuffle(packets_sent);
                 let destination_addr = self.ranges.index(shuffled_index as usize);
-                trace!("sending syn to {destination_addr}");
+                if let Some(limiter) = &mut destination_limiter
+                    && let Err(wait_time) = limiter.try_acquire(*destination_addr.ip(), now)
+                {
+                    // retries are done in order, so we wait instead of deferring
+                    return SendOutcome::WaitUntil(now + wait_time);
+                }
+                retry.next_index += 1;
+
+                trace!("retrying syn to {destination_addr}");
                 scanner_writer.send_syn(destination_addr, cookie(&destination_addr, seed));
-                packets_sent += 1;
+                self.packets_sent += 1;
+                self.retries_sent += 1;
+                return SendOutcome::Sent;
             }
 
-            if packets_sent >= target_count {
-                println!("Finished sending {packets_sent} packets.");
-                break;
-            }
-            // if it's been more than 5 minutes since we started, finish the scan
-            else if (Instant::now() - start).as_secs() > scan_duration_secs {
-                println!("{scan_duration_secs} seconds passed, finishing scan.");
-                break;
-            }
+            retry.passes_left -= 1;
+            retry.next_index = 0;
+            retry.pass_starts_at = None;
         }
+        SendOutcome::Exhausted
+    }
+
+    /// The maximum number of packets that the session can send, including the
+    /// canaries and retries.
+    pub fn count(&self) -> u64 {
+        let passes = 1 + self
+            .retry
+            .as_ref()
+            .map_or(0, |retry| retry.passes_left as u64);
+        self.ranges.count as u64 * passes + self.canaries.len() as u64 + self.canaries_sent
+    }
+
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
 
-        packets_sent
+    pub fn canaries_sent(&self) -> u64 {
+        self.canaries_sent
+    }
+
+    pub fn retries_sent(&self) -> u64 {
+        self.retries_sent
+    }
+
+    /// The number of targets that hadn't replied when the retries started, or
+    /// None if there weren't any retries.
+    pub fn missing_before_retries(&self) -> Option<u64> {
+        self.retry.as_ref().and_then(|retry| retry.missing_before)
+    }
+
+    /// When the first deferred target can be sent.
+    pub fn next_deferred_at(&self) -> Option<Instant> {
+        self.deferred.peek().map(|&Reverse((ready_at, _))| ready_at)
+    }
+
+    /// The number of times a send was deferred because of the destination
+    /// limit, and how many targets are still waiting.
+    pub fn deferred_counts(&self) -> (u64, usize) {
+        (self.deferred_count, self.deferred.len())
+    }
+
+    /// Whether every target was sent to.
+    pub fn is_exhausted(&self) -> bool {
+        self.next_index >= self.ranges.count as u64
+            && self.deferred.is_empty()
+            && self.canaries.is_empty()
+            && self
+                .retry
+                .as_ref()
+                .is_none_or(|retry| retry.passes_left == 0)
     }
 }
 
+/// The lane whose payload the given acknowledgement number acknowledges.
+fn lane_for_acked_payload(
+    lanes: &[ReceiverLane],
+    protocols: &[RwLockReadGuard<ProtocolRegistry>],
+    address: SocketAddrV4,
+    ack: u32,
+) -> Option<usize> {
+    (0..lanes.len()).find(|&i| {
+        let protocol = &*protocols[i].get(address).protocol;
+        payload_was_acked(protocol, address, lanes[i].seed, ack)
+    })
+}
+
+/// Whether the given acknowledgement number means that the server received
+/// the entire payload we sent after the SYN+ACK.
+fn payload_was_acked(protocol: &dyn Protocol, address: SocketAddrV4, seed: u64, ack: u32) -> bool {
+    let packet_size = protocol.payload(address).len();
+    if packet_size == 0 {
+        // we didn't send anything
+        return false;
+    }
+    let expected_ack = cookie(&address, seed).wrapping_add((packet_size + 1) as u32);
+    ack == expected_ack
+}
+
 fn cookie(address: &SocketAddrV4, seed: u64) -> u32 {
     let mut hasher = DefaultHasher::new();
     (*address.ip(), address.port(), seed).hash(&mut hasher);
     hasher.finish() as u32
 }
 
-#[derive(Deserialize, Clone, Copy)]
+/// A port or an inclusive range of ports, like `25565` or `{ min = 25560, max
+/// = 25570 }` in the config.
+#[derive(Deserialize, Clone, Copy, Debug)]
 #[serde(untagged)]
-pub enum SourcePort {
+pub enum PortRange {
     Number(u16),
     Range { min: u16, max: u16 },
 }
 
-impl SourcePort {
+impl PortRange {
     /// Pick a source port based on the given seed.
     ///
     /// If the source port is a range, then the port is chosen uniformly from
     /// the range. Otherwise, the port is the given number.
     pub fn pick(&self, seed: u32) -> u16 {
         match self {
-            SourcePort::Number(port) => *port,
-            SourcePort::Range { min, max } => {
+            PortRange::Number(port) => *port,
+            PortRange::Range { min, max } => {
                 let range = max - min;
                 (seed % range as u32) as u16 + min
             }
@@ -554,14 +921,23 @@ impl SourcePort {
 
     pub fn contains(&self, port: u16) -> bool {
         match self {
-            SourcePort::Number(p) => *p == port,
-            SourcePort::Range { min, max } => *min <= port && port <= *max,
+            PortRange::Number(p) => *p == port,
+            PortRange::Range { min, max } => *min <= port && port <= *max,
+        }
+    }
+
+    /// The first and last port in the range.
+    pub fn bounds(self) -> (u16, u16) {
+        match self {
+            PortRange::Number(port) => (port, port),
+            PortRange::Range { min, max } => (min, max),
         }
     }
 }
 
-impl Default for SourcePort {
+impl Default for PortRange {
+    /// The default source port.
     fn default() -> Self {
-        SourcePort::Number(61000)
+        PortRange::Number(61000)
     }
 }
@@ -1,23 +1,130 @@
 mod minecraft;
 mod minecraft_fingerprinting;
+mod minecraft_legacy;
+mod minecraft_login;
+mod registry;
 
 use std::net::SocketAddrV4;
 
-pub use minecraft::Minecraft;
-pub use minecraft_fingerprinting::MinecraftFingerprinting;
+pub use minecraft::{FmlMarker, Minecraft, read_varint, write_varint};
+pub use minecraft_fingerprinting::{FingerprintProbe, MinecraftFingerprinting};
+pub use minecraft_legacy::MinecraftLegacy;
+pub use minecraft_login::MinecraftLogin;
+pub use registry::{ProtocolRegistry, RegisteredProtocol};
 
 #[derive(Debug)]
 pub enum ParseResponseError {
     Invalid,
-    Incomplete { expected_length: u32 },
+    /// We need more data. `expected_length` is how many bytes the whole
+    /// response will be, or 0 if we can't tell yet.
+    Incomplete {
+        expected_length: u32,
+    },
 }
 
-pub enum Response {
-    Data(Vec<u8>),
+pub enum Response<'a> {
+    /// Everything the server sent so far. The protocol should only take the
+    /// data out of the buffer once the response is complete, so it's only
+    /// moved once instead of being copied every time more data arrives.
+    Data(&'a mut Vec<u8>),
     Rst,
 }
 
+/// What the scanner should do after receiving data on a connection.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// Send more data to the server and keep the connection open.
+    Send(Vec<u8>),
+    /// The conversation is over, the data is sent to the processing task and
+    /// the connection is closed.
+    Done(Vec<u8>),
+    /// Wait for the server to send more data.
+    NeedMore,
+    /// Give up on this connection and close it.
+    Abort,
+}
+
+/// The state that a protocol keeps for each connection, so it can have a
+/// conversation with more than one request and response.
+#[derive(Default)]
+pub struct ConnProtocolState {
+    /// The data we've received that hasn't been consumed by the protocol yet.
+    pub buffer: Vec<u8>,
+    /// Which step of the conversation we're on. This starts at 0 and is only
+    /// changed by the protocol.
+    pub step: u32,
+    /// Data from previous steps that the protocol wants to keep, like the
+    /// status response while we wait for a pong.
+    pub saved: Vec<u8>,
+    /// The length that the buffer has to reach before it's worth parsing it
+    /// again, from the last [`ParseResponseError::Incomplete`].
+    pub expected_length: usize,
+}
+
 pub trait Protocol: Send + Sync {
+    /// The data we send after the SYN+ACK. If this is empty, the connection is
+    /// closed immediately.
     fn payload(&self, address: SocketAddrV4) -> Vec<u8>;
     fn parse_response(&self, response: Response) -> Result<Vec<u8>, ParseResponseError>;
+
+    /// Called every time we receive new data on a connection.
+    ///
+    /// The default implementation is for protocols that send one payload and
+    /// receive one response, it buffers the data until [`Self::parse_response`]
+    /// succeeds.
+    fn on_data(&self, _address: SocketAddrV4, state: &mut ConnProtocolState, data: &[u8]) -> Step {
+        state.buffer.extend_from_slice(data);
+        // we already know that it's not complete, so don't parse it again yet
+        if state.buffer.len() < state.expected_length {
+            return Step::NeedMore;
+        }
+        match self.parse_response(Response::Data(&mut state.buffer)) {
+            Ok(data) => Step::Done(data),
+            Err(ParseResponseError::Incomplete { expected_length }) => {
+                state.expected_length = expected_length as usize;
+                Step::NeedMore
+            }
+            Err(ParseResponseError::Invalid) => Step::Abort,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_incremental_response() {
+        let protocol = Minecraft::new("localhost", 25565, 767);
+        let address = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 25565);
+
+        let status = br#"{"description":"A Minecraft Server"}"#;
+        let mut packet = vec![];
+        write_varint(&mut packet, 0x00);
+        write_varint(&mut packet, status.len() as i32);
+        packet.extend_from_slice(status);
+        let mut response = vec![];
+        write_varint(&mut response, packet.len() as i32);
+        response.extend_from_slice(&packet);
+
+        let mut state = ConnProtocolState::default();
+        assert_eq!(
+            protocol.on_data(address, &mut state, &response[..10]),
+            Step::NeedMore
+        );
+        // the length is known after the first segment, so it's not parsed again
+        // until we have everything
+        assert_eq!(state.expected_length, response.len());
+        assert_eq!(
+            protocol.on_data(address, &mut state, &response[10..20]),
+            Step::NeedMore
+        );
+        assert_eq!(
+            protocol.on_data(address, &mut state, &response[20..]),
+            Step::Done(status.to_vec())
+        );
+        assert!(state.buffer.is_empty());
+    }
 }
@@ -1,24 +1,128 @@
 use std::{
+    collections::HashMap,
     io::{Cursor, Read, Write},
+    mem,
     net::SocketAddrV4,
+    sync::Arc,
 };
 
+use serde::Deserialize;
+
 use super::{ParseResponseError, Protocol, Response};
 
 #[derive(Clone)]
 pub struct Minecraft {
     minecraft_request: Vec<u8>,
+    protocol_version: i32,
+    /// Hostnames that are sent to specific targets instead of the default one,
+    /// for proxies that route based on the hostname.
+    hostnames: Arc<HashMap<SocketAddrV4, String>>,
+    /// The FML marker that's appended to the hostname, so the response
+    /// handler can record which one made the server send its mods.
+    fml_marker: Option<FmlMarker>,
+    /// Whether we're probing which protocol versions servers support, so the
+    /// responses are stored separately from the server's normal response.
+    is_protocol_probe: bool,
 }
 
 impl Minecraft {
     pub fn new(hostname: &str, port: u16, protocol_version: i32) -> Self {
         let minecraft_request = build_latest_request(hostname, port, protocol_version);
-        Self { minecraft_request }
+        Self {
+            minecraft_request,
+            protocol_version,
+            hostnames: Arc::new(HashMap::new()),
+            fml_marker: None,
+            is_protocol_probe: false,
+        }
+    }
+
+    /// Send the given hostnames in the handshake to these targets. Targets
+    /// that aren't in the map get the default hostname, and responses from
+    /// targets that are in it are recorded as virtual hosts.
+    pub fn with_hostnames(mut self, hostnames: HashMap<SocketAddrV4, String>) -> Self {
+        self.hostnames = Arc::new(hostnames);
+        self
+    }
+
+    /// Same as [`Self::new`], but the hostname has the given FML marker
+    /// appended so Forge servers include their mods in the response.
+    pub fn new_with_fml_marker(
+        hostname: &str,
+        port: u16,
+        protocol_version: i32,
+        fml_marker: FmlMarker,
+    ) -> Self {
+        let hostname = format!("{hostname}{}", fml_marker.hostname_suffix());
+        Self {
+            fml_marker: Some(fml_marker),
+            ..Self::new(&hostname, port, protocol_version)
+        }
+    }
+
+    /// Same as [`Self::new`], but the responses are recorded as which protocol
+    /// versions the server supports.
+    pub fn new_protocol_probe(hostname: &str, port: u16, protocol_version: i32) -> Self {
+        Self {
+            is_protocol_probe: true,
+            ..Self::new(hostname, port, protocol_version)
+        }
+    }
+
+    pub fn fml_marker(&self) -> Option<FmlMarker> {
+        self.fml_marker
+    }
+
+    /// The protocol version that we're probing servers with, if this is a
+    /// protocol probe.
+    pub fn probe_protocol_version(&self) -> Option<i32> {
+        self.is_protocol_probe.then_some(self.protocol_version)
+    }
+
+    /// The hostname that was sent to this target, if it isn't the default.
+    pub fn virtual_host(&self, address: SocketAddrV4) -> Option<&str> {
+        self.hostnames.get(&address).map(String::as_str)
+    }
+}
+
+/// The marker that Forge clients append to the hostname in the handshake. Each
+/// Forge generation looks for a different one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Deserialize)]
+pub enum FmlMarker {
+    /// 1.7-1.12
+    #[serde(rename = "FML")]
+    Fml,
+    /// 1.13-1.17
+    #[serde(rename = "FML2")]
+    Fml2,
+    /// 1.18+
+    #[serde(rename = "FML3")]
+    Fml3,
+}
+
+impl FmlMarker {
+    pub fn hostname_suffix(&self) -> &'static str {
+        match self {
+            FmlMarker::Fml => "\0FML\0",
+            FmlMarker::Fml2 => "\0FML2\0",
+            FmlMarker::Fml3 => "\0FML3\0",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FmlMarker::Fml => "FML",
+            FmlMarker::Fml2 => "FML2",
+            FmlMarker::Fml3 => "FML3",
+        }
     }
 }
 
 impl Protocol for Minecraft {
-    fn payload(&self, _address: SocketAddrV4) -> Vec<u8> {
+    fn payload(&self, address: SocketAddrV4) -> Vec<u8> {
+        if let Some(hostname) = self.hostnames.get(&address) {
+            return build_latest_request(hostname, address.port(), self.protocol_version);
+        }
         self.minecraft_request.clone()
     }
 
@@ -29,7 +133,7 @@ impl Protocol for Minecraft {
         };
 
         // ignore the packet length
-        let mut stream = Cursor::new(response);
+        let mut stream = Cursor::new(&response[..]);
         read_varint(&mut stream).ok_or(ParseResponseError::Invalid)?;
         let packet_id = read_varint(&mut stream).ok_or(ParseResponseError::Invalid)?;
         let response_length = read_varint(&mut stream).ok_or(ParseResponseError::Invalid)?;
@@ -38,20 +142,24 @@ impl Protocol for Minecraft {
         }
         // read until end
         let position = stream.position() as usize;
-        let status_buffer = &stream.into_inner()[position..];
-        if status_buffer.len() < response_length as usize {
+        if response.len() - position < response_length as usize {
             return Err(ParseResponseError::Incomplete {
-                expected_length: response_length as u32,
+                expected_length: (position + response_length as usize) as u32,
             });
         }
 
-        let status_string = String::from_utf8_lossy(status_buffer).to_string();
-
         // make sure it starts with {
-        if !status_string.starts_with('{') {
+        if response.get(position) != Some(&b'{') {
             return Err(ParseResponseError::Invalid);
         }
 
+        let mut status_buffer = mem::take(response);
+        status_buffer.drain(..position);
+        let status_string = match String::from_utf8(status_buffer) {
+            Ok(status_string) => status_string,
+            Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+        };
+
         Ok(status_string.into_bytes())
     }
 }
@@ -84,15 +192,13 @@ pub fn build_latest_request(hostname: &str, port: u16, protocol_version: i32) ->
         0x00, // 2nd packet id: 0 for request as VarInt
     ]);
 
-    eprintln!("full_buffer: {full_buffer:?}");
-
     // let mut f = std::fs::File::create("request.bin").unwrap();
     // f.write_all(&full_buffer).unwrap();
 
     full_buffer
 }
 
-fn write_varint(writer: &mut Vec<u8>, mut value: i32) {
+pub fn write_varint(writer: &mut Vec<u8>, mut value: i32) {
     let mut buffer = [0];
     if value == 0 {
         writer.write_all(&buffer).unwrap();
@@ -107,7 +213,7 @@ fn write_varint(writer: &mut Vec<u8>, mut value: i32) {
     }
 }
 
-fn read_varint(reader: &mut (dyn Read + Unpin + Send)) -> Option<i32> {
+pub fn read_varint(reader: &mut (dyn Read + Unpin + Send)) -> Option<i32> {
     let mut buffer = [0];
     let mut ans = 0;
     for i in 0..5 {
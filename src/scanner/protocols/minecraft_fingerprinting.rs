@@ -1,14 +1,57 @@
-use std::{collections::HashMap, io::Write, net::SocketAddrV4};
+use std::{
+    collections::HashMap,
+    io::{Cursor, Write},
+    mem,
+    net::SocketAddrV4,
+};
 
-use super::{ParseResponseError, Protocol, Response};
+use serde::Deserialize;
+
+use super::{ParseResponseError, Protocol, Response, read_varint};
 
 pub struct MinecraftFingerprinting {
     protocol_versions: HashMap<SocketAddrV4, i32>,
+    probe: FingerprintProbe,
 }
 
 impl MinecraftFingerprinting {
-    pub fn new(protocol_versions: HashMap<SocketAddrV4, i32>) -> Self {
-        Self { protocol_versions }
+    pub fn new(protocol_versions: HashMap<SocketAddrV4, i32>, probe: FingerprintProbe) -> Self {
+        Self {
+            protocol_versions,
+            probe,
+        }
+    }
+
+    pub fn probe(&self) -> FingerprintProbe {
+        self.probe
+    }
+}
+
+/// The different kinds of invalid data we can send to make servers respond
+/// with an error. Different implementations fail in different ways, so
+/// sending more than one helps tell them apart.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FingerprintProbe {
+    /// A login start packet with an empty username and an extra byte at the
+    /// end.
+    LoginStart,
+    /// A handshake with a next state that doesn't exist.
+    InvalidNextState,
+    /// A packet length that's bigger than the 21 bits that vanilla allows.
+    OversizedPacket,
+    /// A handshake where the hostname is longer than the packet.
+    MalformedHandshake,
+}
+
+impl FingerprintProbe {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FingerprintProbe::LoginStart => "login_start",
+            FingerprintProbe::InvalidNextState => "invalid_next_state",
+            FingerprintProbe::OversizedPacket => "oversized_packet",
+            FingerprintProbe::MalformedHandshake => "malformed_handshake",
+        }
     }
 }
 
@@ -17,41 +60,49 @@ impl Protocol for MinecraftFingerprinting {
         let Some(&protocol_version) = self.protocol_versions.get(&address) else {
             return vec![];
         };
-        build_fingerprint_request(&address.ip().to_string(), address.port(), protocol_version)
+        let hostname = address.ip().to_string();
+        match self.probe {
+            FingerprintProbe::LoginStart => {
+                build_fingerprint_request(&hostname, address.port(), protocol_version)
+            }
+            FingerprintProbe::InvalidNextState => {
+                build_handshake(&hostname, address.port(), protocol_version, 0x05)
+            }
+            FingerprintProbe::OversizedPacket => build_oversized_packet(),
+            FingerprintProbe::MalformedHandshake => {
+                build_malformed_handshake(&hostname, protocol_version)
+            }
+        }
     }
 
+    /// Returns everything the server sent, once we have at least one full
+    /// packet. Servers that close the connection without sending anything
+    /// result in an empty response.
     fn parse_response(&self, response: Response) -> Result<Vec<u8>, ParseResponseError> {
-        Ok(match response {
-            Response::Data(r) => r.to_owned(),
+        let response = match response {
+            Response::Data(r) => r,
             Response::Rst => return Err(ParseResponseError::Invalid),
-        })
+        };
+
+        // wait for the rest of the first packet, so we don't cut off the error message
+        let mut stream = Cursor::new(&response[..]);
+        if let Some(packet_length) = read_varint(&mut stream)
+            && packet_length > 0
+            && response.len() < stream.position() as usize + packet_length as usize
+        {
+            return Err(ParseResponseError::Incomplete {
+                expected_length: (stream.position() as usize + packet_length as usize) as u32,
+            });
+        }
+
+        Ok(mem::take(response))
     }
 }
 
 /// Create a request that will cause servers to respond with an error (which we
 /// can then use to identify the server software).
 pub fn build_fingerprint_request(hostname: &str, port: u16, protocol_version: i32) -> Vec<u8> {
-    // buffer for the 1st packet's data part
-    let mut buffer = vec![
-        // 0 for handshake packet
-        0x00,
-    ];
-
-    write_varint(&mut buffer, protocol_version); // protocol version
-
-    // Some server implementations require hostname and port to be properly set
-    // (Notchian does not)
-    write_varint(&mut buffer, hostname.len() as i32); // length of hostname as VarInt
-    buffer.extend_from_slice(hostname.as_bytes());
-    buffer.extend_from_slice(&[
-        (port >> 8) as u8,
-        (port & 0b1111_1111) as u8, // server port as unsigned short
-        0x02,                       // next state: 2
-    ]);
-    // buffer for the 1st and 2nd packet
-    let mut full_buffer = vec![];
-    write_varint(&mut full_buffer, buffer.len() as i32); // length of 1st packet id + data as VarInt
-    full_buffer.append(&mut buffer);
+    let mut full_buffer = build_handshake(hostname, port, protocol_version, 0x02);
 
     // ServerboundHelloPacket was changed in 23w31a (1.20.2) so the uuid is no
     // longer optional
@@ -79,6 +130,59 @@ pub fn build_fingerprint_request(hostname: &str, port: u16, protocol_version: i3
     full_buffer
 }
 
+/// A handshake packet with the given next state (1 for status, 2 for login).
+fn build_handshake(hostname: &str, port: u16, protocol_version: i32, next_state: u8) -> Vec<u8> {
+    // buffer for the 1st packet's data part
+    let mut buffer = vec![
+        // 0 for handshake packet
+        0x00,
+    ];
+
+    write_varint(&mut buffer, protocol_version); // protocol version
+
+    // Some server implementations require hostname and port to be properly set
+    // (Notchian does not)
+    write_varint(&mut buffer, hostname.len() as i32); // length of hostname as VarInt
+    buffer.extend_from_slice(hostname.as_bytes());
+    buffer.extend_from_slice(&[
+        (port >> 8) as u8,
+        (port & 0b1111_1111) as u8, // server port as unsigned short
+        next_state,
+    ]);
+    // buffer for the 1st packet
+    let mut full_buffer = vec![];
+    write_varint(&mut full_buffer, buffer.len() as i32); // length of 1st packet id + data as VarInt
+    full_buffer.append(&mut buffer);
+
+    full_buffer
+}
+
+fn build_oversized_packet() -> Vec<u8> {
+    let mut buffer = vec![];
+    // 2^21, one more than vanilla's frame decoder allows
+    write_varint(&mut buffer, 1 << 21);
+    // start of a handshake so it looks somewhat real
+    buffer.extend_from_slice(&[0x00, 0x00]);
+    buffer
+}
+
+fn build_malformed_handshake(hostname: &str, protocol_version: i32) -> Vec<u8> {
+    let mut buffer = vec![
+        // 0 for handshake packet
+        0x00,
+    ];
+    write_varint(&mut buffer, protocol_version);
+    // the hostname is supposedly 255 bytes, but the packet ends after the real
+    // hostname
+    write_varint(&mut buffer, 255);
+    buffer.extend_from_slice(hostname.as_bytes());
+
+    let mut full_buffer = vec![];
+    write_varint(&mut full_buffer, buffer.len() as i32);
+    full_buffer.append(&mut buffer);
+    full_buffer
+}
+
 fn write_varint(writer: &mut Vec<u8>, mut value: i32) {
     let mut buffer = [0];
     if value == 0 {
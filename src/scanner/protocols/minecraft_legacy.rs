@@ -0,0 +1,146 @@
+use std::net::SocketAddrV4;
+
+use super::{ParseResponseError, Protocol, Response};
+
+/// The server list ping used by clients before 1.7. Servers respond to it with
+/// a kick packet that contains the server's status.
+#[derive(Clone)]
+pub struct MinecraftLegacy {
+    minecraft_request: Vec<u8>,
+}
+
+impl MinecraftLegacy {
+    pub fn new(hostname: &str, port: u16) -> Self {
+        let minecraft_request = build_legacy_request(hostname, port);
+        Self { minecraft_request }
+    }
+}
+
+impl Protocol for MinecraftLegacy {
+    fn payload(&self, _address: SocketAddrV4) -> Vec<u8> {
+        self.minecraft_request.clone()
+    }
+
+    fn parse_response(&self, response: Response) -> Result<Vec<u8>, ParseResponseError> {
+        let response = match response {
+            Response::Data(r) => r,
+            Response::Rst => return Err(ParseResponseError::Invalid),
+        };
+
+        let status_string = parse_legacy_kick_packet(response)?;
+
+        // pre-1.4 servers respond with `motd§online§max`, which doesn't have enough
+        // information to be worth keeping
+        if !status_string.starts_with("§1\0") {
+            return Err(ParseResponseError::Invalid);
+        }
+
+        Ok(status_string.into_bytes())
+    }
+}
+
+/// Read the UTF-16BE string from a 0xFF kick packet.
+pub fn parse_legacy_kick_packet(data: &[u8]) -> Result<String, ParseResponseError> {
+    if data.len() < 3 {
+        // not even enough for the packet id and string length
+        return Err(if data.first().is_none_or(|&b| b == 0xff) {
+            ParseResponseError::Incomplete { expected_length: 0 }
+        } else {
+            ParseResponseError::Invalid
+        });
+    }
+    if data[0] != 0xff {
+        return Err(ParseResponseError::Invalid);
+    }
+
+    // the length is in characters, not bytes
+    let string_length = u16::from_be_bytes([data[1], data[2]]) as usize;
+    let string_bytes = &data[3..];
+    if string_bytes.len() < string_length * 2 {
+        return Err(ParseResponseError::Incomplete {
+            expected_length: (3 + string_length * 2) as u32,
+        });
+    }
+
+    let utf16 = string_bytes[..string_length * 2]
+        .chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect::<Vec<_>>();
+    String::from_utf16(&utf16).map_err(|_| ParseResponseError::Invalid)
+}
+
+/// Build the 1.6 version of the legacy ping. Servers older than 1.6 ignore
+/// everything after the first two bytes.
+///
+/// https://minecraft.wiki/w/Java_Edition_protocol/Server_List_Ping#1.6
+pub fn build_legacy_request(hostname: &str, port: u16) -> Vec<u8> {
+    // 1.6.4, the last version that used this ping
+    const PROTOCOL_VERSION: u8 = 78;
+
+    let mut buffer = vec![
+        0xfe, // server list ping
+        0x01, // server list ping payload, always 1
+        0xfa, // plugin message
+    ];
+    write_utf16_string(&mut buffer, "MC|PingHost");
+
+    let hostname_utf16 = hostname.encode_utf16().collect::<Vec<_>>();
+    // protocol version + hostname + port
+    let data_length = 1 + (2 + hostname_utf16.len() * 2) + 4;
+    buffer.extend_from_slice(&(data_length as u16).to_be_bytes());
+    buffer.push(PROTOCOL_VERSION);
+    write_utf16_string(&mut buffer, hostname);
+    buffer.extend_from_slice(&(port as i32).to_be_bytes());
+
+    buffer
+}
+
+fn write_utf16_string(buffer: &mut Vec<u8>, s: &str) {
+    let utf16 = s.encode_utf16().collect::<Vec<_>>();
+    buffer.extend_from_slice(&(utf16.len() as u16).to_be_bytes());
+    for c in utf16 {
+        buffer.extend_from_slice(&c.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_kick_packet(s: &str) -> Vec<u8> {
+        let mut data = vec![0xff];
+        write_utf16_string(&mut data, s);
+        data
+    }
+
+    #[test]
+    fn test_parse_legacy_response() {
+        let protocol = MinecraftLegacy::new("matscan", 1337);
+        let status = "§1\x0078\x001.6.4\x00A Minecraft Server\x003\x0020";
+        let data = protocol
+            .parse_response(Response::Data(&mut make_kick_packet(status)))
+            .unwrap();
+        assert_eq!(String::from_utf8(data).unwrap(), status);
+    }
+
+    #[test]
+    fn test_parse_incomplete_legacy_response() {
+        let protocol = MinecraftLegacy::new("matscan", 1337);
+        let mut data = make_kick_packet("§1\x0078\x001.6.4\x00A Minecraft Server\x003\x0020");
+        data.truncate(10);
+        assert!(matches!(
+            protocol.parse_response(Response::Data(&mut data)),
+            Err(ParseResponseError::Incomplete { .. })
+        ));
+    }
+
+    #[test]
+    fn test_reject_beta_legacy_response() {
+        let protocol = MinecraftLegacy::new("matscan", 1337);
+        let mut data = make_kick_packet("A Minecraft Server§3§20");
+        assert!(matches!(
+            protocol.parse_response(Response::Data(&mut data)),
+            Err(ParseResponseError::Invalid)
+        ));
+    }
+}
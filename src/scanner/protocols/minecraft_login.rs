@@ -0,0 +1,155 @@
+use std::{collections::HashMap, io::Cursor, mem, net::SocketAddrV4};
+
+use uuid::Uuid;
+
+use super::{
+    ParseResponseError, Protocol, Response,
+    minecraft::{read_varint, write_varint},
+};
+
+/// Starts logging in to the server, so we can tell whether it's in online
+/// mode, whitelisted, or only accepts connections from a proxy.
+pub struct MinecraftLogin {
+    username: String,
+    uuid: Uuid,
+    protocol_versions: HashMap<SocketAddrV4, i32>,
+}
+
+impl MinecraftLogin {
+    pub fn new(username: &str, uuid: Uuid, protocol_versions: HashMap<SocketAddrV4, i32>) -> Self {
+        Self {
+            username: username.to_owned(),
+            uuid,
+            protocol_versions,
+        }
+    }
+}
+
+impl Protocol for MinecraftLogin {
+    fn payload(&self, address: SocketAddrV4) -> Vec<u8> {
+        let Some(&protocol_version) = self.protocol_versions.get(&address) else {
+            return vec![];
+        };
+        build_login_request(
+            &address.ip().to_string(),
+            address.port(),
+            protocol_version,
+            &self.username,
+            self.uuid,
+        )
+    }
+
+    /// Returns the first packet the server sent (packet id + data), without
+    /// the length prefix.
+    fn parse_response(&self, response: Response) -> Result<Vec<u8>, ParseResponseError> {
+        let response = match response {
+            Response::Data(r) => r,
+            Response::Rst => return Err(ParseResponseError::Invalid),
+        };
+
+        let mut stream = Cursor::new(&response[..]);
+        let packet_length = read_varint(&mut stream)
+            .ok_or(ParseResponseError::Incomplete { expected_length: 0 })?;
+        if packet_length <= 0 {
+            return Err(ParseResponseError::Invalid);
+        }
+        let position = stream.position() as usize;
+        let packet_end = position + packet_length as usize;
+        if response.len() < packet_end {
+            return Err(ParseResponseError::Incomplete {
+                expected_length: packet_end as u32,
+            });
+        }
+
+        let mut packet = mem::take(response);
+        packet.truncate(packet_end);
+        packet.drain(..position);
+        Ok(packet)
+    }
+}
+
+/// Build a handshake with the next state set to login, followed by a login
+/// start packet.
+pub fn build_login_request(
+    hostname: &str,
+    port: u16,
+    protocol_version: i32,
+    username: &str,
+    uuid: Uuid,
+) -> Vec<u8> {
+    // buffer for the 1st packet's data part
+    let mut buffer = vec![
+        // 0 for handshake packet
+        0x00,
+    ];
+
+    write_varint(&mut buffer, protocol_version); // protocol version
+
+    write_varint(&mut buffer, hostname.len() as i32); // length of hostname as VarInt
+    buffer.extend_from_slice(hostname.as_bytes());
+    buffer.extend_from_slice(&[
+        (port >> 8) as u8,
+        (port & 0b1111_1111) as u8, // server port as unsigned short
+        0x02,                       // next state: 2 (login)
+    ]);
+    // buffer for the 1st and 2nd packet
+    let mut full_buffer = vec![];
+    write_varint(&mut full_buffer, buffer.len() as i32); // length of 1st packet id + data as VarInt
+    full_buffer.append(&mut buffer);
+
+    let mut login_start = vec![
+        // 0 for login start packet
+        0x00,
+    ];
+    write_varint(&mut login_start, username.len() as i32);
+    login_start.extend_from_slice(username.as_bytes());
+    // the login start packet changed a lot in 1.19.x
+    match protocol_version {
+        // 1.20.2+, the uuid is required
+        764..0x40000000 | 1073741968.. => {
+            login_start.extend_from_slice(uuid.as_bytes());
+        }
+        // 1.19.3-1.20.1, optional uuid
+        761..=763 => {
+            login_start.push(0x01);
+            login_start.extend_from_slice(uuid.as_bytes());
+        }
+        // 1.19.1-1.19.2, no signature data and optional uuid
+        760 => {
+            login_start.extend_from_slice(&[0x00, 0x01]);
+            login_start.extend_from_slice(uuid.as_bytes());
+        }
+        // 1.19, no signature data
+        759 => {
+            login_start.push(0x00);
+        }
+        _ => {}
+    }
+    write_varint(&mut full_buffer, login_start.len() as i32);
+    full_buffer.append(&mut login_start);
+
+    full_buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_login_response() {
+        let protocol = MinecraftLogin::new("matscan", Uuid::nil(), HashMap::new());
+        // encryption request, cut off
+        let mut data = vec![0x10, 0x01, 0x00, 0x00];
+        assert!(matches!(
+            protocol.parse_response(Response::Data(&mut data)),
+            Err(ParseResponseError::Incomplete { .. })
+        ));
+
+        // set compression followed by another packet
+        let mut data = vec![0x03, 0x03, 0x80, 0x02, 0x05, 0x02];
+        assert_eq!(
+            protocol.parse_response(Response::Data(&mut data)).unwrap(),
+            vec![0x03, 0x80, 0x02]
+        );
+    }
+}
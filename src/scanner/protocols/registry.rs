@@ -0,0 +1,124 @@
+use std::{net::SocketAddrV4, ops::RangeInclusive, sync::Arc};
+
+use rustc_hash::FxHashMap;
+
+use super::Protocol;
+use crate::processing::{ProcessableProtocol, ResponseHandler, response_handler};
+
+/// A protocol along with the function that processes its responses.
+pub struct RegisteredProtocol {
+    pub protocol: Arc<dyn Protocol>,
+    /// Handles responses with the same protocol, so it knows what we sent.
+    pub handler: ResponseHandler,
+}
+
+impl RegisteredProtocol {
+    fn new<P: Protocol + ProcessableProtocol>(protocol: P) -> Self {
+        let protocol = Arc::new(protocol);
+        Self {
+            protocol: protocol.clone(),
+            handler: response_handler(protocol),
+        }
+    }
+}
+
+/// Decides which protocol is used for each target, so one scan can use more
+/// than one protocol.
+///
+/// Targets that were given a tag use the protocol registered for that tag,
+/// then the first matching port range is used, and everything else uses the
+/// default protocol.
+pub struct ProtocolRegistry {
+    default: RegisteredProtocol,
+    ports: Vec<(RangeInclusive<u16>, RegisteredProtocol)>,
+    tags: Vec<(String, RegisteredProtocol)>,
+    /// The index in `tags` for every tagged target.
+    target_tags: FxHashMap<SocketAddrV4, usize>,
+}
+
+impl ProtocolRegistry {
+    pub fn new<P: Protocol + ProcessableProtocol>(default: P) -> Self {
+        Self {
+            default: RegisteredProtocol::new(default),
+            ports: Vec::new(),
+            tags: Vec::new(),
+            target_tags: FxHashMap::default(),
+        }
+    }
+
+    /// Use the protocol for targets on the given ports.
+    pub fn with_ports<P: Protocol + ProcessableProtocol>(
+        mut self,
+        ports: RangeInclusive<u16>,
+        protocol: P,
+    ) -> Self {
+        self.ports.push((ports, RegisteredProtocol::new(protocol)));
+        self
+    }
+
+    /// Use the protocol for the targets that are given the tag with
+    /// [`Self::tag_targets`].
+    pub fn with_tag<P: Protocol + ProcessableProtocol>(mut self, tag: &str, protocol: P) -> Self {
+        self.tags
+            .push((tag.to_owned(), RegisteredProtocol::new(protocol)));
+        self
+    }
+
+    /// Give the targets a tag, so they use the protocol that was registered
+    /// for it. This replaces any tag that the targets already had.
+    ///
+    /// # Panics
+    ///
+    /// If no protocol was registered for the tag.
+    pub fn tag_targets(&mut self, tag: &str, targets: impl IntoIterator<Item = SocketAddrV4>) {
+        let index = self
+            .tags
+            .iter()
+            .position(|(t, _)| t == tag)
+            .unwrap_or_else(|| panic!("no protocol is registered for the {tag:?} tag"));
+        for target in targets {
+            self.target_tags.insert(target, index);
+        }
+    }
+
+    /// The protocol that's used for the target.
+    pub fn get(&self, address: SocketAddrV4) -> &RegisteredProtocol {
+        if let Some(&index) = self.target_tags.get(&address) {
+            return &self.tags[index].1;
+        }
+        self.ports
+            .iter()
+            .find(|(ports, _)| ports.contains(&address.port()))
+            .map_or(&self.default, |(_, protocol)| protocol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::scanner::protocols::{Minecraft, MinecraftLegacy};
+
+    #[test]
+    fn test_protocol_registry() {
+        let default = Minecraft::new("localhost", 25565, 767);
+        let legacy = MinecraftLegacy::new("localhost", 25565);
+        let other_hostname = Minecraft::new("example.com", 25565, 767);
+        let mut registry = ProtocolRegistry::new(Minecraft::new("localhost", 25565, 767))
+            .with_ports(25500..=25599, MinecraftLegacy::new("localhost", 25565))
+            .with_tag("example", Minecraft::new("example.com", 25565, 767));
+
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        let tagged = SocketAddrV4::new(ip, 25565);
+        registry.tag_targets("example", [tagged]);
+
+        let payload = |address| registry.get(address).protocol.payload(address);
+        // tags go before ports
+        assert_eq!(payload(tagged), other_hostname.payload(tagged));
+        let legacy_target = SocketAddrV4::new(ip, 25566);
+        assert_eq!(payload(legacy_target), legacy.payload(legacy_target));
+        let other = SocketAddrV4::new(ip, 80);
+        assert_eq!(payload(other), default.payload(other));
+    }
+}
@@ -0,0 +1,99 @@
+//! Keeps track of which targets in a session replied, so the ones that didn't
+//! can be sent another SYN.
+
+use std::{
+    net::SocketAddrV4,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use super::targets::StaticScanRanges;
+
+/// A bit for every target in a session's ranges, indexed by the shuffled
+/// index. It's set when the target replies with a SYN+ACK.
+pub struct SynAckBitmap {
+    ranges: Arc<StaticScanRanges>,
+    bits: Box<[AtomicU64]>,
+}
+
+impl SynAckBitmap {
+    pub fn new(ranges: Arc<StaticScanRanges>) -> Self {
+        let bits = (0..ranges.count.div_ceil(64))
+            .map(|_| AtomicU64::new(0))
+            .collect();
+        Self { ranges, bits }
+    }
+
+    /// Mark the target as having replied. Addresses that aren't in the
+    /// session are ignored, and addresses that are in more than one of its
+    /// ranges are marked in all of them.
+    pub fn record(&self, address: SocketAddrV4) {
+        for index in self.ranges.positions(address) {
+            self.bits[index / 64].fetch_or(1 << (index % 64), Ordering::Relaxed);
+        }
+    }
+
+    pub fn contains(&self, index: u64) -> bool {
+        let index = index as usize;
+        self.bits[index / 64].load(Ordering::Relaxed) & (1 << (index % 64)) != 0
+    }
+
+    /// The number of targets that haven't replied.
+    pub fn missing(&self) -> u64 {
+        let replied = self
+            .bits
+            .iter()
+            .map(|word| word.load(Ordering::Relaxed).count_ones() as u64)
+            .sum::<u64>();
+        self.ranges.count as u64 - replied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::scanner::targets::{ScanRange, ScanRanges};
+
+    #[test]
+    fn test_syn_ack_bitmap() {
+        let ranges = Arc::new(
+            ScanRanges::new(vec![ScanRange::single_port(
+                Ipv4Addr::new(1, 2, 3, 0),
+                Ipv4Addr::new(1, 2, 3, 99),
+                25565,
+            )])
+            .to_static(),
+        );
+        let bitmap = SynAckBitmap::new(ranges);
+        assert_eq!(bitmap.missing(), 100);
+
+        bitmap.record(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 70), 25565));
+        // not in the session
+        bitmap.record(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 70), 25566));
+        assert!(bitmap.contains(70));
+        assert!(!bitmap.contains(69));
+        assert_eq!(bitmap.missing(), 99);
+    }
+
+    #[test]
+    fn test_syn_ack_bitmap_overlapping() {
+        let ranges = Arc::new(
+            ScanRanges::new(vec![
+                ScanRange::single_port(Ipv4Addr::new(1, 2, 3, 0), Ipv4Addr::new(1, 2, 3, 9), 25565),
+                ScanRange::single(Ipv4Addr::new(1, 2, 3, 5), 25565),
+            ])
+            .to_static(),
+        );
+        let bitmap = SynAckBitmap::new(ranges);
+
+        // the retry pass could get to it from either range
+        bitmap.record(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 5), 25565));
+        assert!(bitmap.contains(5));
+        assert!(bitmap.contains(10));
+        assert_eq!(bitmap.missing(), 9);
+    }
+}
@@ -0,0 +1,432 @@
+//! Sends the SYNs for every scan session that's running at the same time, so
+//! a long discovery sweep doesn't delay time-sensitive rescans.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, TryRecvError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use eyre::bail;
+use tracing::info;
+
+use super::{
+    ScanSession, SendOutcome,
+    destination_limit::DestinationLimiter,
+    throttle::{AdaptiveRate, Throttler},
+};
+use crate::net::tcp::StatelessTcpWriteHalf;
+
+#[derive(Clone)]
+pub struct SessionOptions {
+    /// Shown in the logs.
+    pub name: String,
+    /// The seed for the SYN cookies. This has to be the seed of the
+    /// [`ReceiverLane`](super::ReceiverLane) that should handle the responses.
+    pub seed: u64,
+    /// Sessions with a higher priority send first in each batch, and get the
+    /// rate that other sessions aren't using.
+    pub priority: u8,
+    /// How much of the rate this session gets compared to the other sessions
+    /// that are running.
+    pub rate_share: f64,
+    pub scan_duration_secs: u64,
+}
+
+struct NewSession {
+    session: ScanSession,
+    options: SessionOptions,
+    done: mpsc::Sender<SessionSummary>,
+}
+
+struct RunningSession {
+    session: ScanSession,
+    options: SessionOptions,
+    done: mpsc::Sender<SessionSummary>,
+    started: Instant,
+    /// The maximum number of packets to send, based on the rate and the scan
+    /// duration.
+    target_count: u64,
+}
+
+pub struct SessionHandle {
+    done: mpsc::Receiver<SessionSummary>,
+}
+
+/// What a session sent, once it's done.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionSummary {
+    /// Including the canaries and retries.
+    pub packets_sent: u64,
+    pub canaries_sent: u64,
+    pub retries_sent: u64,
+    /// The number of targets that hadn't replied when the retries started.
+    pub missing_before_retries: Option<u64>,
+}
+
+impl SessionHandle {
+    /// What the session sent, or None if the session is still running. This
+    /// is an error if the sender thread stopped before finishing the session.
+    pub fn try_summary(&self) -> eyre::Result<Option<SessionSummary>> {
+        match self.done.try_recv() {
+            Ok(summary) => Ok(Some(summary)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => bail!("the sender thread stopped"),
+        }
+    }
+}
+
+/// A handle to the sender thread. It can be cloned to submit sessions from
+/// more than one place.
+#[derive(Clone)]
+pub struct ScanSender {
+    new_sessions: mpsc::Sender<NewSession>,
+    rate_cap: Arc<AtomicU64>,
+}
+
+impl ScanSender {
+    /// Start the sender thread.
+    ///
+    /// If `adaptive_rate` is given, it decides the rate instead of
+    /// `max_packets_per_second`.
+    pub fn spawn(
+        scanner_writer: StatelessTcpWriteHalf,
+        max_packets_per_second: u64,
+        adaptive_rate: Option<AdaptiveRate>,
+        destination_limiter: Option<DestinationLimiter>,
+    ) -> Self {
+        let (new_sessions_sender, new_sessions) = mpsc::channel();
+        let rate_cap = Arc::new(AtomicU64::new(u64::MAX));
+
+        let sender_loop = SenderLoop {
+            scanner_writer,
+            throttler: Throttler::new(match &adaptive_rate {
+                Some(adaptive_rate) => adaptive_rate.rate(),
+                None => max_packets_per_second,
+            }),
+            max_packets_per_second,
+            adaptive_rate,
+            destination_limiter,
+            rate_cap: rate_cap.clone(),
+            sessions: Vec::new(),
+            packets_sent: 0,
+        };
+        thread::spawn(move || sender_loop.run(new_sessions));
+
+        Self {
+            new_sessions: new_sessions_sender,
+            rate_cap,
+        }
+    }
+
+    /// Start sending packets for the session. It shares the rate with the
+    /// other sessions until it's done.
+    pub fn submit(
+        &self,
+        session: ScanSession,
+        options: SessionOptions,
+    ) -> eyre::Result<SessionHandle> {
+        let (done_sender, done) = mpsc::channel();
+        if self
+            .new_sessions
+            .send(NewSession {
+                session,
+                options,
+                done: done_sender,
+            })
+            .is_err()
+        {
+            bail!("the sender thread stopped");
+        }
+        Ok(SessionHandle { done })
+    }
+
+    /// Limit the total rate, like during quiet hours. None removes the limit.
+    pub fn set_rate_cap(&self, rate_cap: Option<u64>) {
+        self.rate_cap
+            .store(rate_cap.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+}
+
+struct SenderLoop {
+    scanner_writer: StatelessTcpWriteHalf,
+    throttler: Throttler,
+    max_packets_per_second: u64,
+    adaptive_rate: Option<AdaptiveRate>,
+    destination_limiter: Option<DestinationLimiter>,
+    rate_cap: Arc<AtomicU64>,
+
+    /// Sorted by priority, highest first.
+    sessions: Vec<RunningSession>,
+    /// The packets sent by every session, used for the adaptive rate.
+    packets_sent: u64,
+}
+
+impl SenderLoop {
+    fn run(mut self, new_sessions: mpsc::Receiver<NewSession>) {
+        let mut packets_sent_last_print = 0;
+        let mut last_print_time = Instant::now();
+
+        loop {
+            if self.sessions.is_empty() {
+                // wait until there's something to send
+                let Ok(new_session) = new_sessions.recv() else {
+                    return;
+                };
+                self.add_session(new_session);
+                packets_sent_last_print = self.packets_sent;
+                last_print_time = Instant::now();
+            }
+            while let Ok(new_session) = new_sessions.try_recv() {
+                self.add_session(new_session);
+            }
+
+            // print info about packets per second every 5 seconds
+            let time_since_last_print = Instant::now() - last_print_time;
+            if time_since_last_print > Duration::from_secs(5) {
+                self.print_progress(
+                    (self.packets_sent - packets_sent_last_print) as f64
+                        / time_since_last_print.as_secs_f64(),
+                );
+                packets_sent_last_print = self.packets_sent;
+                last_print_time = Instant::now();
+            }
+
+            self.update_rate();
+            let batch_size = self.throttler.next_batch();
+            self.send_batch(batch_size);
+
+            self.finish_sessions();
+        }
+    }
+
+    fn add_session(&mut self, new_session: NewSession) {
+        let max_packets_per_second = match &self.adaptive_rate {
+            Some(adaptive_rate) => adaptive_rate.max_rate(),
+            None => self.max_packets_per_second,
+        };
+        let target_count = u64::min(
+            new_session.session.count(),
+            max_packets_per_second * new_session.options.scan_duration_secs,
+        );
+
+        let running_session = RunningSession {
+            session: new_session.session,
+            options: new_session.options,
+            done: new_session.done,
+            started: Instant::now(),
+            target_count,
+        };
+        // keep it sorted by priority, and after other sessions with the same
+        // priority
+        let index = self
+            .sessions
+            .iter()
+            .position(|s| s.options.priority < running_session.options.priority)
+            .unwrap_or(self.sessions.len());
+        self.sessions.insert(index, running_session);
+
+        if let Some(adaptive_rate) = &mut self.adaptive_rate {
+            adaptive_rate.reset_syn_ack_ratio();
+        }
+        if self.sessions.len() > 1 {
+            println!(
+                "started sending for {} while {} other sessions are running",
+                self.sessions[index].options.name,
+                self.sessions.len() - 1
+            );
+        }
+    }
+
+    fn update_rate(&mut self) {
+        let rate_cap = self.rate_cap.load(Ordering::Relaxed);
+        match &mut self.adaptive_rate {
+            Some(adaptive_rate) => {
+                adaptive_rate.set_rate_cap(rate_cap);
+                adaptive_rate.update(self.packets_sent);
+                self.throttler.set_max_rate(adaptive_rate.rate());
+            }
+            None => {
+                self.throttler
+                    .set_max_rate(self.max_packets_per_second.min(rate_cap));
+            }
+        }
+    }
+
+    /// Split the batch between the sessions. Each session gets its share
+    /// first, and then whatever's left goes to whoever can use it in order of
+    /// priority.
+    fn send_batch(&mut self, batch_size: u64) {
+        let total_share = self
+            .sessions
+            .iter()
+            .map(|s| s.options.rate_share)
+            .sum::<f64>();
+
+        let mut batch_sent = 0;
+        let mut wait_until: Option<Instant> = None;
+        for is_share_pass in [true, false] {
+            for running_session in &mut self.sessions {
+                let quota = if is_share_pass {
+                    if total_share <= 0. {
+                        continue;
+                    }
+                    (batch_size as f64 * running_session.options.rate_share / total_share).ceil()
+                        as u64
+                } else {
+                    batch_size
+                };
+
+                let mut session_sent = 0;
+                let mut session_deferred = 0;
+                while session_sent < quota
+                    && batch_sent < batch_size
+                    && running_session.session.packets_sent() < running_session.target_count
+                {
+                    match running_session.session.send_next(
+                        &mut self.scanner_writer,
+                        running_session.options.seed,
+                        self.destination_limiter.as_mut(),
+                    ) {
+                        SendOutcome::Sent => {
+                            session_sent += 1;
+                            batch_sent += 1;
+                        }
+                        SendOutcome::Deferred => {
+                            session_deferred += 1;
+                            // don't spend the whole batch on targets that all have to wait
+                            if session_deferred >= quota {
+                                if let Some(ready_at) = running_session.session.next_deferred_at() {
+                                    wait_until =
+                                        Some(wait_until.map_or(ready_at, |t| t.min(ready_at)));
+                                }
+                                break;
+                            }
+                        }
+                        SendOutcome::WaitUntil(ready_at) => {
+                            wait_until = Some(wait_until.map_or(ready_at, |t| t.min(ready_at)));
+                            break;
+                        }
+                        SendOutcome::Exhausted => break,
+                    }
+                }
+            }
+        }
+        self.packets_sent += batch_sent;
+
+        // everything that's left was deferred, so wait for the first one
+        if batch_sent == 0
+            && let Some(wait_until) = wait_until
+        {
+            thread::sleep(
+                wait_until
+                    .saturating_duration_since(Instant::now())
+                    .min(Duration::from_millis(100)),
+            );
+        }
+    }
+
+    fn finish_sessions(&mut self) {
+        let mut i = 0;
+        while i < self.sessions.len() {
+            let running_session = &self.sessions[i];
+            let name = &running_session.options.name;
+            let packets_sent = running_session.session.packets_sent();
+            let scan_duration_secs = running_session.options.scan_duration_secs;
+
+            if packets_sent >= running_session.target_count
+                || running_session.session.is_exhausted()
+            {
+                println!("Finished sending {packets_sent} packets for {name}.");
+            }
+            // if it's been more than 5 minutes since we started, finish the scan
+            else if running_session.started.elapsed().as_secs() > scan_duration_secs {
+                println!("{scan_duration_secs} seconds passed, finishing scan for {name}.");
+            } else {
+                i += 1;
+                continue;
+            }
+
+            let running_session = self.sessions.remove(i);
+            self.finish_session(running_session);
+        }
+    }
+
+    fn finish_session(&mut self, running_session: RunningSession) {
+        let packets_sent = running_session.session.packets_sent();
+
+        let (deferred_count, still_deferred) = running_session.session.deferred_counts();
+        if deferred_count > 0 {
+            println!(
+                "deferred {deferred_count} sends because of the destination limit ({still_deferred} still waiting)",
+            );
+        }
+        if let Some(adaptive_rate) = &mut self.adaptive_rate {
+            let average_rate =
+                packets_sent as f64 / running_session.started.elapsed().as_secs_f64();
+            println!(
+                "adaptive rate is at {} pps (averaged {} pps for {})",
+                adaptive_rate.rate(),
+                average_rate.round() as u64,
+                running_session.options.name
+            );
+            info!(
+                "adaptive rate is at {} pps (averaged {} pps for {})",
+                adaptive_rate.rate(),
+                average_rate.round() as u64,
+                running_session.options.name
+            );
+            adaptive_rate.reset_syn_ack_ratio();
+        }
+
+        // the session might've been given up on, that's fine
+        let _ = running_session.done.send(SessionSummary {
+            packets_sent,
+            canaries_sent: running_session.session.canaries_sent(),
+            retries_sent: running_session.session.retries_sent(),
+            missing_before_retries: running_session.session.missing_before_retries(),
+        });
+    }
+
+    fn print_progress(&self, packets_per_second: f64) {
+        let packets_per_info = format_packets_per_second(packets_per_second);
+        let throttler_packets_per_info =
+            format_packets_per_second(self.throttler.estimated_packets_per_second() as f64);
+
+        let mut info = format!(
+            "packets_sent = {} ({packets_per_info}, throttler estimate: {throttler_packets_per_info}",
+            self.packets_sent
+        );
+        if self.adaptive_rate.is_some() {
+            info.push_str(&format!(
+                ", adaptive limit: {} pps",
+                self.throttler.max_rate()
+            ));
+        }
+        info.push(')');
+        if self.sessions.len() > 1 {
+            let sessions_info = self
+                .sessions
+                .iter()
+                .map(|s| format!("{}: {}", s.options.name, s.session.packets_sent()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            info.push_str(&format!(" [{sessions_info}]"));
+        }
+        println!("{info}");
+    }
+}
+
+fn format_packets_per_second(packets_per_second: f64) -> String {
+    if packets_per_second > 10_000_000. {
+        format!("{} mpps", (packets_per_second / 1_000_000.).round() as u64)
+    } else if packets_per_second > 10_000. {
+        format!("{} kpps", (packets_per_second / 1_000.).round() as u64)
+    } else {
+        format!("{} pps", packets_per_second.round() as u64)
+    }
+}
@@ -208,12 +208,15 @@ impl ScanRanges {
     pub fn to_static(self) -> StaticScanRanges {
         let mut ranges = Vec::with_capacity(self.ranges.len());
         let mut index = 0;
+        let mut max_ip_end = Ipv4Addr::UNSPECIFIED;
         for range in self.ranges {
             let count = range.count();
+            max_ip_end = max_ip_end.max(range.ip_end);
             ranges.push(StaticScanRange {
                 count,
                 range,
                 index,
+                max_ip_end,
             });
             index += count;
         }
@@ -237,6 +240,9 @@ pub struct StaticScanRange {
     pub range: ScanRange,
     count: usize,
     index: usize,
+    /// The highest `ip_end` of this range and every range before it, so
+    /// [`StaticScanRanges::position`] knows when to stop looking.
+    max_ip_end: Ipv4Addr,
 }
 
 impl StaticScanRanges {
@@ -257,6 +263,30 @@ impl StaticScanRanges {
         }
         panic!("index out of bounds");
     }
+
+    /// The indexes of the given address, which is more than one if the ranges
+    /// overlap and none if it's not in any of them. They're in reverse order.
+    pub fn positions(&self, address: SocketAddrV4) -> impl Iterator<Item = usize> + '_ {
+        let ip = *address.ip();
+        let port = address.port();
+        // the ranges are sorted by ip_start, so only the ones before this can
+        // contain the ip
+        let end = self.ranges.partition_point(|r| r.range.ip_start <= ip);
+        self.ranges[..end]
+            .iter()
+            .rev()
+            .take_while(move |range| range.max_ip_end >= ip)
+            .filter_map(move |range| {
+                let r = &range.range;
+                if ip <= r.ip_end && (r.port_start..=r.port_end).contains(&port) {
+                    let addr_index = (u32::from(ip) - u32::from(r.ip_start)) as usize;
+                    let port_index = (port - r.port_start) as usize;
+                    Some(range.index + addr_index * r.count_ports() + port_index)
+                } else {
+                    None
+                }
+            })
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -333,6 +363,43 @@ mod test {
         assert_eq!(ranges.count(), 2usize.pow(32));
     }
 
+    #[test]
+    fn test_position() {
+        let ranges = ScanRanges::new(vec![
+            ScanRange::single_port(
+                Ipv4Addr::new(1, 0, 0, 0),
+                Ipv4Addr::new(1, 0, 0, 255),
+                25565,
+            ),
+            ScanRange::single_address(Ipv4Addr::new(1, 0, 0, 5), 25500, 25599),
+            ScanRange::single(Ipv4Addr::new(2, 0, 0, 0), 80),
+        ])
+        .to_static();
+        for index in 0..ranges.count {
+            let address = ranges.index(index);
+            assert!(ranges.positions(address).any(|position| position == index));
+        }
+        // in both of the first two ranges
+        assert_eq!(
+            ranges
+                .positions(SocketAddrV4::new(Ipv4Addr::new(1, 0, 0, 5), 25565))
+                .collect::<Vec<_>>(),
+            vec![256 + 65, 5]
+        );
+        assert_eq!(
+            ranges
+                .positions(SocketAddrV4::new(Ipv4Addr::new(1, 0, 0, 6), 25500))
+                .next(),
+            None
+        );
+        assert_eq!(
+            ranges
+                .positions(SocketAddrV4::new(Ipv4Addr::new(3, 0, 0, 0), 80))
+                .next(),
+            None
+        );
+    }
+
     #[test]
     fn test_subtract_center() {
         let mut ranges = ScanRanges::new(vec![ScanRange::single_port(
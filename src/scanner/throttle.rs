@@ -2,10 +2,17 @@
 
 use std::{
     collections::VecDeque,
+    fmt::{self, Display},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
     thread,
     time::{Duration, Instant},
 };
 
+use crate::config::AdaptiveRateConfig;
+
 pub struct Throttler {
     max_rate: u64,
 
@@ -91,6 +98,14 @@ impl Throttler {
         batch_size
     }
 
+    pub fn max_rate(&self) -> u64 {
+        self.max_rate
+    }
+
+    pub fn set_max_rate(&mut self, max_packets_per_second: u64) {
+        self.max_rate = max_packets_per_second;
+    }
+
     pub fn estimated_packets_per_second(&self) -> u64 {
         // compare the total_packets_sent_before of the oldest and newest batch
 
@@ -105,3 +120,248 @@ impl Throttler {
             / (newest_batch.time - oldest_batch.time).as_secs_f64()) as u64
     }
 }
+
+/// Counters from the receiving and processing side, used for adjusting the
+/// rate when [`AdaptiveRateConfig`] is enabled.
+#[derive(Default)]
+pub struct RateFeedback {
+    pub syn_acks: AtomicU64,
+    /// The number of received packets that the kernel dropped because our
+    /// socket's buffer was full.
+    pub rx_drops: AtomicU64,
+    /// The number of responses waiting in `SharedData::queue`.
+    pub queue_len: AtomicUsize,
+    /// How long it took to handle each response in the last chunk that was
+    /// processed, on average.
+    pub db_latency_micros: AtomicU64,
+}
+
+/// What happened since the last time the rate was adjusted.
+#[derive(Debug, Clone, Copy, Default)]
+struct FeedbackSample {
+    elapsed: Duration,
+    packets_sent: u64,
+    syn_acks: u64,
+    rx_drops: u64,
+    queue_len: usize,
+    db_latency: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BackoffReason {
+    RxDrops(u64),
+    SynAckRatio(f64),
+    QueueLen(usize),
+    DbLatency(Duration),
+}
+
+impl Display for BackoffReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackoffReason::RxDrops(drops) => write!(f, "{drops} rx drops/s"),
+            BackoffReason::SynAckRatio(ratio) => write!(f, "syn+ack ratio fell to {ratio:.4}"),
+            BackoffReason::QueueLen(len) => write!(f, "{len} responses waiting to be processed"),
+            BackoffReason::DbLatency(latency) => write!(f, "db latency is {latency:?}"),
+        }
+    }
+}
+
+/// Lowers the rate when we're receiving or processing responses slower than
+/// they come in, and slowly raises it again when we aren't.
+pub struct AdaptiveRate {
+    config: AdaptiveRateConfig,
+    feedback: Arc<RateFeedback>,
+    min_rate: u64,
+    max_rate: u64,
+    /// The maximum rate before [`Self::set_rate_cap`] was applied.
+    configured_max_rate: u64,
+    /// This is a float so it can be raised by small factors.
+    rate: f64,
+
+    last_update: Instant,
+    last_packets_sent: u64,
+    last_syn_acks: u64,
+    last_rx_drops: u64,
+    /// The best SYN+ACKs per SYN that we've seen since the running sessions
+    /// last changed.
+    best_syn_ack_ratio: f64,
+}
+
+impl AdaptiveRate {
+    /// How often the rate is adjusted.
+    const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+    /// The SYN+ACK ratio is too noisy to use if we sent fewer packets than this
+    /// since the last update.
+    const MIN_PACKETS_FOR_SYN_ACK_RATIO: u64 = 1000;
+
+    pub fn new(config: &AdaptiveRateConfig, rate: u64, feedback: Arc<RateFeedback>) -> Self {
+        let max_rate = config.max_rate.unwrap_or(rate).max(config.min_rate);
+        let min_rate = config.min_rate;
+        let initial_rate = rate.clamp(min_rate, max_rate);
+
+        Self {
+            last_syn_acks: feedback.syn_acks.load(Ordering::Relaxed),
+            last_rx_drops: feedback.rx_drops.load(Ordering::Relaxed),
+            config: config.clone(),
+            feedback,
+            min_rate,
+            max_rate,
+            configured_max_rate: max_rate,
+            rate: initial_rate as f64,
+
+            last_update: Instant::now(),
+            last_packets_sent: 0,
+            best_syn_ack_ratio: 0.,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate as u64
+    }
+
+    pub fn max_rate(&self) -> u64 {
+        self.max_rate
+    }
+
+    /// Don't go above the given rate, like during quiet hours. This takes
+    /// priority over the configured minimum rate. Use `u64::MAX` to remove
+    /// the cap.
+    pub fn set_rate_cap(&mut self, rate_cap: u64) {
+        self.max_rate = self.configured_max_rate.min(rate_cap);
+        self.min_rate = self.config.min_rate.min(self.max_rate);
+        self.rate = self.rate.clamp(self.min_rate as f64, self.max_rate as f64);
+    }
+
+    /// Forget the best SYN+ACK ratio, for when the targets we're scanning
+    /// change and the ratio isn't comparable anymore.
+    pub fn reset_syn_ack_ratio(&mut self) {
+        self.best_syn_ack_ratio = 0.;
+    }
+
+    /// Look at the feedback and adjust the rate if it's been long enough since
+    /// the last update. Returns the new rate if it changed.
+    pub fn update(&mut self, packets_sent: u64) -> Option<u64> {
+        let elapsed = self.last_update.elapsed();
+        if elapsed < Self::UPDATE_INTERVAL {
+            return None;
+        }
+
+        let syn_acks = self.feedback.syn_acks.load(Ordering::Relaxed);
+        let rx_drops = self.feedback.rx_drops.load(Ordering::Relaxed);
+        let sample = FeedbackSample {
+            elapsed,
+            packets_sent: packets_sent - self.last_packets_sent,
+            syn_acks: syn_acks - self.last_syn_acks,
+            rx_drops: rx_drops - self.last_rx_drops,
+            queue_len: self.feedback.queue_len.load(Ordering::Relaxed),
+            db_latency: Duration::from_micros(
+                self.feedback.db_latency_micros.load(Ordering::Relaxed),
+            ),
+        };
+        self.last_update = Instant::now();
+        self.last_packets_sent = packets_sent;
+        self.last_syn_acks = syn_acks;
+        self.last_rx_drops = rx_drops;
+
+        let old_rate = self.rate();
+        if let Some(reason) = self.adjust(sample) {
+            println!("backing off to {} pps ({reason})", self.rate());
+        }
+
+        let new_rate = self.rate();
+        (new_rate != old_rate).then_some(new_rate)
+    }
+
+    /// Change the rate based on the sample, and return why we backed off if we
+    /// did.
+    fn adjust(&mut self, sample: FeedbackSample) -> Option<BackoffReason> {
+        let reason = self.backoff_reason(sample);
+        if reason.is_some() {
+            self.rate *= self.config.backoff_factor;
+        } else {
+            self.rate *= self.config.ramp_up_factor;
+        }
+        self.rate = self.rate.clamp(self.min_rate as f64, self.max_rate as f64);
+        reason
+    }
+
+    fn backoff_reason(&mut self, sample: FeedbackSample) -> Option<BackoffReason> {
+        let rx_drops_per_sec = (sample.rx_drops as f64 / sample.elapsed.as_secs_f64()) as u64;
+        if rx_drops_per_sec > self.config.max_rx_drops_per_sec {
+            return Some(BackoffReason::RxDrops(rx_drops_per_sec));
+        }
+        if sample.queue_len > self.config.max_queue_len {
+            return Some(BackoffReason::QueueLen(sample.queue_len));
+        }
+        if sample.db_latency > Duration::from_millis(self.config.max_db_latency_ms) {
+            return Some(BackoffReason::DbLatency(sample.db_latency));
+        }
+
+        if sample.packets_sent >= Self::MIN_PACKETS_FOR_SYN_ACK_RATIO {
+            let syn_ack_ratio = sample.syn_acks as f64 / sample.packets_sent as f64;
+            if syn_ack_ratio > self.best_syn_ack_ratio {
+                self.best_syn_ack_ratio = syn_ack_ratio;
+            } else if syn_ack_ratio < self.best_syn_ack_ratio * self.config.min_syn_ack_ratio {
+                return Some(BackoffReason::SynAckRatio(syn_ack_ratio));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_rate() {
+        let config = AdaptiveRateConfig {
+            enabled: true,
+            min_rate: 1000,
+            max_rate: Some(10_000),
+            ..Default::default()
+        };
+        let mut adaptive = AdaptiveRate::new(&config, 5000, Arc::default());
+        let healthy = FeedbackSample {
+            elapsed: Duration::from_secs(1),
+            packets_sent: 5000,
+            syn_acks: 50,
+            ..Default::default()
+        };
+
+        assert_eq!(adaptive.adjust(healthy), None);
+        assert!(adaptive.rate() > 5000);
+
+        assert_eq!(
+            adaptive.adjust(FeedbackSample {
+                rx_drops: 500,
+                ..healthy
+            }),
+            Some(BackoffReason::RxDrops(500))
+        );
+        assert!(adaptive.rate() < 4000);
+
+        // fewer syn+acks than before means packets are getting lost
+        assert_eq!(
+            adaptive.adjust(FeedbackSample {
+                syn_acks: 10,
+                ..healthy
+            }),
+            Some(BackoffReason::SynAckRatio(0.002))
+        );
+
+        for _ in 0..10 {
+            adaptive.adjust(FeedbackSample {
+                queue_len: 1_000_000,
+                ..healthy
+            });
+        }
+        assert_eq!(adaptive.rate(), 1000);
+
+        for _ in 0..100 {
+            adaptive.adjust(healthy);
+        }
+        assert_eq!(adaptive.rate(), 10_000);
+    }
+}
@@ -0,0 +1,151 @@
+//! A hierarchical timer wheel, used for expiring connections without having to
+//! look at every connection.
+
+use std::time::{Duration, Instant};
+
+/// The number of slots in each level of the wheel.
+const SLOTS_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOTS_BITS;
+const LEVELS: usize = 4;
+
+/// Timers are put in the level that matches how far away they are, so they
+/// only have to be moved a few times before they expire. With 10ms ticks, the
+/// levels cover 640ms, 41s, 44m and 46h.
+pub struct TimerWheel<T> {
+    start: Instant,
+    tick: Duration,
+    /// The last tick that was processed.
+    current_tick: u64,
+    /// Each entry has the tick that it expires on.
+    levels: [[Vec<(u64, T)>; SLOTS]; LEVELS],
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            tick,
+            current_tick: 0,
+            levels: std::array::from_fn(|_| std::array::from_fn(|_| Vec::new())),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add a timer that expires at the given time. Timers can't be removed, so
+    /// whoever handles the expired timers should check that they're still
+    /// relevant.
+    pub fn insert(&mut self, deadline: Instant, value: T) {
+        let deadline_tick = self.tick_for(deadline);
+        self.insert_at_tick(deadline_tick, value);
+        self.len += 1;
+    }
+
+    /// Process every tick up to the given time, and call `on_expired` for the
+    /// timers that expired.
+    pub fn advance(&mut self, now: Instant, mut on_expired: impl FnMut(T)) {
+        // deadlines are rounded up to a tick, so a tick is processed once we're
+        // past its start
+        let now_tick =
+            (now.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos()) as u64;
+        if self.len == 0 {
+            // nothing would happen in the ticks we're skipping
+            self.current_tick = self.current_tick.max(now_tick);
+            return;
+        }
+
+        while self.current_tick < now_tick {
+            self.current_tick += 1;
+            let tick = self.current_tick;
+
+            // move the timers from the higher levels down, starting from the top
+            // so they can cascade more than one level
+            for level in (1..LEVELS).rev() {
+                let level_shift = SLOTS_BITS * level as u32;
+                if tick & ((1 << level_shift) - 1) != 0 {
+                    continue;
+                }
+                let slot = ((tick >> level_shift) as usize) & (SLOTS - 1);
+                for (deadline_tick, value) in std::mem::take(&mut self.levels[level][slot]) {
+                    if deadline_tick <= tick {
+                        self.len -= 1;
+                        on_expired(value);
+                    } else {
+                        self.insert_at_tick(deadline_tick, value);
+                    }
+                }
+            }
+
+            let slot = (tick as usize) & (SLOTS - 1);
+            for (_, value) in std::mem::take(&mut self.levels[0][slot]) {
+                self.len -= 1;
+                on_expired(value);
+            }
+        }
+    }
+
+    /// The first tick that's at or after the given time.
+    fn tick_for(&self, time: Instant) -> u64 {
+        let elapsed = time.saturating_duration_since(self.start);
+        elapsed.as_nanos().div_ceil(self.tick.as_nanos()) as u64
+    }
+
+    fn insert_at_tick(&mut self, deadline_tick: u64, value: T) {
+        // things that are already expired go in the next tick
+        let placement_tick = deadline_tick.max(self.current_tick + 1);
+        let max_delta = (1 << (SLOTS_BITS * LEVELS as u32)) - 1;
+        // timers that are too far away go in the last slot, and are moved again
+        // when they get there
+        let placement_tick = placement_tick.min(self.current_tick + max_delta);
+
+        let delta = placement_tick - self.current_tick;
+        let level = (0..LEVELS)
+            .find(|&level| delta < 1 << (SLOTS_BITS * (level as u32 + 1)))
+            .unwrap_or(LEVELS - 1);
+        let slot = ((placement_tick >> (SLOTS_BITS * level as u32)) as usize) & (SLOTS - 1);
+        self.levels[level][slot].push((deadline_tick, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_wheel() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(10));
+        let start = wheel.start;
+
+        let deadlines = [5, 30, 650, 700, 41_000, 60_000, 3_000_000];
+        for (i, &ms) in deadlines.iter().enumerate() {
+            wheel.insert(start + Duration::from_millis(ms), i);
+        }
+        assert_eq!(wheel.len(), deadlines.len());
+
+        let mut expired = Vec::new();
+        let mut now = start;
+        while !wheel.is_empty() {
+            now += Duration::from_millis(10);
+            wheel.advance(now, |i| expired.push((i, now)));
+        }
+
+        assert_eq!(expired.len(), deadlines.len());
+        for (i, expired_at) in expired {
+            let deadline = start + Duration::from_millis(deadlines[i]);
+            // timers expire in the first tick that's at or after their deadline
+            assert!(expired_at >= deadline, "timer {i} expired too early");
+            assert!(
+                expired_at - deadline < Duration::from_millis(10),
+                "timer {i} expired too late"
+            );
+        }
+    }
+}
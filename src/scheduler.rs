@@ -0,0 +1,360 @@
+//! Decides what kind of scan to do next.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, DurationRound, Timelike, Utc};
+use eyre::{bail, eyre};
+use serde::Deserialize;
+
+use crate::config::ScheduleConfig;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyCategory {
+    Normal,
+    Rescan,
+    Fingerprint,
+    Query,
+    LoginProbe,
+    FmlPing,
+    VirtualHosts,
+    ProtocolProbe,
+}
+
+/// The next scan that should be done.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledScan {
+    pub category: StrategyCategory,
+    /// The rescan profile (like `rescan2`) that's due, if this scan was
+    /// triggered by a rescan schedule. If this is None and the category is
+    /// Rescan, then all the unscheduled rescan profiles should be used.
+    pub rescan_profile: Option<String>,
+}
+
+pub struct Scheduler {
+    /// The enabled categories and their weights.
+    weights: Vec<(StrategyCategory, i64)>,
+    /// For smooth weighted round-robin, so categories with the same weight
+    /// still alternate instead of being picked randomly.
+    current_weights: Vec<i64>,
+
+    rescan_schedules: Vec<RescanSchedule>,
+    quiet_hours: Vec<QuietHours>,
+}
+
+struct RescanSchedule {
+    profile: String,
+    cron: CronSchedule,
+    next_run: Option<DateTime<Utc>>,
+}
+
+struct QuietHours {
+    start_hour: u32,
+    end_hour: u32,
+    rate: u64,
+}
+
+impl Scheduler {
+    /// Create a scheduler that picks between the given enabled categories.
+    pub fn new(
+        config: &ScheduleConfig,
+        categories: &[StrategyCategory],
+        now: DateTime<Utc>,
+    ) -> Self {
+        let weights = categories
+            .iter()
+            .map(|category| {
+                (
+                    *category,
+                    *config.weights.get(category).unwrap_or(&1) as i64,
+                )
+            })
+            .filter(|(_, weight)| *weight > 0)
+            .collect::<Vec<_>>();
+
+        Self {
+            current_weights: vec![0; weights.len()],
+            weights,
+            rescan_schedules: config
+                .rescan
+                .iter()
+                // concurrent rescans aren't scheduled with everything else
+                .filter(|schedule| !schedule.concurrent)
+                .map(|schedule| RescanSchedule {
+                    profile: schedule.profile.clone(),
+                    cron: schedule.cron.clone(),
+                    next_run: schedule.cron.next_after(now),
+                })
+                .collect(),
+            quiet_hours: config
+                .quiet_hours
+                .iter()
+                .map(|quiet_hours| QuietHours {
+                    start_hour: quiet_hours.start_hour,
+                    end_hour: quiet_hours.end_hour,
+                    rate: quiet_hours.rate,
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether there's anything that can be scheduled.
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_empty() && self.rescan_schedules.is_empty()
+    }
+
+    /// Pick the next scan. Rescan profiles that are due always go first.
+    ///
+    /// If nothing can be picked right now, the time to wait until something
+    /// is due is returned instead.
+    pub fn next(&mut self, now: DateTime<Utc>) -> Result<ScheduledScan, Duration> {
+        if let Some(schedule) = self
+            .rescan_schedules
+            .iter_mut()
+            .filter(|schedule| schedule.next_run.is_some_and(|next_run| next_run <= now))
+            .min_by_key(|schedule| schedule.next_run)
+        {
+            schedule.next_run = schedule.cron.next_after(now);
+            return Ok(ScheduledScan {
+                category: StrategyCategory::Rescan,
+                rescan_profile: Some(schedule.profile.clone()),
+            });
+        }
+
+        if self.weights.is_empty() {
+            let next_run = self
+                .rescan_schedules
+                .iter()
+                .filter_map(|schedule| schedule.next_run)
+                .min();
+            return Err(next_run.map_or(Duration::minutes(1), |next_run| next_run - now));
+        }
+
+        let total_weight = self.weights.iter().map(|(_, weight)| weight).sum::<i64>();
+        let mut best_index = 0;
+        for (i, (_, weight)) in self.weights.iter().enumerate() {
+            self.current_weights[i] += weight;
+            if self.current_weights[i] > self.current_weights[best_index] {
+                best_index = i;
+            }
+        }
+        self.current_weights[best_index] -= total_weight;
+
+        Ok(ScheduledScan {
+            category: self.weights[best_index].0,
+            rescan_profile: None,
+        })
+    }
+
+    /// The rate that scans should be limited to right now, if we're in quiet
+    /// hours.
+    pub fn quiet_rate(&self, now: DateTime<Utc>) -> Option<u64> {
+        let hour = now.hour();
+        self.quiet_hours
+            .iter()
+            .filter(|quiet_hours| {
+                if quiet_hours.start_hour <= quiet_hours.end_hour {
+                    quiet_hours.start_hour <= hour && hour < quiet_hours.end_hour
+                } else {
+                    // wraps around midnight
+                    hour >= quiet_hours.start_hour || hour < quiet_hours.end_hour
+                }
+            })
+            .map(|quiet_hours| quiet_hours.rate)
+            .min()
+    }
+}
+
+/// A schedule in the usual 5-field cron format (minute, hour, day of month,
+/// month, day of week), in UTC. Each field can be `*`, a number, a range like
+/// `1-5`, a step like `*/10`, or a comma-separated list of those.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// Whether the day of month and day of week fields aren't `*`. Like in
+    /// regular cron, if both are restricted then a day matches if either of
+    /// them do.
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl CronSchedule {
+    /// The first time after the given one that matches the schedule, or None
+    /// if nothing matches in the next few years (like on February 31st).
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut time = after.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+        let give_up_at = time + Duration::days(366 * 4);
+
+        while time < give_up_at {
+            if !self.matches_day(time) {
+                time = (time + Duration::days(1)).with_hour(0)?.with_minute(0)?;
+                continue;
+            }
+            if self.hours & (1 << time.hour()) == 0 {
+                time = (time + Duration::hours(1)).with_minute(0)?;
+                continue;
+            }
+            if self.minutes & (1 << time.minute()) == 0 {
+                time += Duration::minutes(1);
+                continue;
+            }
+            return Some(time);
+        }
+        None
+    }
+
+    fn matches_day(&self, time: DateTime<Utc>) -> bool {
+        if self.months & (1 << time.month()) == 0 {
+            return false;
+        }
+        let day_of_month = self.days_of_month & (1 << time.day()) != 0;
+        let day_of_week = self.days_of_week & (1 << time.weekday().num_days_from_sunday()) != 0;
+        if self.days_of_month_restricted && self.days_of_week_restricted {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        }
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            bail!("cron schedule {s:?} should have 5 fields");
+        };
+
+        let mut days_of_week_bits = parse_cron_field(days_of_week, 0, 7)?;
+        // 7 is also sunday
+        if days_of_week_bits & (1 << 7) != 0 {
+            days_of_week_bits = (days_of_week_bits & !(1 << 7)) | 1;
+        }
+
+        Ok(Self {
+            minutes: parse_cron_field(minutes, 0, 59)?,
+            hours: parse_cron_field(hours, 0, 23)?,
+            days_of_month: parse_cron_field(days_of_month, 1, 31)?,
+            months: parse_cron_field(months, 1, 12)?,
+            days_of_week: days_of_week_bits,
+            days_of_month_restricted: days_of_month != "*",
+            days_of_week_restricted: days_of_week != "*",
+        })
+    }
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = eyre::Report;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Parse a cron field into a bitset where bit `n` is set if `n` matches.
+fn parse_cron_field(field: &str, min: u32, max: u32) -> eyre::Result<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>()?)),
+            None => (part, None),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse()?, end.parse()?)
+        } else {
+            let start = range.parse()?;
+            // "5/10" means every 10 starting at 5
+            (start, if step.is_some() { max } else { start })
+        };
+
+        if start < min || end > max || start > end {
+            bail!("{part:?} is out of range ({min}-{max})");
+        }
+        let step = step.unwrap_or(1);
+        if step == 0 {
+            return Err(eyre!("step in {part:?} can't be 0"));
+        }
+        for n in (start..=end).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_cron_schedule() {
+        let every_10_minutes = "*/10 * * * *".parse::<CronSchedule>().unwrap();
+        let now = Utc.with_ymd_and_hms(2026, 10, 19, 13, 24, 30).unwrap();
+        assert_eq!(
+            every_10_minutes.next_after(now),
+            Some(Utc.with_ymd_and_hms(2026, 10, 19, 13, 30, 0).unwrap())
+        );
+
+        // weekdays at 3am, and 2026-10-19 is a monday
+        let weekdays = "0 3 * * 1-5".parse::<CronSchedule>().unwrap();
+        assert_eq!(
+            weekdays.next_after(now),
+            Some(Utc.with_ymd_and_hms(2026, 10, 20, 3, 0, 0).unwrap())
+        );
+        let friday = Utc.with_ymd_and_hms(2026, 10, 23, 4, 0, 0).unwrap();
+        assert_eq!(
+            weekdays.next_after(friday),
+            Some(Utc.with_ymd_and_hms(2026, 10, 26, 3, 0, 0).unwrap())
+        );
+
+        assert_eq!(
+            "0 0 31 2 *"
+                .parse::<CronSchedule>()
+                .unwrap()
+                .next_after(now),
+            None
+        );
+        assert!("* * *".parse::<CronSchedule>().is_err());
+        assert!("60 * * * *".parse::<CronSchedule>().is_err());
+    }
+
+    #[test]
+    fn test_weighted_categories() {
+        let config = ScheduleConfig {
+            weights: [(StrategyCategory::Normal, 3), (StrategyCategory::Query, 0)]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let now = Utc::now();
+        let mut scheduler = Scheduler::new(
+            &config,
+            &[
+                StrategyCategory::Normal,
+                StrategyCategory::Rescan,
+                StrategyCategory::Query,
+            ],
+            now,
+        );
+
+        use StrategyCategory::{Normal, Rescan};
+        let picked = (0..8)
+            .map(|_| scheduler.next(now).unwrap().category)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            picked,
+            [
+                Normal, Normal, Rescan, Normal, Normal, Normal, Rescan, Normal
+            ]
+        );
+    }
+}
@@ -1,15 +1,25 @@
-use std::{collections::HashMap, fs, str::FromStr};
+use std::{collections::HashMap, fs, time::Duration};
 
-use rand::prelude::*;
+use eyre::{bail, eyre};
 
-use self::rescan::Sort;
+use self::{
+    bandit::{ArmStats, Bandit},
+    rescan::Sort,
+};
 use crate::{
     config::{Config, RescanConfig},
     database::Database,
     scanner::targets::ScanRange,
 };
 
+pub mod bandit;
+pub mod configured;
 pub mod fingerprint;
+pub mod fml_ping;
+pub mod follow_up;
+mod learned_ports;
+pub mod login_probe;
+pub mod protocol_probe;
 pub mod rescan;
 mod slash0;
 mod slash16_a;
@@ -18,6 +28,8 @@ mod slash24_a;
 mod slash24_b;
 mod slash24_c;
 mod slash32;
+mod top_asns;
+pub mod virtual_hosts;
 
 #[derive(
     Clone, Copy, Debug, Eq, PartialEq, Hash, enum_utils::FromStr, enum_utils::IterVariants,
@@ -30,6 +42,8 @@ pub enum ScanStrategy {
     Slash24b,
     Slash24c,
     Slash32,
+    TopAsns,
+    LearnedPorts,
 
     Rescan1day,
     Rescan7days,
@@ -38,114 +52,171 @@ pub enum ScanStrategy {
     RescanOlderThan365days,
 }
 
+/// A strategy that the [`StrategyPicker`] can pick.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum StrategyId {
+    BuiltIn(ScanStrategy),
+    /// The index of a `[[strategy]]` in the config.
+    Configured(usize),
+}
+
+impl StrategyId {
+    pub async fn get_ranges(
+        &self,
+        database: &mut Database,
+        config: &Config,
+    ) -> eyre::Result<Vec<ScanRange>> {
+        if let Some(only_scan_addr) = config.debug.only_scan_addr {
+            let ip = *only_scan_addr.ip();
+            let port = only_scan_addr.port();
+            return Ok(vec![ScanRange {
+                ip_start: ip,
+                ip_end: ip,
+                port_start: port,
+                port_end: port,
+            }]);
+        }
+
+        match self {
+            StrategyId::BuiltIn(strategy) => strategy.get_ranges(database, config).await,
+            StrategyId::Configured(index) => {
+                configured::get_ranges(database, &config.custom_strategies[*index]).await
+            }
+        }
+    }
+}
+
 pub struct StrategyPicker {
-    // the number of new servers last attempt
-    // defaults to a big number (so we try all of them first)
-    // (we can't do usize::MAX because WeightedIndex breaks)
-    strategies: HashMap<ScanStrategy, usize>,
+    bandit: Bandit<StrategyId>,
+    /// Every strategy and its name in strategies.json.
+    strategies: Vec<(StrategyId, String)>,
+    secs_per_million_packets: f64,
 }
 
-const DEFAULT_FOUND: usize = 1_000_000;
-impl Default for StrategyPicker {
-    fn default() -> Self {
-        // make a hashmap of { mode: servers fount last scan } and default to 2^16
+/// Make sure the `[[strategy]]`s in the config are valid and don't have the
+/// same name as another strategy.
+pub fn check_custom_strategies(config: &Config) -> eyre::Result<()> {
+    let mut names = ScanStrategy::iter()
+        .map(|strategy| format!("{strategy:?}"))
+        .collect::<Vec<_>>();
+    for strategy in &config.custom_strategies {
+        configured::check(strategy)
+            .map_err(|err| eyre!("invalid [[strategy]] {:?}: {err}", strategy.name))?;
+        if names.contains(&strategy.name) {
+            bail!(
+                "there's more than one strategy called {:?}, [[strategy]] names must be unique",
+                strategy.name
+            );
+        }
+        names.push(strategy.name.clone());
+    }
+    Ok(())
+}
+
+impl StrategyPicker {
+    /// Register the built-in strategies and the ones from the config, and
+    /// load their history from strategies.json.
+    ///
+    /// The config's strategies should've been checked with
+    /// [`check_custom_strategies`] already.
+    pub fn new(config: &Config) -> Self {
+        let mut strategies = ScanStrategy::iter()
+            .map(|strategy| (StrategyId::BuiltIn(strategy), format!("{strategy:?}")))
+            .collect::<Vec<_>>();
+        for (index, strategy) in config.custom_strategies.iter().enumerate() {
+            strategies.push((StrategyId::Configured(index), strategy.name.clone()));
+        }
 
         // backwards compat
         if !fs::exists("strategies.json").unwrap() {
             let _ = fs::rename("modes.json", "strategies.json");
         }
 
-        // read strategies.json
-        let mut modes = std::fs::read_to_string("strategies.json")
+        let history = std::fs::read_to_string("strategies.json")
             .unwrap_or_default()
             .parse::<serde_json::Value>()
             .unwrap_or(serde_json::Value::Object(serde_json::Map::new()))
             .as_object()
             .expect("failed to parse strategies.json")
             .iter()
-            .filter_map(|(mode, count)| {
-                if let Ok(mode) = ScanStrategy::from_str(mode) {
-                    Some((
-                        mode,
-                        count.as_u64().expect("couldn't parse count as number") as usize,
-                    ))
-                } else {
-                    None
-                }
+            .filter_map(|(name, stats)| {
+                let (strategy, _) = strategies.iter().find(|(_, n)| n == name)?;
+                Some((*strategy, parse_arm_stats(stats)?))
             })
             .collect::<HashMap<_, _>>();
 
-        for mode in ScanStrategy::iter() {
-            modes.entry(mode).or_insert(DEFAULT_FOUND);
+        Self {
+            bandit: Bandit::new(&config.scanner.bandit, history),
+            strategies,
+            secs_per_million_packets: config.scanner.bandit.secs_per_million_packets,
         }
+    }
 
-        Self { strategies: modes }
+    /// The strategy with the given name, which can be a built-in one or one
+    /// from the config.
+    pub fn find(&self, name: &str) -> Option<StrategyId> {
+        self.strategies
+            .iter()
+            .find(|(_, n)| n == name)
+            .map(|(strategy, _)| *strategy)
+    }
+
+    pub fn name(&self, strategy: StrategyId) -> &str {
+        self.strategies
+            .iter()
+            .find(|(s, _)| *s == strategy)
+            .map(|(_, name)| name.as_str())
+            .expect("strategies are only made by the picker")
     }
-}
 
-impl StrategyPicker {
     /// Picks a mode to scan with. You can optionally pass a list of modes to
     /// pick from, otherwise it'll use all of them.
-    pub fn pick_strategy(&self, modes: Option<Vec<ScanStrategy>>) -> ScanStrategy {
+    pub fn pick_strategy(&self, modes: Option<Vec<StrategyId>>) -> StrategyId {
+        let slash0 = StrategyId::BuiltIn(ScanStrategy::Slash0);
         #[cfg(feature = "benchmark")]
-        return ScanStrategy::Slash0;
+        return slash0;
         // return ScanMode::Slash32RangePorts;
 
-        // if they're all 0, pick Slash0.
+        // if none of them have found anything, pick Slash0.
         // this mostly fixes a bug where some modes panic when the database is empty.
-        if self
-            .strategies
-            .values()
-            .all(|&count| count == 0 || count == DEFAULT_FOUND)
-        {
-            return ScanStrategy::Slash0;
+        if self.bandit.arms().values().all(|stats| stats.reward == 0.) {
+            return slash0;
         }
 
-        let modes_vec = self
-            .strategies
-            .iter()
-            .map(|(m, i)| (*m, *i))
-            .collect::<Vec<_>>();
-        // filter by the modes argument
-        let modes_vec = if let Some(modes) = modes {
-            modes_vec
-                .into_iter()
-                .filter(|(mode, _)| modes.contains(mode))
-                .collect::<Vec<_>>()
-        } else {
-            modes_vec
-        };
-
-        // 1% chance to pick a random strategy
-        if rand::random::<f64>() < 0.01 {
-            return modes_vec
+        let modes = modes.unwrap_or_else(|| {
+            self.strategies
                 .iter()
-                .map(|(mode, _)| *mode)
-                .choose(&mut rand::rng())
-                .unwrap();
-        }
-
-        // otherwise, pick the best one
-        let mut best_mode = ScanStrategy::Slash0;
-        let mut best_score = 0;
-        for (mode, score) in modes_vec.iter() {
-            if *score > best_score {
-                best_score = *score;
-                best_mode = *mode;
-            }
-        }
-        best_mode
+                .map(|(strategy, _)| *strategy)
+                .collect()
+        });
+        self.bandit.pick(&modes).unwrap_or(slash0)
     }
 
-    pub fn update_strategy(&mut self, mode: ScanStrategy, score: usize) {
-        self.strategies.insert(mode, score);
+    /// Record how well a scan with the strategy did and write strategies.json.
+    pub fn update_strategy(
+        &mut self,
+        mode: StrategyId,
+        score: f64,
+        elapsed: Duration,
+        packets_sent: u64,
+    ) {
+        // we add 30 seconds so if a strategy finishes very quickly it's not super
+        // biased towards it
+        let cost_secs = elapsed.as_secs_f64()
+            + 30.
+            + packets_sent as f64 / 1_000_000. * self.secs_per_million_packets;
+        self.bandit.update(mode, score, cost_secs / 3600.);
 
         // write strategies.json
         let mut modes = serde_json::Map::new();
-        for (mode, count) in self.strategies.iter() {
+        for (mode, stats) in self.bandit.arms() {
             modes.insert(
-                format!("{mode:?}"),
-                serde_json::Value::Number((*count).into()),
+                self.name(*mode).to_owned(),
+                serde_json::json!({
+                    "observations": stats.observations,
+                    "reward": stats.reward,
+                    "cost_hours": stats.cost_hours,
+                }),
             );
         }
 
@@ -158,23 +229,31 @@ impl StrategyPicker {
     }
 }
 
+/// strategies.json used to only have the score from the last scan of each
+/// strategy, which is treated like one scan that took an hour.
+const OLD_DEFAULT_FOUND: u64 = 1_000_000;
+fn parse_arm_stats(stats: &serde_json::Value) -> Option<ArmStats> {
+    if let Some(score) = stats.as_u64() {
+        // the old default means that it was never picked
+        return (score != OLD_DEFAULT_FOUND).then_some(ArmStats {
+            observations: 1.,
+            reward: score as f64,
+            cost_hours: 1.,
+        });
+    }
+    Some(ArmStats {
+        observations: stats.get("observations")?.as_f64()?,
+        reward: stats.get("reward")?.as_f64()?,
+        cost_hours: stats.get("cost_hours")?.as_f64()?,
+    })
+}
+
 impl ScanStrategy {
     pub async fn get_ranges(
         &self,
         database: &mut Database,
         config: &Config,
     ) -> eyre::Result<Vec<ScanRange>> {
-        if let Some(only_scan_addr) = config.debug.only_scan_addr {
-            let ip = *only_scan_addr.ip();
-            let port = only_scan_addr.port();
-            return Ok(vec![ScanRange {
-                ip_start: ip,
-                ip_end: ip,
-                port_start: port,
-                port_end: port,
-            }]);
-        }
-
         match self {
             ScanStrategy::Slash0 => slash0::get_ranges(database).await,
             ScanStrategy::Slash16a => slash16_a::get_ranges(database).await,
@@ -183,6 +262,8 @@ impl ScanStrategy {
             ScanStrategy::Slash24b => slash24_b::get_ranges(database).await,
             ScanStrategy::Slash24c => slash24_c::get_ranges(database).await,
             ScanStrategy::Slash32 => slash32::get_ranges(database).await,
+            ScanStrategy::TopAsns => top_asns::get_ranges(database, &config.asn_strategy).await,
+            ScanStrategy::LearnedPorts => learned_ports::get_ranges(database, config).await,
 
             ScanStrategy::Rescan1day => {
                 rescan::get_ranges(
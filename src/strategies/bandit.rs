@@ -0,0 +1,134 @@
+//! A multi-armed bandit (discounted UCB1) for picking which strategy to scan
+//! with next.
+
+use std::{collections::HashMap, hash::Hash};
+
+use crate::config::BanditConfig;
+
+/// How well an arm has done, with older scans weighted less.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ArmStats {
+    /// The decayed number of times the arm was picked.
+    pub observations: f64,
+    /// The decayed sum of the scores.
+    pub reward: f64,
+    /// The decayed sum of how much the scans cost, in hours.
+    pub cost_hours: f64,
+}
+
+impl ArmStats {
+    /// The score per hour, or None if the arm was never picked.
+    pub fn rate(&self) -> Option<f64> {
+        (self.cost_hours > 0.).then(|| self.reward / self.cost_hours)
+    }
+}
+
+pub struct Bandit<K> {
+    arms: HashMap<K, ArmStats>,
+    decay: f64,
+    exploration: f64,
+}
+
+impl<K: Copy + Eq + Hash> Bandit<K> {
+    pub fn new(config: &BanditConfig, arms: HashMap<K, ArmStats>) -> Self {
+        Self {
+            arms,
+            decay: config.decay,
+            exploration: config.exploration,
+        }
+    }
+
+    pub fn arms(&self) -> &HashMap<K, ArmStats> {
+        &self.arms
+    }
+
+    /// Pick the candidate with the highest upper confidence bound. Arms that
+    /// were never picked go first.
+    pub fn pick(&self, candidates: &[K]) -> Option<K> {
+        let stats = |arm: &K| self.arms.get(arm).copied().unwrap_or_default();
+
+        if let Some(&untried) = candidates.iter().find(|arm| stats(arm).observations == 0.) {
+            return Some(untried);
+        }
+
+        let total_observations = candidates
+            .iter()
+            .map(|arm| stats(arm).observations)
+            .sum::<f64>();
+        // UCB1 expects rewards between 0 and 1, so the rates are scaled by the
+        // best one
+        let best_rate = candidates
+            .iter()
+            .filter_map(|arm| stats(arm).rate())
+            .fold(0., f64::max);
+
+        let upper_bound = |arm: &K| {
+            let stats = stats(arm);
+            let scaled_rate = if best_rate > 0. {
+                stats.rate().unwrap_or_default() / best_rate
+            } else {
+                0.
+            };
+            // arms that haven't been picked in a while have decayed to fewer
+            // observations, so they get explored again
+            let confidence = (2. * total_observations.max(1.).ln() / stats.observations).sqrt();
+            scaled_rate + self.exploration * confidence
+        };
+
+        candidates
+            .iter()
+            .copied()
+            .max_by(|a, b| upper_bound(a).total_cmp(&upper_bound(b)))
+    }
+
+    /// Record the result of a scan. Every arm's history is decayed first, so
+    /// recent scans matter more.
+    pub fn update(&mut self, arm: K, reward: f64, cost_hours: f64) {
+        for stats in self.arms.values_mut() {
+            stats.observations *= self.decay;
+            stats.reward *= self.decay;
+            stats.cost_hours *= self.decay;
+        }
+        let stats = self.arms.entry(arm).or_default();
+        stats.observations += 1.;
+        stats.reward += reward;
+        stats.cost_hours += cost_hours;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bandit() {
+        let config = BanditConfig {
+            decay: 0.9,
+            exploration: 0.2,
+            ..Default::default()
+        };
+        let mut bandit = Bandit::new(&config, HashMap::new());
+
+        // everything is tried once first
+        assert_eq!(bandit.pick(&['a']), Some('a'));
+        bandit.update('a', 100., 1.);
+        assert_eq!(bandit.pick(&['a', 'b']), Some('b'));
+        // the same score in half the time is better
+        bandit.update('b', 100., 0.5);
+        assert_eq!(bandit.pick(&['a', 'b']), Some('b'));
+
+        // one noisy scan doesn't undo the history
+        for _ in 0..5 {
+            bandit.update('b', 100., 0.5);
+        }
+        bandit.update('b', 0., 0.5);
+        assert_eq!(bandit.pick(&['a', 'b']), Some('b'));
+
+        // but once b stops finding anything, a gets picked again
+        for _ in 0..10 {
+            bandit.update('b', 0., 0.5);
+        }
+        assert_eq!(bandit.pick(&['a', 'b']), Some('a'));
+        assert!(bandit.arms()[&'a'].observations < 1.);
+    }
+}
@@ -0,0 +1,132 @@
+//! Strategies that are defined in the config with `[[strategy]]`, which scan
+//! the blocks around known servers.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use eyre::bail;
+use rustc_hash::FxHashMap;
+
+use crate::{config::StrategyConfig, database::Database, scanner::targets::ScanRange};
+
+pub async fn get_ranges(
+    database: &Database,
+    config: &StrategyConfig,
+) -> eyre::Result<Vec<ScanRange>> {
+    let known_servers = database.collect_all_servers(config.filter).await?;
+    Ok(to_ranges(&known_servers, config))
+}
+
+/// Make sure the strategy makes sense, so we don't find out in the middle of
+/// scanning.
+pub fn check(config: &StrategyConfig) -> eyre::Result<()> {
+    if !(8..=32).contains(&config.prefix_len) {
+        bail!("prefix_len must be from 8 to 32");
+    }
+    if config.ports.is_empty() {
+        bail!("ports can't be empty");
+    }
+    for ports in &config.ports {
+        let (min, max) = ports.bounds();
+        if min > max {
+            bail!("port range {min}-{max} is backwards");
+        }
+    }
+    Ok(())
+}
+
+/// Scan the configured ports in every block that has enough known servers.
+fn to_ranges(known_servers: &[SocketAddrV4], config: &StrategyConfig) -> Vec<ScanRange> {
+    let mask = u32::MAX << (32 - config.prefix_len as u32);
+
+    let mut blocks = FxHashMap::<u32, usize>::default();
+    for target in known_servers {
+        *blocks.entry(target.ip().to_bits() & mask).or_default() += 1;
+    }
+
+    let mut target_ranges = Vec::new();
+    if config.pad_slash0 && !blocks.is_empty() {
+        // also scan /0 at the same time to avoid overwhelming our targets
+        target_ranges.push(ScanRange::single_port(
+            Ipv4Addr::new(0, 0, 0, 0),
+            Ipv4Addr::new(255, 255, 255, 255),
+            25565,
+        ));
+    }
+
+    for (block, servers) in blocks {
+        if servers < config.min_servers {
+            continue;
+        }
+        for ports in &config.ports {
+            let (port_start, port_end) = ports.bounds();
+            target_ranges.push(ScanRange {
+                ip_start: Ipv4Addr::from_bits(block),
+                ip_end: Ipv4Addr::from_bits(block | !mask),
+                port_start,
+                port_end,
+            });
+        }
+    }
+
+    target_ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::collect_servers::CollectServersFilter, scanner::PortRange};
+
+    #[test]
+    fn test_configured_strategy() {
+        let mut config = StrategyConfig {
+            name: "slash20".to_owned(),
+            prefix_len: 20,
+            ports: vec![
+                PortRange::Number(25565),
+                PortRange::Range {
+                    min: 25560,
+                    max: 25564,
+                },
+            ],
+            min_servers: 2,
+            filter: CollectServersFilter::Active365d,
+            pad_slash0: false,
+        };
+        assert!(check(&config).is_ok());
+
+        let known_servers = [
+            SocketAddrV4::new(Ipv4Addr::new(1, 2, 16, 1), 25565),
+            SocketAddrV4::new(Ipv4Addr::new(1, 2, 31, 255), 25565),
+            // only one server in this block
+            SocketAddrV4::new(Ipv4Addr::new(1, 2, 32, 1), 25565),
+        ];
+        let ranges = to_ranges(&known_servers, &config);
+        assert_eq!(
+            ranges,
+            vec![
+                ScanRange::single_port(
+                    Ipv4Addr::new(1, 2, 16, 0),
+                    Ipv4Addr::new(1, 2, 31, 255),
+                    25565
+                ),
+                ScanRange {
+                    ip_start: Ipv4Addr::new(1, 2, 16, 0),
+                    ip_end: Ipv4Addr::new(1, 2, 31, 255),
+                    port_start: 25560,
+                    port_end: 25564,
+                },
+            ]
+        );
+
+        config.prefix_len = 32;
+        config.min_servers = 1;
+        config.pad_slash0 = true;
+        let ranges = to_ranges(&known_servers, &config);
+        // the /0 and then two port ranges for each server
+        assert_eq!(ranges.len(), 7);
+        assert_eq!(ranges[0].count_addresses(), 1 << 32);
+
+        config.prefix_len = 33;
+        assert!(check(&config).is_err());
+    }
+}
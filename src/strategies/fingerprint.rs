@@ -1,9 +1,23 @@
 use std::net::SocketAddrV4;
 
-use crate::database::Database;
+use super::follow_up::pick_and_mark;
+use crate::{config::FingerprintingConfig, database::Database};
 
+/// Get the servers that should be actively fingerprinted, along with the
+/// protocol version they reported.
 pub async fn get_addrs_and_protocol_versions(
-    _database: &Database,
+    database: &Database,
+    config: &FingerprintingConfig,
 ) -> eyre::Result<Vec<(SocketAddrV4, i32)>> {
-    unimplemented!("active fingerprinting was removed from matscan")
+    let picked = pick_and_mark(
+        database,
+        "last_active_fingerprint_attempt",
+        Some("version_protocol IS NOT NULL"),
+        &config.follow_up,
+    )
+    .await?;
+    Ok(picked
+        .into_iter()
+        .filter_map(|(addr, protocol_version)| Some((addr, protocol_version?)))
+        .collect())
 }
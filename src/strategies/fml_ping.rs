@@ -0,0 +1,20 @@
+use super::follow_up::pick_and_mark;
+use crate::{config::FmlPingConfig, database::Database, scanner::targets::ScanRange};
+
+/// Get the Forge servers that should be pinged again with the FML markers.
+pub async fn get_ranges(
+    database: &Database,
+    config: &FmlPingConfig,
+) -> eyre::Result<Vec<ScanRange>> {
+    let picked = pick_and_mark(
+        database,
+        "last_fml_ping",
+        Some("forgedata_fml_network_version IS NOT NULL OR modinfo_type IS NOT NULL OR is_modded"),
+        &config.follow_up,
+    )
+    .await?;
+    Ok(picked
+        .into_iter()
+        .map(|(addr, _)| ScanRange::single(*addr.ip(), addr.port()))
+        .collect())
+}
@@ -0,0 +1,64 @@
+//! Picking known servers for the strategies that follow up on them, like login
+//! probes and FML pings.
+//!
+//! Servers are marked as soon as they're picked instead of when they respond,
+//! since we won't be told about the ones that never respond and they'd be
+//! picked again every time otherwise.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use sqlx::Row;
+
+use crate::{
+    config::FollowUpConfig,
+    database::{Database, PgU16, PgU32},
+};
+
+/// Pick the servers that were pinged recently and haven't been picked in
+/// `config.every_secs`, and set `column` to the current time for them.
+///
+/// `filter` is an extra SQL condition that the servers must match. Returns the
+/// picked servers along with the protocol version they reported.
+pub async fn pick_and_mark(
+    database: &Database,
+    column: &'static str,
+    filter: Option<&'static str>,
+    config: &FollowUpConfig,
+) -> eyre::Result<Vec<(SocketAddrV4, Option<i32>)>> {
+    let filter = filter
+        .map(|filter| format!("AND ({filter})"))
+        .unwrap_or_default();
+    let rows = sqlx::query(&format!(
+        "
+        UPDATE servers SET {column} = NOW()
+        WHERE (ip, port) IN (
+            SELECT ip, port FROM servers
+            WHERE
+                last_pinged > NOW() - make_interval(secs => $1)
+                AND ({column} IS NULL OR {column} < NOW() - make_interval(secs => $2))
+                {filter}
+            ORDER BY {column} NULLS FIRST
+            LIMIT $3
+        )
+        RETURNING ip, port, version_protocol
+        "
+    ))
+    .bind(config.last_ping_ago_max_secs as f64)
+    .bind(config.every_secs as f64)
+    .bind(config.limit as i64)
+    .fetch_all(&database.pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                SocketAddrV4::new(
+                    Ipv4Addr::from_bits(row.get::<PgU32, _>(0).0),
+                    row.get::<PgU16, _>(1).0,
+                ),
+                row.get::<Option<i32>, _>(2),
+            )
+        })
+        .collect())
+}
@@ -0,0 +1,254 @@
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fmt,
+    net::{Ipv4Addr, SocketAddrV4},
+};
+
+use rustc_hash::FxHashMap;
+use sqlx::{QueryBuilder, Row};
+use tracing::info;
+
+use crate::{
+    asns::{self, AsnRanges},
+    config::{Config, LearnedPortsConfig, PortGroupBy},
+    database::{Database, PgU16, collect_servers::CollectServersFilter},
+    scanner::targets::ScanRange,
+};
+
+/// A /16 or an ASN that the ports are counted in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+enum Prefix {
+    Slash16(u8, u8),
+    Asn(u32),
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prefix::Slash16(a, b) => write!(f, "{a}.{b}.0.0/16"),
+            Prefix::Asn(asn) => write!(f, "AS{asn}"),
+        }
+    }
+}
+
+/// Scan the ports that hosting providers actually use, by looking at which
+/// ports the known servers in each /16 or ASN are on.
+pub async fn get_ranges(database: &Database, config: &Config) -> eyre::Result<Vec<ScanRange>> {
+    let learned_ports = &config.learned_ports;
+    let asns = match learned_ports.group_by {
+        PortGroupBy::Slash16 => None,
+        PortGroupBy::Asn => Some(asns::get(config.asn_strategy.table_path.as_deref()).await?),
+    };
+    let known_servers = database
+        .collect_all_servers(CollectServersFilter::Active365d)
+        .await?;
+
+    let recently_scanned = sqlx::query(
+        "SELECT prefix, port FROM learned_port_scans WHERE last_scanned > NOW() - make_interval(secs => $1)",
+    )
+    .bind(learned_ports.rescan_every_secs as f64)
+    .fetch_all(&database.pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.get::<String, _>(0), row.get::<PgU16, _>(1).0))
+    .collect::<HashSet<_>>();
+
+    let histograms = port_histograms(&known_servers, asns);
+    // the scan stops after scan_duration_secs, and the pairs that didn't fit
+    // would be marked as scanned without being scanned
+    let max_targets = learned_ports
+        .max_targets
+        .unwrap_or(config.rate * config.scan_duration_secs.unwrap_or(60 * 5));
+    let asn_sizes = asns.map(asn_sizes).unwrap_or_default();
+    let prefix_size = |prefix| match prefix {
+        Prefix::Slash16(..) => 1 << 16,
+        Prefix::Asn(asn) => asn_sizes.get(&asn).copied().unwrap_or_default(),
+    };
+    let picked = pick_ports(
+        &histograms,
+        &recently_scanned,
+        learned_ports,
+        max_targets,
+        prefix_size,
+    );
+    info!(
+        "Picked {} prefix and port pairs from {} prefixes",
+        picked.len(),
+        histograms.len()
+    );
+    if picked.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // they're marked as scanned now, since we won't know about the targets that
+    // don't respond
+    for chunk in picked.chunks(10_000) {
+        let mut query_builder =
+            QueryBuilder::new("INSERT INTO learned_port_scans (prefix, port, last_scanned) ");
+        query_builder.push_values(chunk, |mut b, (prefix, port)| {
+            b.push_bind(prefix.to_string())
+                .push_bind(PgU16(*port))
+                .push("NOW()");
+        });
+        query_builder.push(" ON CONFLICT (prefix, port) DO UPDATE SET last_scanned = NOW()");
+        query_builder.build().execute(&database.pool).await?;
+    }
+
+    let mut target_ranges = Vec::new();
+    for (prefix, port) in picked {
+        match prefix {
+            Prefix::Slash16(a, b) => target_ranges.push(ScanRange::single_port(
+                Ipv4Addr::new(a, b, 0, 0),
+                Ipv4Addr::new(a, b, 255, 255),
+                port,
+            )),
+            Prefix::Asn(asn) => {
+                let asns = asns.expect("asn prefixes are only made when grouping by asn");
+                for range in asns.get_ranges_for_asn(asn) {
+                    target_ranges.push(ScanRange::single_port(range.start, range.end, port));
+                }
+            }
+        }
+    }
+    Ok(target_ranges)
+}
+
+/// The number of known servers on each port in every prefix. If `asns` is
+/// given, the prefixes are ASNs instead of /16s.
+fn port_histograms(
+    known_servers: &[SocketAddrV4],
+    asns: Option<&AsnRanges>,
+) -> FxHashMap<Prefix, FxHashMap<u16, usize>> {
+    let mut histograms = FxHashMap::<Prefix, FxHashMap<u16, usize>>::default();
+    for target in known_servers {
+        let prefix = match asns {
+            Some(asns) => match asns.get_asn(*target.ip()) {
+                // 0 means the ip isn't routed
+                Some(asn) if asn != 0 => Prefix::Asn(asn),
+                _ => continue,
+            },
+            None => {
+                let [a, b, _, _] = target.ip().octets();
+                Prefix::Slash16(a, b)
+            }
+        };
+        *histograms
+            .entry(prefix)
+            .or_default()
+            .entry(target.port())
+            .or_default() += 1;
+    }
+    histograms
+}
+
+/// The number of addresses in each ASN.
+fn asn_sizes(asns: &AsnRanges) -> FxHashMap<u32, u64> {
+    let mut sizes = FxHashMap::<u32, u64>::default();
+    for (range, asn) in &asns.0 {
+        *sizes.entry(*asn).or_default() += (range.end.to_bits() - range.start.to_bits()) as u64 + 1;
+    }
+    sizes
+}
+
+/// The top ports in each prefix that weren't scanned recently, with the ones
+/// that have the most known servers first. Pairs are skipped if they'd make
+/// the scan bigger than `max_targets` addresses.
+fn pick_ports(
+    histograms: &FxHashMap<Prefix, FxHashMap<u16, usize>>,
+    recently_scanned: &HashSet<(String, u16)>,
+    config: &LearnedPortsConfig,
+    max_targets: u64,
+    prefix_size: impl Fn(Prefix) -> u64,
+) -> Vec<(Prefix, u16)> {
+    let mut candidates = Vec::new();
+    for (&prefix, ports) in histograms {
+        let mut ports = ports
+            .iter()
+            .map(|(&port, &servers)| (port, servers))
+            .filter(|&(_, servers)| servers >= config.min_servers)
+            .collect::<Vec<_>>();
+        ports.sort_by_key(|&(port, servers)| (Reverse(servers), port));
+        ports.truncate(config.top);
+
+        let prefix_name = prefix.to_string();
+        for (port, servers) in ports {
+            if !recently_scanned.contains(&(prefix_name.clone(), port)) {
+                candidates.push((servers, prefix, port));
+            }
+        }
+    }
+
+    candidates.sort_by_key(|&(servers, prefix, port)| (Reverse(servers), prefix, port));
+    let mut targets = 0;
+    candidates
+        .into_iter()
+        .filter(|&(_, prefix, _)| {
+            let size = prefix_size(prefix);
+            if targets + size > max_targets {
+                return false;
+            }
+            targets += size;
+            true
+        })
+        .take(config.limit)
+        .map(|(_, prefix, port)| (prefix, port))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learned_ports() {
+        let server = |c, port| SocketAddrV4::new(Ipv4Addr::new(1, 2, c, 1), port);
+        let mut known_servers = Vec::new();
+        for (port, servers) in [(25565, 1), (30000, 5), (30001, 4), (30002, 3), (25600, 2)] {
+            for i in 0..servers {
+                known_servers.push(server(i, port));
+            }
+        }
+        known_servers.push(SocketAddrV4::new(Ipv4Addr::new(5, 6, 7, 8), 25565));
+        known_servers.push(SocketAddrV4::new(Ipv4Addr::new(5, 6, 7, 9), 25565));
+
+        let histograms = port_histograms(&known_servers, None);
+        assert_eq!(histograms.len(), 2);
+
+        let config = LearnedPortsConfig {
+            top: 3,
+            min_servers: 2,
+            ..Default::default()
+        };
+        let recently_scanned = HashSet::from([("1.2.0.0/16".to_owned(), 30001)]);
+        let prefix_size = |_| 1 << 16;
+        assert_eq!(
+            pick_ports(
+                &histograms,
+                &recently_scanned,
+                &config,
+                u64::MAX,
+                prefix_size
+            ),
+            vec![
+                (Prefix::Slash16(1, 2), 30000),
+                (Prefix::Slash16(1, 2), 30002),
+                (Prefix::Slash16(5, 6), 25565),
+            ]
+        );
+        // only two /16s fit
+        assert_eq!(
+            pick_ports(
+                &histograms,
+                &recently_scanned,
+                &config,
+                1 << 17,
+                prefix_size
+            ),
+            vec![
+                (Prefix::Slash16(1, 2), 30000),
+                (Prefix::Slash16(1, 2), 30002),
+            ]
+        );
+    }
+}
@@ -0,0 +1,23 @@
+use std::net::SocketAddrV4;
+
+use super::follow_up::pick_and_mark;
+use crate::{config::LoginProbeConfig, database::Database};
+
+/// Get the servers that should have their login probed, along with the
+/// protocol version we should use for them.
+pub async fn get_addrs_and_protocol_versions(
+    database: &Database,
+    config: &LoginProbeConfig,
+) -> eyre::Result<Vec<(SocketAddrV4, i32)>> {
+    let picked = pick_and_mark(
+        database,
+        "last_login_probe_attempt",
+        Some("version_protocol IS NOT NULL"),
+        &config.follow_up,
+    )
+    .await?;
+    Ok(picked
+        .into_iter()
+        .filter_map(|(addr, protocol_version)| Some((addr, protocol_version?)))
+        .collect())
+}
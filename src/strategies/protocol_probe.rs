@@ -0,0 +1,15 @@
+use super::follow_up::pick_and_mark;
+use crate::{config::ProtocolProbeConfig, database::Database, scanner::targets::ScanRange};
+
+/// Get the servers that should be pinged again with different protocol
+/// versions.
+pub async fn get_ranges(
+    database: &Database,
+    config: &ProtocolProbeConfig,
+) -> eyre::Result<Vec<ScanRange>> {
+    let picked = pick_and_mark(database, "last_protocol_probe", None, &config.follow_up).await?;
+    Ok(picked
+        .into_iter()
+        .map(|(addr, _)| ScanRange::single(*addr.ip(), addr.port()))
+        .collect())
+}
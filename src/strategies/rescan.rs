@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV4};
 
 use futures_util::StreamExt;
 use rustc_hash::FxHashSet;
@@ -20,11 +20,21 @@ pub enum Sort {
 }
 
 pub async fn get_ranges(database: &Database, opts: &RescanConfig) -> eyre::Result<Vec<ScanRange>> {
+    Ok(get_targets(database, opts).await?.0)
+}
+
+/// The ranges to rescan, and the servers in them that only responded to the
+/// legacy ping last time so they can be pinged with it again directly.
+pub async fn get_targets(
+    database: &Database,
+    opts: &RescanConfig,
+) -> eyre::Result<(Vec<ScanRange>, Vec<SocketAddrV4>)> {
     let mut ranges = FxHashSet::default();
+    let mut legacy_servers = Vec::new();
 
     let mut qb: QueryBuilder<'_, Postgres> = QueryBuilder::new(format!(
         "
-        SELECT ip, port FROM servers
+        SELECT ip, port, is_legacy_ping FROM servers
         WHERE
             last_pinged > NOW() - INTERVAL '{} seconds'
             AND last_pinged < NOW() - INTERVAL '{} seconds'
@@ -90,6 +100,10 @@ pub async fn get_ranges(database: &Database, opts: &RescanConfig) -> eyre::Resul
             continue;
         }
 
+        if row.get::<bool, _>(2) {
+            legacy_servers.push(SocketAddrV4::new(ip, port));
+        }
+
         if opts.padded && port == 25565 {
             // if padding is enabled, scan some extra addresses that aren't specifically
             // known to have minecraft servers so we're not flooded with responses
@@ -111,5 +125,5 @@ pub async fn get_ranges(database: &Database, opts: &RescanConfig) -> eyre::Resul
         servers += 1;
     }
 
-    Ok(ranges.into_iter().collect())
+    Ok((ranges.into_iter().collect(), legacy_servers))
 }
@@ -0,0 +1,145 @@
+use std::{cmp::Reverse, net::SocketAddrV4};
+
+use rustc_hash::FxHashMap;
+use tracing::info;
+
+use crate::{
+    asns::{self, AsnRanges},
+    config::AsnStrategyConfig,
+    database::{Database, collect_servers::CollectServersFilter},
+    scanner::targets::ScanRange,
+};
+
+/// Scan every prefix of the ASNs with the most known servers for their size,
+/// on the ports that are the most common in each of them.
+pub async fn get_ranges(
+    database: &Database,
+    config: &AsnStrategyConfig,
+) -> eyre::Result<Vec<ScanRange>> {
+    let asns = asns::get(config.table_path.as_deref()).await?;
+    let known_servers = database
+        .collect_all_servers(CollectServersFilter::Active365d)
+        .await?;
+
+    Ok(to_ranges(&known_servers, asns, config))
+}
+
+#[derive(Default)]
+struct AsnServers {
+    servers: usize,
+    ports: FxHashMap<u16, usize>,
+    addresses: u64,
+}
+
+fn to_ranges(
+    known_servers: &[SocketAddrV4],
+    asns: &AsnRanges,
+    config: &AsnStrategyConfig,
+) -> Vec<ScanRange> {
+    let mut servers_per_asn = FxHashMap::<u32, AsnServers>::default();
+    for target in known_servers {
+        // 0 means the ip isn't routed
+        let Some(asn) = asns.get_asn(*target.ip()).filter(|&asn| asn != 0) else {
+            continue;
+        };
+        let asn_servers = servers_per_asn.entry(asn).or_default();
+        asn_servers.servers += 1;
+        *asn_servers.ports.entry(target.port()).or_default() += 1;
+    }
+    servers_per_asn.retain(|_, asn_servers| asn_servers.servers >= config.min_servers);
+
+    for (range, asn) in &asns.0 {
+        if let Some(asn_servers) = servers_per_asn.get_mut(asn) {
+            asn_servers.addresses += range.end.to_bits() as u64 - range.start.to_bits() as u64 + 1;
+        }
+    }
+
+    let density =
+        |asn_servers: &AsnServers| asn_servers.servers as f64 / asn_servers.addresses.max(1) as f64;
+    let mut ranked = servers_per_asn.into_iter().collect::<Vec<_>>();
+    ranked.sort_by(|(_, a), (_, b)| density(b).total_cmp(&density(a)));
+    ranked.truncate(config.top);
+
+    let mut target_ranges = Vec::new();
+    for (asn, asn_servers) in ranked {
+        let mut ports = asn_servers.ports.into_iter().collect::<Vec<_>>();
+        // the most common ports first, and then the lowest
+        ports.sort_by_key(|&(port, servers)| (Reverse(servers), port));
+        ports.truncate(config.ports);
+
+        let prefixes = asns.get_ranges_for_asn(asn);
+        info!(
+            "Scanning {} prefixes of AS{asn} ({} known servers) on ports {:?}",
+            prefixes.len(),
+            asn_servers.servers,
+            ports.iter().map(|(port, _)| port).collect::<Vec<_>>()
+        );
+        for prefix in prefixes {
+            for &(port, _) in &ports {
+                target_ranges.push(ScanRange::single_port(prefix.start, prefix.end, port));
+            }
+        }
+    }
+
+    target_ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_top_asns() {
+        // AS1 is a /24 and AS2 is a /16, AS3 is spread over two /24s
+        let tsv = "\
+16777216\t16777471\t1\tUS\tONE
+16777472\t16842751\t2\tUS\tTWO
+33554432\t33554687\t3\tUS\tTHREE
+50331648\t50331903\t3\tUS\tTHREE
+";
+        let asns = AsnRanges::from_tsv(tsv.as_bytes()).unwrap();
+        let server = |a, b, c, d, port| SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port);
+        let known_servers = [
+            server(1, 0, 0, 1, 25565),
+            server(1, 0, 0, 2, 25566),
+            server(1, 0, 0, 3, 25566),
+            server(1, 0, 1, 1, 25565),
+            server(1, 0, 2, 1, 25565),
+            server(1, 0, 3, 1, 25565),
+            server(2, 0, 0, 1, 25565),
+            server(3, 0, 0, 1, 25567),
+            server(3, 0, 0, 2, 25567),
+        ];
+        let config = AsnStrategyConfig {
+            top: 2,
+            ports: 1,
+            min_servers: 2,
+            ..Default::default()
+        };
+
+        let ranges = to_ranges(&known_servers, &asns, &config);
+        // AS1 is the densest, then AS3, and AS2 is too sparse to be in the top 2
+        assert_eq!(
+            ranges,
+            vec![
+                ScanRange::single_port(
+                    Ipv4Addr::new(1, 0, 0, 0),
+                    Ipv4Addr::new(1, 0, 0, 255),
+                    25566
+                ),
+                ScanRange::single_port(
+                    Ipv4Addr::new(2, 0, 0, 0),
+                    Ipv4Addr::new(2, 0, 0, 255),
+                    25567
+                ),
+                ScanRange::single_port(
+                    Ipv4Addr::new(3, 0, 0, 0),
+                    Ipv4Addr::new(3, 0, 0, 255),
+                    25567
+                ),
+            ]
+        );
+    }
+}
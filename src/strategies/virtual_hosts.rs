@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddrV4},
+};
+
+use sqlx::Row;
+
+use crate::{
+    config::VirtualHostsConfig,
+    database::{Database, PgU16, PgU32},
+};
+
+/// Get the known domains that should be sent to each server on their IP.
+///
+/// The domains are marked when they're picked, like the servers in
+/// [`super::follow_up`].
+pub async fn get_targets(
+    database: &Database,
+    config: &VirtualHostsConfig,
+) -> eyre::Result<HashMap<SocketAddrV4, Vec<String>>> {
+    let rows = sqlx::query(
+        "
+        WITH probed AS (
+            UPDATE known_domains SET last_probed = NOW()
+            WHERE (ip, domain) IN (
+                SELECT ip, domain FROM known_domains
+                WHERE
+                    (last_probed IS NULL OR last_probed < NOW() - make_interval(secs => $2))
+                    AND EXISTS (
+                        SELECT 1 FROM servers
+                        WHERE servers.ip = known_domains.ip AND last_pinged > NOW() - make_interval(secs => $1)
+                    )
+                ORDER BY last_probed NULLS FIRST
+                LIMIT $3
+            )
+            RETURNING ip, domain
+        )
+        SELECT servers.ip, servers.port, probed.domain FROM probed
+        JOIN servers ON servers.ip = probed.ip
+        WHERE servers.last_pinged > NOW() - make_interval(secs => $1)
+        ",
+    )
+    .bind(config.follow_up.last_ping_ago_max_secs as f64)
+    .bind(config.follow_up.every_secs as f64)
+    .bind(config.follow_up.limit as i64)
+    .fetch_all(&database.pool)
+    .await?;
+
+    let mut targets: HashMap<SocketAddrV4, Vec<String>> = HashMap::new();
+    for row in rows {
+        let addr = SocketAddrV4::new(
+            Ipv4Addr::from_bits(row.get::<PgU32, _>(0).0),
+            row.get::<PgU16, _>(1).0,
+        );
+        targets.entry(addr).or_default().push(row.get(2));
+    }
+    Ok(targets)
+}
+
+/// Split the targets into rounds where every target has at most one domain,
+/// since we can only have one connection to each target at a time.
+pub fn into_rounds(
+    targets: HashMap<SocketAddrV4, Vec<String>>,
+) -> Vec<HashMap<SocketAddrV4, String>> {
+    let mut rounds: Vec<HashMap<SocketAddrV4, String>> = Vec::new();
+    for (addr, domains) in targets {
+        for (i, domain) in domains.into_iter().enumerate() {
+            if rounds.len() <= i {
+                rounds.push(HashMap::new());
+            }
+            rounds[i].insert(addr, domain);
+        }
+    }
+    rounds
+}